@@ -0,0 +1,438 @@
+//! Page tables for user programs
+//!
+//! Every `AddressSpace` has a level 4 table of its own. The user half
+//! (`USER_SPACE_START` to `USER_SPACE_END`) is private to it, the kernel half
+//! is shared with the kernel page table. The scheduler loads the address
+//! space of a task into CR3 when it switches to the task.
+//!
+//! `fork` shares the user frames of an address space with its copy. Shared
+//! pages that were writable are marked `COPY_ON_WRITE` and read-only in
+//! both, and `handle_cow_fault` gives the writer a private copy on the first
+//! write. Frames are reference counted (see `memory::acquire_frame`), so the
+//! last address space using a frame frees it.
+
+use x86_64::instructions::tlb;
+use x86_64::registers::control::Cr3;
+use x86_64::structures::idt::PageFaultErrorCode;
+use x86_64::structures::paging::mapper::MapToError;
+use x86_64::structures::paging::page_table::PageTableEntry;
+use x86_64::structures::paging::{
+    FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags,
+    PageTableIndex, PhysFrame, Size4KiB, Translate,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::memory::{
+    self, GlobalFrameAllocator, COPY_ON_WRITE, SHARED, USER_SPACE_END, USER_SPACE_START,
+};
+
+/// A level 4 table and the user mappings below it
+///
+/// Dropping an address space unmaps all of user space and frees its frames,
+/// including those of the page tables themselves.
+#[derive(Debug)]
+pub struct AddressSpace {
+    level_4_frame: PhysFrame,
+}
+
+impl AddressSpace {
+    /// Creates an address space with an empty user half, or `None` if no
+    /// frame is left
+    pub fn new() -> Option<Self> {
+        memory::new_user_page_table().map(|level_4_frame| AddressSpace { level_4_frame })
+    }
+
+    pub fn level_4_frame(&self) -> PhysFrame {
+        self.level_4_frame
+    }
+
+    /// Returns whether this address space is loaded in CR3
+    pub fn is_active(&self) -> bool {
+        Cr3::read().0 == self.level_4_frame
+    }
+
+    /// Loads this address space into CR3
+    ///
+    /// # Safety
+    ///
+    /// The scheduler loads the page table of the next task on every switch,
+    /// so this only lasts until the next switch unless interrupts are off.
+    /// The address space must not be dropped while it is active.
+    pub unsafe fn activate(&self) {
+        let (_, flags) = Cr3::read();
+        Cr3::write(self.level_4_frame, flags);
+    }
+
+    /// Returns a mapper for the page tables of this address space
+    ///
+    /// Only the user half may be changed through it.
+    pub fn mapper(&mut self) -> OffsetPageTable<'static> {
+        // `&mut self` makes sure this is the only mapper for the table
+        unsafe { memory::mapper_for(self.level_4_frame) }
+    }
+
+    /// Maps fresh frames into user space, see `memory::map_user_region`
+    pub fn map_user_region(
+        &mut self,
+        start: VirtAddr,
+        size: u64,
+        data: &[u8],
+        flags: PageTableFlags,
+    ) -> Result<(), MapToError<Size4KiB>> {
+        memory::map_user_region(&mut self.mapper(), start, size, data, flags)
+    }
+
+    /// Returns the physical address `addr` is mapped to in this address space
+    pub fn translate(&self, addr: VirtAddr) -> Option<PhysAddr> {
+        unsafe { memory::mapper_for(self.level_4_frame) }.translate_addr(addr)
+    }
+
+    /// Maps `frames` to consecutive pages starting at `start` and adds an
+    /// owner to each of them, see `memory::acquire_frame`
+    ///
+    /// The pages are always `PRESENT | USER_ACCESSIBLE` in addition to
+    /// `flags`. Nothing stays mapped if a page is already in use.
+    pub fn map_frames<I>(
+        &mut self,
+        start: VirtAddr,
+        frames: I,
+        flags: PageTableFlags,
+    ) -> Result<(), MapToError<Size4KiB>>
+    where
+        I: IntoIterator<Item = PhysFrame>,
+    {
+        let mut mapper = self.mapper();
+        let flags = flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+        let table_flags =
+            PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+        let first_page = Page::containing_address(start);
+        let mut mapped = 0;
+        for (page, frame) in (0..).map(|i| first_page + i).zip(frames) {
+            assert!(
+                page.start_address().as_u64() >= USER_SPACE_START
+                    && page.start_address().as_u64() < USER_SPACE_END,
+                "user region outside of user space"
+            );
+            let result = unsafe {
+                mapper.map_to_with_table_flags(
+                    page,
+                    frame,
+                    flags,
+                    table_flags,
+                    &mut GlobalFrameAllocator,
+                )
+            };
+            match result {
+                Ok(flush) => {
+                    memory::acquire_frame(frame);
+                    // unused pages are not cached in the TLB
+                    flush.ignore();
+                    mapped += 1;
+                }
+                Err(error) => {
+                    self.unmap_region(start, mapped * page.size());
+                    return Err(error);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Unmaps the user pages in `[start, start + size)` and removes an owner
+    /// from their frames, returns how many pages were mapped
+    pub fn unmap_region(&mut self, start: VirtAddr, size: u64) -> u64 {
+        if size == 0 {
+            return 0;
+        }
+        assert!(
+            start.as_u64() >= USER_SPACE_START && start.as_u64() + size <= USER_SPACE_END,
+            "user region outside of user space"
+        );
+        let active = self.is_active();
+        let mut mapper = self.mapper();
+        let first_page: Page = Page::containing_address(start);
+        let last_page: Page = Page::containing_address(start + (size - 1));
+        let mut unmapped = 0;
+        for page in Page::range_inclusive(first_page, last_page) {
+            if let Ok((frame, flush)) = mapper.unmap(page) {
+                if active {
+                    flush.flush();
+                } else {
+                    flush.ignore();
+                }
+                unsafe { memory::release_frame(frame) };
+                unmapped += 1;
+            }
+        }
+        unmapped
+    }
+
+    /// Creates a copy of this address space that shares all user frames
+    ///
+    /// Writable pages become read-only and `COPY_ON_WRITE` in both address
+    /// spaces, except for shared memory. Returns `None` if no frames are left
+    /// for the page tables of the copy.
+    pub fn fork(&mut self) -> Option<AddressSpace> {
+        let mut child = AddressSpace::new()?;
+        let mut child_mapper = child.mapper();
+        let table_flags =
+            PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+
+        let mut result = Ok(());
+        for_each_user_page(self.level_4_frame, |page, entry| {
+            let mut flags = entry.flags();
+            // shared memory stays shared
+            if flags.contains(PageTableFlags::WRITABLE) && !flags.contains(SHARED) {
+                flags.remove(PageTableFlags::WRITABLE);
+                flags.insert(COPY_ON_WRITE);
+                entry.set_flags(flags);
+            }
+            let frame = entry.frame().expect("huge page in user space");
+            memory::acquire_frame(frame);
+            // the copy is not active, so there is nothing to flush; its table
+            // entries are writable so that copying a page is enough to write it
+            let mapped = unsafe {
+                child_mapper.map_to_with_table_flags(
+                    page,
+                    frame,
+                    flags,
+                    table_flags,
+                    &mut GlobalFrameAllocator,
+                )
+            };
+            match mapped {
+                Ok(flush) => {
+                    flush.ignore();
+                    true
+                }
+                Err(error) => {
+                    unsafe { memory::release_frame(frame) };
+                    result = Err(error);
+                    false
+                }
+            }
+        });
+        // pages of this address space lost their write permission
+        if self.is_active() {
+            tlb::flush_all();
+        }
+        // on an error, dropping the copy releases the frames shared so far
+        result.ok().map(|_| child)
+    }
+}
+
+/// Calls `f` for every mapped page in the user half of the given level 4
+/// table, with the entry mapping it, until `f` returns false
+fn for_each_user_page<F>(level_4_frame: PhysFrame, mut f: F)
+where
+    F: FnMut(Page, &mut PageTableEntry) -> bool,
+{
+    let first = usize::from(VirtAddr::new(USER_SPACE_START).p4_index());
+    let last = usize::from(VirtAddr::new(USER_SPACE_END - 1).p4_index());
+    let index = |i: usize| PageTableIndex::new(i as u16);
+
+    for i4 in first..=last {
+        let level_3 = match table(level_4_frame)[i4].frame() {
+            Ok(frame) => table(frame),
+            Err(_) => continue,
+        };
+        for i3 in 0..512 {
+            let level_2 = match level_3[i3].frame() {
+                Ok(frame) => table(frame),
+                Err(_) => continue,
+            };
+            for i2 in 0..512 {
+                let level_1 = match level_2[i2].frame() {
+                    Ok(frame) => table(frame),
+                    Err(_) => continue,
+                };
+                for i1 in 0..512 {
+                    if level_1[i1].is_unused() {
+                        continue;
+                    }
+                    let page = Page::from_page_table_indices(index(i4), index(i3), index(i2), index(i1));
+                    if !f(page, &mut level_1[i1]) {
+                        return;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Returns the entry that maps `page` in the given level 4 table, `None`
+/// if a table on the way is missing
+fn leaf_entry(level_4_frame: PhysFrame, page: Page) -> Option<&'static mut PageTableEntry> {
+    let level_3 = table(table(level_4_frame)[page.p4_index()].frame().ok()?);
+    let level_2 = table(level_3[page.p3_index()].frame().ok()?);
+    let level_1 = table(level_2[page.p2_index()].frame().ok()?);
+    Some(&mut level_1[page.p1_index()])
+}
+
+/// Resolves a write fault on a `COPY_ON_WRITE` page of the active address
+/// space, returns false if the fault has another cause
+///
+/// The page gets a private copy of its frame, or just its write permission
+/// back if no other address space shares the frame anymore. Called by the
+/// page fault handler with interrupts disabled, for faults from user mode
+/// and from the kernel writing to user memory alike.
+pub fn handle_cow_fault(addr: VirtAddr, error_code: PageFaultErrorCode) -> bool {
+    let write_to_present_page =
+        PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::CAUSED_BY_WRITE;
+    if !error_code.contains(write_to_present_page)
+        || addr.as_u64() < USER_SPACE_START
+        || addr.as_u64() >= USER_SPACE_END
+    {
+        return false;
+    }
+    let page = Page::containing_address(addr);
+    let entry = match leaf_entry(Cr3::read().0, page) {
+        Some(entry) if entry.flags().contains(COPY_ON_WRITE) => entry,
+        _ => return false,
+    };
+
+    let old_frame = entry.frame().expect("huge page in user space");
+    let flags = (entry.flags() - COPY_ON_WRITE) | PageTableFlags::WRITABLE;
+    if memory::frame_ref_count(old_frame) == 1 {
+        entry.set_flags(flags);
+    } else {
+        let new_frame = match GlobalFrameAllocator.allocate_frame() {
+            Some(frame) => frame,
+            // the fault is handled like any other then
+            None => return false,
+        };
+        unsafe {
+            let src: *const u8 = memory::phys_to_virt(old_frame.start_address()).as_ptr();
+            let dst: *mut u8 = memory::phys_to_virt(new_frame.start_address()).as_mut_ptr();
+            core::ptr::copy_nonoverlapping(src, dst, page.size() as usize);
+        }
+        entry.set_addr(new_frame.start_address(), flags);
+        unsafe { memory::release_frame(old_frame) };
+    }
+    tlb::flush(page.start_address());
+    true
+}
+
+/// Returns the page table stored in `frame`
+fn table(frame: PhysFrame) -> &'static mut PageTable {
+    unsafe { &mut *memory::phys_to_virt(frame.start_address()).as_mut_ptr() }
+}
+
+/// Frees the frames mapped by `table` and the tables below it, then `table` itself
+///
+/// `level` is 1 for a table mapping pages, 4 for a level 4 table.
+unsafe fn free_table(frame: PhysFrame, level: u8) {
+    for entry in table(frame).iter_mut() {
+        if entry.is_unused() {
+            continue;
+        }
+        // the user half never has huge pages, see `map_user_region`
+        if let Ok(next) = entry.frame() {
+            if level > 1 {
+                free_table(next, level - 1);
+            } else {
+                // other address spaces may still share the frame
+                memory::release_frame(next);
+            }
+        }
+        entry.set_unused();
+    }
+    GlobalFrameAllocator.deallocate_frame(frame);
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        assert!(!self.is_active(), "dropping the active address space");
+        let level_4 = table(self.level_4_frame);
+        let first = usize::from(VirtAddr::new(USER_SPACE_START).p4_index());
+        let last = usize::from(VirtAddr::new(USER_SPACE_END - 1).p4_index());
+        for i in first..=last {
+            if let Ok(frame) = level_4[i].frame() {
+                unsafe { free_table(frame, 3) };
+            }
+            level_4[i].set_unused();
+        }
+        // the kernel half belongs to the kernel page table
+        unsafe { GlobalFrameAllocator.deallocate_frame(self.level_4_frame) };
+    }
+}
+
+#[test_case]
+fn test_address_spaces_are_isolated() {
+    use x86_64::instructions::interrupts;
+
+    let mut first = AddressSpace::new().expect("out of frames");
+    let mut second = AddressSpace::new().expect("out of frames");
+    let addr = VirtAddr::new(USER_SPACE_START);
+    first.map_user_region(addr, 4096, &[], PageTableFlags::WRITABLE).unwrap();
+    second.map_user_region(addr, 4096, &[], PageTableFlags::WRITABLE).unwrap();
+    assert_ne!(first.translate(addr), second.translate(addr));
+
+    let ptr: *mut u64 = addr.as_mut_ptr();
+    // no task switch may load another page table in between
+    interrupts::without_interrupts(|| unsafe {
+        let kernel = memory::kernel_page_table().unwrap();
+        let (_, flags) = Cr3::read();
+        first.activate();
+        ptr.write_volatile(0xdead_beef);
+        second.activate();
+        let seen = ptr.read_volatile();
+        first.activate();
+        let written = ptr.read_volatile();
+        Cr3::write(kernel, flags);
+        assert_eq!(seen, 0);
+        assert_eq!(written, 0xdead_beef);
+    });
+}
+
+#[test_case]
+fn test_drop_frees_all_frames() {
+    let before = memory::frames_in_use();
+    let mut space = AddressSpace::new().expect("out of frames");
+    space
+        .map_user_region(VirtAddr::new(USER_SPACE_START), 3 * 4096, &[1, 2, 3], PageTableFlags::empty())
+        .unwrap();
+    space
+        .map_user_region(VirtAddr::new(USER_SPACE_END - 4096), 4096, &[], PageTableFlags::WRITABLE)
+        .unwrap();
+    assert!(memory::frames_in_use() > before);
+    drop(space);
+    assert_eq!(memory::frames_in_use(), before);
+}
+
+#[test_case]
+fn test_fork_copies_on_write() {
+    use x86_64::instructions::interrupts;
+
+    let before = memory::frames_in_use();
+    let addr = VirtAddr::new(USER_SPACE_START);
+    let mut parent = AddressSpace::new().expect("out of frames");
+    parent.map_user_region(addr, 4096, &[7], PageTableFlags::WRITABLE).unwrap();
+    let child = parent.fork().expect("fork failed");
+
+    let shared = parent.translate(addr).unwrap();
+    assert_eq!(child.translate(addr), Some(shared));
+    assert_eq!(memory::frame_ref_count(PhysFrame::containing_address(shared)), 2);
+
+    let ptr: *mut u8 = addr.as_mut_ptr();
+    let (written, seen_by_child) = interrupts::without_interrupts(|| unsafe {
+        let kernel = memory::kernel_page_table().unwrap();
+        let (_, flags) = Cr3::read();
+        parent.activate();
+        // faults and gets a private copy
+        ptr.write_volatile(8);
+        let written = ptr.read_volatile();
+        child.activate();
+        let seen_by_child = ptr.read_volatile();
+        Cr3::write(kernel, flags);
+        (written, seen_by_child)
+    });
+    assert_eq!(written, 8);
+    assert_eq!(seen_by_child, 7);
+    assert_ne!(parent.translate(addr), child.translate(addr));
+    assert_eq!(memory::frame_ref_count(PhysFrame::containing_address(shared)), 1);
+
+    drop(parent);
+    drop(child);
+    assert_eq!(memory::frames_in_use(), before);
+}
@@ -0,0 +1,178 @@
+//! Parser for ANSI/VT100 escape sequences
+//!
+//! The parser splits a byte stream into printable bytes, control bytes, ESC
+//! sequences and CSI (`ESC [`) sequences; acting on them is left to the user,
+//! see `vga_buffer::Writer`. It is a reduced version of the state machine
+//! used by VT100 compatible terminals: OSC strings and the like are not
+//! supported, and a CSI sequence that is cut off by a control byte is dropped.
+
+/// Maximum number of parameters of a CSI sequence, further ones are ignored
+pub const MAX_PARAMS: usize = 8;
+
+const ESC: u8 = 0x1b;
+
+/// A complete CSI sequence, e.g. `ESC [ 1 ; 31 m`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Csi {
+    params: [u16; MAX_PARAMS],
+    /// number of parameters given, omitted ones before a `;` count as 0; can
+    /// exceed `MAX_PARAMS`
+    count: usize,
+    /// the sequence started with `?`, as DEC private modes do
+    pub private: bool,
+    /// the byte ending the sequence, which selects the function
+    pub final_byte: u8,
+}
+
+impl Csi {
+    /// Returns the parameters given, an empty slice if there are none
+    pub fn params(&self) -> &[u16] {
+        &self.params[..self.count.min(MAX_PARAMS)]
+    }
+
+    /// Returns parameter `index`, or `default` if it is omitted or 0
+    pub fn param(&self, index: usize, default: u16) -> u16 {
+        match self.params().get(index) {
+            Some(&value) if value != 0 => value,
+            _ => default,
+        }
+    }
+}
+
+/// What a byte fed to the parser amounts to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    /// a byte to be shown, including the bytes of multibyte UTF-8 characters
+    Print(u8),
+    /// a C0 control byte such as `\n` or backspace
+    Execute(u8),
+    /// `ESC` followed by `byte`, e.g. `ESC 7` to save the cursor position
+    Escape(u8),
+    Csi(Csi),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Ground,
+    Escape,
+    Csi,
+}
+
+#[derive(Debug, Clone)]
+pub struct Parser {
+    state: State,
+    csi: Csi,
+}
+
+impl Parser {
+    pub const fn new() -> Self {
+        Parser {
+            state: State::Ground,
+            csi: Csi { params: [0; MAX_PARAMS], count: 0, private: false, final_byte: 0 },
+        }
+    }
+
+    /// Feeds one byte, returns what it completes, if anything
+    pub fn advance(&mut self, byte: u8) -> Option<Action> {
+        match self.state {
+            State::Ground => match byte {
+                ESC => {
+                    self.state = State::Escape;
+                    None
+                }
+                0x00..=0x1f | 0x7f => Some(Action::Execute(byte)),
+                _ => Some(Action::Print(byte)),
+            },
+            State::Escape => match byte {
+                b'[' => {
+                    self.state = State::Csi;
+                    self.csi = Parser::new().csi;
+                    None
+                }
+                ESC => None,
+                // intermediate bytes, which none of the supported sequences use
+                0x20..=0x2f => None,
+                0x30..=0x7e => {
+                    self.state = State::Ground;
+                    Some(Action::Escape(byte))
+                }
+                _ => {
+                    self.state = State::Ground;
+                    None
+                }
+            },
+            State::Csi => self.advance_csi(byte),
+        }
+    }
+
+    fn advance_csi(&mut self, byte: u8) -> Option<Action> {
+        let csi = &mut self.csi;
+        match byte {
+            b'0'..=b'9' => {
+                csi.count = csi.count.max(1);
+                if let Some(param) = csi.params.get_mut(csi.count - 1) {
+                    *param = param.saturating_mul(10).saturating_add(u16::from(byte - b'0'));
+                }
+                None
+            }
+            b';' => {
+                csi.count = csi.count.max(1).saturating_add(1);
+                None
+            }
+            b'?' => {
+                csi.private = true;
+                None
+            }
+            // other parameter and intermediate bytes
+            0x20..=0x3f => None,
+            0x40..=0x7e => {
+                self.state = State::Ground;
+                csi.final_byte = byte;
+                Some(Action::Csi(*csi))
+            }
+            ESC => {
+                self.state = State::Escape;
+                None
+            }
+            _ => {
+                self.state = State::Ground;
+                None
+            }
+        }
+    }
+}
+
+impl Default for Parser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[test_case]
+fn test_parser() {
+    let mut parser = Parser::new();
+    let mut actions = [None; 8];
+    let mut len = 0;
+    for &byte in b"a\n\x1b[1;31m\x1b[;5H\x1b[?25l\x1b7\x1b[K".iter() {
+        if let Some(action) = parser.advance(byte) {
+            actions[len] = Some(action);
+            len += 1;
+        }
+    }
+    let csi = |action: Option<Action>| match action {
+        Some(Action::Csi(csi)) => csi,
+        _ => panic!("not a CSI sequence: {:?}", action),
+    };
+    assert_eq!(len, 7);
+    assert_eq!(actions[0], Some(Action::Print(b'a')));
+    assert_eq!(actions[1], Some(Action::Execute(b'\n')));
+    let sgr = csi(actions[2]);
+    assert_eq!((sgr.params(), sgr.final_byte), (&[1, 31][..], b'm'));
+    let position = csi(actions[3]);
+    assert_eq!((position.param(0, 1), position.param(1, 1)), (1, 5));
+    let mode = csi(actions[4]);
+    assert!(mode.private);
+    assert_eq!((mode.params(), mode.final_byte), (&[25][..], b'l'));
+    assert_eq!(actions[5], Some(Action::Escape(b'7')));
+    assert_eq!(csi(actions[6]).params(), &[][..]);
+}
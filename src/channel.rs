@@ -0,0 +1,396 @@
+//! Bounded message channels between tasks and processes
+//!
+//! A channel queues up to `CAPACITY` messages of at most `MAX_MESSAGE_SIZE`
+//! bytes each. It has any number of `Sender`s and `Receiver`s: once every
+//! receiver is gone sending fails with `Disconnected`, and once every sender
+//! is gone receiving fails with `Disconnected` as soon as the queue is empty.
+//!
+//! User programs use channels through handles, small numbers that belong to
+//! one task. A forked child gets a copy of the handles of its parent, and the
+//! handles of a task are closed when it exits.
+
+use crate::signal;
+use crate::sync::{SpinLock, WaitQueue};
+use crate::task::{self, TaskId, MAX_TASKS};
+
+/// Maximum number of open channels
+pub const MAX_CHANNELS: usize = 16;
+/// Number of messages a channel holds before senders block
+pub const CAPACITY: usize = 16;
+/// Maximum length of a message in bytes
+pub const MAX_MESSAGE_SIZE: usize = 256;
+/// Maximum number of handles a task can have open
+pub const MAX_HANDLES: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChannelError {
+    TooManyChannels,
+    /// the message is longer than `MAX_MESSAGE_SIZE`, or longer than the
+    /// buffer it is received into; it stays queued then
+    MessageTooLarge,
+    /// the channel is full (or empty), returned by the non-blocking variants
+    WouldBlock,
+    /// the other side of the channel is closed
+    Disconnected,
+    /// the handle is not open in the current task, or is the wrong end
+    BadHandle,
+    TooManyHandles,
+    /// a signal arrived while blocked, see `signal::interrupted`
+    Interrupted,
+}
+
+#[derive(Clone, Copy)]
+struct Message {
+    data: [u8; MAX_MESSAGE_SIZE],
+    len: usize,
+}
+
+struct Channel {
+    /// ring buffer of `len` messages starting at `head`
+    messages: [Message; CAPACITY],
+    head: usize,
+    len: usize,
+    senders: usize,
+    receivers: usize,
+}
+
+impl Channel {
+    fn is_free(&self) -> bool {
+        self.senders == 0 && self.receivers == 0
+    }
+
+    fn push(&mut self, message: &[u8]) -> Result<(), ChannelError> {
+        if self.receivers == 0 {
+            return Err(ChannelError::Disconnected);
+        }
+        if self.len == CAPACITY {
+            return Err(ChannelError::WouldBlock);
+        }
+        let slot = &mut self.messages[(self.head + self.len) % CAPACITY];
+        slot.data[..message.len()].copy_from_slice(message);
+        slot.len = message.len();
+        self.len += 1;
+        Ok(())
+    }
+
+    fn pop(&mut self, buf: &mut [u8]) -> Result<usize, ChannelError> {
+        if self.len == 0 {
+            return Err(if self.senders == 0 {
+                ChannelError::Disconnected
+            } else {
+                ChannelError::WouldBlock
+            });
+        }
+        let message = &self.messages[self.head];
+        if message.len > buf.len() {
+            return Err(ChannelError::MessageTooLarge);
+        }
+        buf[..message.len].copy_from_slice(&message.data[..message.len]);
+        self.head = (self.head + 1) % CAPACITY;
+        self.len -= 1;
+        Ok(message.len)
+    }
+}
+
+const EMPTY_MESSAGE: Message = Message { data: [0; MAX_MESSAGE_SIZE], len: 0 };
+const FREE_CHANNEL: Channel = Channel {
+    messages: [EMPTY_MESSAGE; CAPACITY],
+    head: 0,
+    len: 0,
+    senders: 0,
+    receivers: 0,
+};
+
+static CHANNELS: SpinLock<[Channel; MAX_CHANNELS]> = SpinLock::new([FREE_CHANNEL; MAX_CHANNELS]);
+
+/// Woken whenever a message is sent or received and whenever an end is
+/// dropped
+static CHANGED: WaitQueue = WaitQueue::new();
+
+/// The sending end of a channel
+#[derive(Debug)]
+pub struct Sender {
+    channel: usize,
+}
+
+/// The receiving end of a channel
+#[derive(Debug)]
+pub struct Receiver {
+    channel: usize,
+}
+
+/// Creates a channel and returns both of its ends
+pub fn channel() -> Result<(Sender, Receiver), ChannelError> {
+    let mut channels = CHANNELS.lock();
+    let index = channels
+        .iter()
+        .position(Channel::is_free)
+        .ok_or(ChannelError::TooManyChannels)?;
+    let channel = &mut channels[index];
+    channel.head = 0;
+    channel.len = 0;
+    channel.senders = 1;
+    channel.receivers = 1;
+    Ok((Sender { channel: index }, Receiver { channel: index }))
+}
+
+impl Sender {
+    /// Queues `message`, blocking while the channel is full
+    pub fn send(&self, message: &[u8]) -> Result<(), ChannelError> {
+        send(self.channel, message, true)
+    }
+
+    /// Queues `message`, fails with `WouldBlock` if the channel is full
+    pub fn try_send(&self, message: &[u8]) -> Result<(), ChannelError> {
+        send(self.channel, message, false)
+    }
+}
+
+impl Clone for Sender {
+    fn clone(&self) -> Self {
+        CHANNELS.lock()[self.channel].senders += 1;
+        Sender { channel: self.channel }
+    }
+}
+
+impl Drop for Sender {
+    fn drop(&mut self) {
+        CHANNELS.lock()[self.channel].senders -= 1;
+        CHANGED.wake_all();
+    }
+}
+
+impl Receiver {
+    /// Copies the oldest message into `buf` and returns its length, blocking
+    /// while the channel is empty
+    pub fn receive(&self, buf: &mut [u8]) -> Result<usize, ChannelError> {
+        receive(self.channel, buf, true)
+    }
+
+    /// Like `receive`, but fails with `WouldBlock` if the channel is empty
+    pub fn try_receive(&self, buf: &mut [u8]) -> Result<usize, ChannelError> {
+        receive(self.channel, buf, false)
+    }
+}
+
+impl Clone for Receiver {
+    fn clone(&self) -> Self {
+        CHANNELS.lock()[self.channel].receivers += 1;
+        Receiver { channel: self.channel }
+    }
+}
+
+impl Drop for Receiver {
+    fn drop(&mut self) {
+        CHANNELS.lock()[self.channel].receivers -= 1;
+        CHANGED.wake_all();
+    }
+}
+
+fn send(channel: usize, message: &[u8], block: bool) -> Result<(), ChannelError> {
+    if message.len() > MAX_MESSAGE_SIZE {
+        return Err(ChannelError::MessageTooLarge);
+    }
+    let mut result = Err(ChannelError::WouldBlock);
+    CHANGED.wait_until(|| {
+        result = CHANNELS.lock()[channel].push(message);
+        if block && result == Err(ChannelError::WouldBlock) && signal::interrupted() {
+            result = Err(ChannelError::Interrupted);
+        }
+        !block || result != Err(ChannelError::WouldBlock)
+    });
+    if result.is_ok() {
+        CHANGED.wake_all();
+    }
+    result
+}
+
+fn receive(channel: usize, buf: &mut [u8], block: bool) -> Result<usize, ChannelError> {
+    let mut result = Err(ChannelError::WouldBlock);
+    CHANGED.wait_until(|| {
+        result = CHANNELS.lock()[channel].pop(buf);
+        if block && result == Err(ChannelError::WouldBlock) && signal::interrupted() {
+            result = Err(ChannelError::Interrupted);
+        }
+        !block || result != Err(ChannelError::WouldBlock)
+    });
+    if result.is_ok() {
+        CHANGED.wake_all();
+    }
+    result
+}
+
+#[derive(Debug, Clone)]
+enum End {
+    Sender(Sender),
+    Receiver(Receiver),
+}
+
+struct HandleTable {
+    owner: TaskId,
+    ends: [Option<End>; MAX_HANDLES],
+}
+
+const NO_END: Option<End> = None;
+const NO_TABLE: Option<HandleTable> = None;
+
+/// Handle tables of the tasks that have handles open. Tables are removed
+/// when their task exits, so there is always room for every task.
+static HANDLES: SpinLock<[Option<HandleTable>; MAX_TASKS]> = SpinLock::new([NO_TABLE; MAX_TASKS]);
+
+/// Returns the handle table of `owner`, creating it if needed
+fn table_of(tables: &mut [Option<HandleTable>; MAX_TASKS], owner: TaskId) -> &mut HandleTable {
+    let slot = match tables.iter().position(|t| matches!(t, Some(table) if table.owner == owner)) {
+        Some(slot) => slot,
+        None => {
+            let slot = tables
+                .iter()
+                .position(|t| t.is_none())
+                .expect("more handle tables than tasks");
+            tables[slot] = Some(HandleTable { owner, ends: [NO_END; MAX_HANDLES] });
+            slot
+        }
+    };
+    tables[slot].as_mut().unwrap()
+}
+
+/// Creates a channel and returns a sending and a receiving handle for it,
+/// owned by the current task
+pub fn create_handles() -> Result<(u64, u64), ChannelError> {
+    let (sender, receiver) = channel()?;
+    let mut tables = HANDLES.lock();
+    let table = table_of(&mut tables, task::current_id());
+    let mut free = table.ends.iter().enumerate().filter(|(_, end)| end.is_none()).map(|(i, _)| i);
+    let (send_handle, receive_handle) = match (free.next(), free.next()) {
+        (Some(first), Some(second)) => (first, second),
+        // dropping the ends frees the channel again
+        _ => return Err(ChannelError::TooManyHandles),
+    };
+    table.ends[send_handle] = Some(End::Sender(sender));
+    table.ends[receive_handle] = Some(End::Receiver(receiver));
+    Ok((send_handle as u64, receive_handle as u64))
+}
+
+/// Returns the channel behind a handle of the current task
+fn lookup(handle: u64, sending: bool) -> Result<usize, ChannelError> {
+    let tables = HANDLES.lock();
+    let owner = task::current_id();
+    let table = tables.iter().flatten().find(|table| table.owner == owner);
+    let end = table.and_then(|table| table.ends.get(handle as usize));
+    // the handle keeps the channel open while the task uses it, as only the
+    // task itself can close it
+    match (end, sending) {
+        (Some(Some(End::Sender(sender))), true) => Ok(sender.channel),
+        (Some(Some(End::Receiver(receiver))), false) => Ok(receiver.channel),
+        _ => Err(ChannelError::BadHandle),
+    }
+}
+
+/// Sends `message` through a sending handle of the current task, see `Sender::send`
+pub fn send_by_handle(handle: u64, message: &[u8], block: bool) -> Result<(), ChannelError> {
+    send(lookup(handle, true)?, message, block)
+}
+
+/// Receives through a receiving handle of the current task, see `Receiver::receive`
+pub fn receive_by_handle(handle: u64, buf: &mut [u8], block: bool) -> Result<usize, ChannelError> {
+    receive(lookup(handle, false)?, buf, block)
+}
+
+/// Closes a handle of the current task
+pub fn close_handle(handle: u64) -> Result<(), ChannelError> {
+    let end = {
+        let mut tables = HANDLES.lock();
+        let owner = task::current_id();
+        let table = tables.iter_mut().flatten().find(|table| table.owner == owner);
+        table
+            .and_then(|table| table.ends.get_mut(handle as usize))
+            .and_then(Option::take)
+            .ok_or(ChannelError::BadHandle)?
+    };
+    drop(end);
+    Ok(())
+}
+
+/// Gives task `to` a copy of all handles of task `from`, under the same numbers
+pub(crate) fn inherit_handles(from: TaskId, to: TaskId) {
+    let mut tables = HANDLES.lock();
+    let ends = match tables.iter().flatten().find(|table| table.owner == from) {
+        Some(table) => table.ends.clone(),
+        None => return,
+    };
+    table_of(&mut tables, to).ends = ends;
+}
+
+/// Called by `task::exit` for every task before it ends, closes its handles
+pub(crate) fn task_exited(id: TaskId) {
+    let table = {
+        let mut tables = HANDLES.lock();
+        tables
+            .iter_mut()
+            .find(|t| matches!(t, Some(table) if table.owner == id))
+            .and_then(Option::take)
+    };
+    // wakes receivers blocked on the other end
+    drop(table);
+}
+
+#[test_case]
+fn test_messages_arrive_in_order() {
+    static SENDER: SpinLock<Option<Sender>> = SpinLock::new(None);
+
+    fn producer(count: usize) {
+        let sender = SENDER.lock().take().unwrap();
+        for i in 0..count {
+            // more messages than fit, so this blocks until they are received
+            sender.send(&[i as u8; 3][..i % 4]).unwrap();
+        }
+    }
+
+    let (sender, receiver) = channel().expect("no free channel");
+    *SENDER.lock() = Some(sender);
+    let count = CAPACITY * 3;
+    let id = task::spawn(producer, count).expect("spawn failed");
+    let mut buf = [0; MAX_MESSAGE_SIZE];
+    for i in 0..count {
+        let len = receiver.receive(&mut buf).unwrap();
+        assert_eq!(&buf[..len], &[i as u8; 3][..i % 4]);
+    }
+    // the sender was dropped when the producer returned
+    assert_eq!(receiver.receive(&mut buf), Err(ChannelError::Disconnected));
+    task::join(id);
+}
+
+#[test_case]
+fn test_receiver_wakes_when_sender_exits() {
+    static SENDER: SpinLock<Option<Sender>> = SpinLock::new(None);
+
+    fn exiting_sender(_: usize) {
+        let sender = SENDER.lock().take().unwrap();
+        sender.send(b"last").unwrap();
+        task::sleep(crate::interrupts::ms_to_ticks(20));
+    }
+
+    let (sender, receiver) = channel().expect("no free channel");
+    *SENDER.lock() = Some(sender);
+    let id = task::spawn(exiting_sender, 0).expect("spawn failed");
+    let mut buf = [0; 4];
+    assert_eq!(receiver.receive(&mut buf), Ok(4));
+    // blocks until the task has exited
+    assert_eq!(receiver.receive(&mut buf), Err(ChannelError::Disconnected));
+    assert_eq!(task::join(id), Some(0));
+}
+
+#[test_case]
+fn test_non_blocking_and_size_limits() {
+    let (sender, receiver) = channel().expect("no free channel");
+    let mut buf = [0; 2];
+    assert_eq!(receiver.try_receive(&mut buf), Err(ChannelError::WouldBlock));
+    assert_eq!(sender.try_send(&[0; MAX_MESSAGE_SIZE + 1]), Err(ChannelError::MessageTooLarge));
+    for _ in 0..CAPACITY {
+        sender.try_send(b"abc").unwrap();
+    }
+    assert_eq!(sender.try_send(b"abc"), Err(ChannelError::WouldBlock));
+    assert_eq!(receiver.try_receive(&mut buf), Err(ChannelError::MessageTooLarge));
+    drop(receiver);
+    assert_eq!(sender.send(b"abc"), Err(ChannelError::Disconnected));
+}
@@ -0,0 +1,172 @@
+//! Translation of UTF-8 text to code page 437
+//!
+//! The VGA text mode font has the glyphs of code page 437: ASCII, accented
+//! letters, Greek letters, math symbols and box drawing lines. `Utf8Decoder`
+//! turns bytes into characters, even when a character arrives in pieces, and
+//! `encode` finds the glyph for a character.
+
+use core::char::REPLACEMENT_CHARACTER;
+
+/// Glyph shown for characters that have none in code page 437, `■`
+pub const REPLACEMENT: u8 = 0xfe;
+
+/// Characters of the glyphs 0x01 to 0x1f, which stand for control characters
+/// in ASCII
+const LOW: [char; 31] = [
+    '☺', '☻', '♥', '♦', '♣', '♠', '•', '◘', '○', '◙', '♂', '♀', '♪', '♫', '☼', '►',
+    '◄', '↕', '‼', '¶', '§', '▬', '↨', '↑', '↓', '→', '←', '∟', '↔', '▲', '▼',
+];
+
+/// Characters of the glyphs 0x80 to 0xff
+const HIGH: [char; 128] = [
+    'Ç', 'ü', 'é', 'â', 'ä', 'à', 'å', 'ç', 'ê', 'ë', 'è', 'ï', 'î', 'ì', 'Ä', 'Å',
+    'É', 'æ', 'Æ', 'ô', 'ö', 'ò', 'û', 'ù', 'ÿ', 'Ö', 'Ü', '¢', '£', '¥', '₧', 'ƒ',
+    'á', 'í', 'ó', 'ú', 'ñ', 'Ñ', 'ª', 'º', '¿', '⌐', '¬', '½', '¼', '¡', '«', '»',
+    '░', '▒', '▓', '│', '┤', '╡', '╢', '╖', '╕', '╣', '║', '╗', '╝', '╜', '╛', '┐',
+    '└', '┴', '┬', '├', '─', '┼', '╞', '╟', '╚', '╔', '╩', '╦', '╠', '═', '╬', '╧',
+    '╨', '╤', '╥', '╙', '╘', '╒', '╓', '╫', '╪', '┘', '┌', '█', '▄', '▌', '▐', '▀',
+    'α', 'ß', 'Γ', 'π', 'Σ', 'σ', 'µ', 'τ', 'Φ', 'Θ', 'Ω', 'δ', '∞', 'φ', 'ε', '∩',
+    '≡', '±', '≥', '≤', '⌠', '⌡', '÷', '≈', '°', '∙', '·', '√', 'ⁿ', '²', '■', '\u{a0}',
+];
+
+/// Characters that look like a glyph of another character
+const ALIASES: [(char, u8); 6] = [
+    ('β', 0xe1),
+    ('μ', 0xe6),
+    ('\u{2126}', 0xea), // ohm sign
+    ('∑', 0xe4),
+    ('∈', 0xee),
+    ('⌂', 0x7f),
+];
+
+/// Returns the code page 437 glyph of `c`, `None` if there is none
+pub fn encode(c: char) -> Option<u8> {
+    if (' '..='~').contains(&c) {
+        return Some(c as u8);
+    }
+    let position = |table: &[char]| table.iter().position(|&glyph| glyph == c);
+    position(&LOW)
+        .map(|i| i as u8 + 0x01)
+        .or_else(|| position(&HIGH).map(|i| i as u8 + 0x80))
+        .or_else(|| ALIASES.iter().find(|&&(alias, _)| alias == c).map(|&(_, glyph)| glyph))
+}
+
+/// Decodes UTF-8 one byte at a time
+///
+/// Invalid and cut off sequences decode to U+FFFD, which has no glyph.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Utf8Decoder {
+    code_point: u32,
+    /// length of the sequence being decoded
+    length: u8,
+    /// continuation bytes still missing
+    remaining: u8,
+}
+
+impl Utf8Decoder {
+    pub const fn new() -> Self {
+        Utf8Decoder { code_point: 0, length: 0, remaining: 0 }
+    }
+
+    /// Feeds one byte and calls `emit` for every character it completes,
+    /// which may be two if it cuts off the previous sequence
+    pub fn push(&mut self, byte: u8, mut emit: impl FnMut(char)) {
+        if self.remaining > 0 {
+            if byte & 0xc0 == 0x80 {
+                self.code_point = self.code_point << 6 | u32::from(byte & 0x3f);
+                self.remaining -= 1;
+                if self.remaining == 0 {
+                    emit(self.finish());
+                }
+                return;
+            }
+            self.remaining = 0;
+            emit(REPLACEMENT_CHARACTER);
+        }
+        match byte {
+            0x00..=0x7f => emit(char::from(byte)),
+            0xc2..=0xdf => self.start(byte & 0x1f, 2),
+            0xe0..=0xef => self.start(byte & 0x0f, 3),
+            0xf0..=0xf4 => self.start(byte & 0x07, 4),
+            // continuation bytes without a lead byte, and bytes never used by UTF-8
+            _ => emit(REPLACEMENT_CHARACTER),
+        }
+    }
+
+    /// Drops a sequence that has not been completed yet, returning U+FFFD if
+    /// there was one
+    pub fn reset(&mut self) -> Option<char> {
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining = 0;
+        Some(REPLACEMENT_CHARACTER)
+    }
+
+    fn start(&mut self, bits: u8, length: u8) {
+        self.code_point = u32::from(bits);
+        self.length = length;
+        self.remaining = length - 1;
+    }
+
+    fn finish(&self) -> char {
+        // overlong encodings are invalid
+        let min = match self.length {
+            2 => 0x80,
+            3 => 0x800,
+            _ => 0x1_0000,
+        };
+        if self.code_point < min {
+            return REPLACEMENT_CHARACTER;
+        }
+        core::char::from_u32(self.code_point).unwrap_or(REPLACEMENT_CHARACTER)
+    }
+}
+
+impl Default for Utf8Decoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[test_case]
+fn test_encode() {
+    assert_eq!(encode('A'), Some(b'A'));
+    assert_eq!(encode('é'), Some(0x82));
+    assert_eq!(encode('ü'), Some(0x81));
+    assert_eq!(encode('°'), Some(0xf8));
+    assert_eq!(encode('─'), Some(0xc4));
+    assert_eq!(encode('♥'), Some(0x03));
+    assert_eq!(encode('μ'), Some(0xe6));
+    assert_eq!(encode('€'), None);
+    assert_eq!(encode('\n'), None);
+}
+
+#[test_case]
+fn test_decoder() {
+    fn decode(decoder: &mut Utf8Decoder, bytes: &[u8]) -> ([char; 4], usize) {
+        let mut chars = ['\0'; 4];
+        let mut len = 0;
+        for &byte in bytes {
+            decoder.push(byte, |c| {
+                chars[len] = c;
+                len += 1;
+            });
+        }
+        (chars, len)
+    }
+    let r = REPLACEMENT_CHARACTER;
+    let mut decoder = Utf8Decoder::new();
+    // "é" and "─" split between calls
+    assert_eq!(decode(&mut decoder, b"a\xc3"), (['a', '\0', '\0', '\0'], 1));
+    assert_eq!(decode(&mut decoder, b"\xa9\xe2\x94"), (['é', '\0', '\0', '\0'], 1));
+    assert_eq!(decode(&mut decoder, b"\x80"), (['─', '\0', '\0', '\0'], 1));
+    // a cut off sequence, an overlong encoding, a stray continuation byte and
+    // a surrogate
+    assert_eq!(decode(&mut decoder, b"\xc3x"), ([r, 'x', '\0', '\0'], 2));
+    assert_eq!(decode(&mut decoder, b"\xe0\x80\x80\x80"), ([r, r, '\0', '\0'], 2));
+    assert_eq!(decode(&mut decoder, b"\xed\xa0\x80"), ([r, '\0', '\0', '\0'], 1));
+    assert_eq!(decoder.reset(), None);
+    decode(&mut decoder, b"\xf0\x9f");
+    assert_eq!(decoder.reset(), Some(r));
+}
@@ -0,0 +1,354 @@
+//! Loading ELF64 executables into user space
+//!
+//! Only statically linked x86_64 executables (`ET_EXEC`) are supported. Every
+//! program gets an `AddressSpace` of its own.
+
+use x86_64::structures::paging::mapper::MapToError;
+use x86_64::structures::paging::{PageTableFlags, Size4KiB};
+use x86_64::VirtAddr;
+
+use crate::address_space::AddressSpace;
+use crate::memory::{self, USER_SPACE_END, USER_SPACE_START};
+use crate::task::{SpawnError, TaskId};
+use crate::usermode;
+
+const ELF_MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const EV_CURRENT: u8 = 1;
+const ET_EXEC: u16 = 2;
+const EM_X86_64: u16 = 62;
+const ELF_HEADER_SIZE: usize = 64;
+const PROGRAM_HEADER_SIZE: usize = 56;
+
+/// Program header type of a segment that is loaded into memory
+pub const PT_LOAD: u32 = 1;
+/// Segment flag: executable
+pub const PF_X: u32 = 1;
+/// Segment flag: writable
+pub const PF_W: u32 = 2;
+/// Segment flag: readable
+pub const PF_R: u32 = 4;
+
+/// End (exclusive) of the user stack of every loaded program
+pub const USER_STACK_TOP: u64 = USER_SPACE_END;
+/// Size of the user stack, including the arguments at its top
+pub const USER_STACK_SIZE: u64 = 4096 * 16;
+/// Space at the top of the stack for the argument and environment strings
+/// and the pointers to them
+const ARGS_SIZE: usize = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfError {
+    /// the file is shorter than its headers claim
+    Truncated,
+    BadMagic,
+    /// not a little endian ELF64 file of the current version
+    UnsupportedFormat,
+    NotExecutable,
+    WrongMachine,
+    /// a loadable segment lies outside of user space or has bad sizes
+    BadSegment,
+    /// the entry point is not in an executable segment
+    BadEntry,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoadError {
+    Elf(ElfError),
+    OutOfMemory,
+    /// two segments (or a segment and the stack) share a page
+    OverlappingSegments,
+    /// argv and envp do not fit into the space reserved for them
+    ArgumentsTooLarge,
+    Spawn(SpawnError),
+}
+
+impl From<ElfError> for LoadError {
+    fn from(error: ElfError) -> Self {
+        LoadError::Elf(error)
+    }
+}
+
+impl From<MapToError<Size4KiB>> for LoadError {
+    fn from(error: MapToError<Size4KiB>) -> Self {
+        match error {
+            MapToError::FrameAllocationFailed => LoadError::OutOfMemory,
+            MapToError::PageAlreadyMapped(_) | MapToError::ParentEntryHugePage => {
+                LoadError::OverlappingSegments
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProgramHeader {
+    /// `p_type`, e.g. `PT_LOAD`
+    pub kind: u32,
+    /// `PF_R`, `PF_W` and `PF_X` bits
+    pub flags: u32,
+    pub offset: u64,
+    pub vaddr: u64,
+    pub file_size: u64,
+    pub mem_size: u64,
+}
+
+impl ProgramHeader {
+    fn contains(&self, addr: u64) -> bool {
+        addr >= self.vaddr && addr - self.vaddr < self.mem_size
+    }
+}
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(&data[offset..offset + 4]);
+    u32::from_le_bytes(bytes)
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&data[offset..offset + 8]);
+    u64::from_le_bytes(bytes)
+}
+
+/// A validated ELF64 executable
+pub struct ElfFile<'a> {
+    data: &'a [u8],
+    entry: u64,
+    ph_offset: usize,
+    ph_count: usize,
+}
+
+impl<'a> ElfFile<'a> {
+    /// Checks the headers of `data`
+    ///
+    /// All loadable segments must lie within the file and within user space,
+    /// so `load` does not have to check anything else.
+    pub fn parse(data: &'a [u8]) -> Result<Self, ElfError> {
+        if data.len() < ELF_HEADER_SIZE {
+            return Err(ElfError::Truncated);
+        }
+        if data[0..4] != ELF_MAGIC {
+            return Err(ElfError::BadMagic);
+        }
+        if data[4] != ELFCLASS64 || data[5] != ELFDATA2LSB || data[6] != EV_CURRENT {
+            return Err(ElfError::UnsupportedFormat);
+        }
+        if read_u16(data, 16) != ET_EXEC {
+            return Err(ElfError::NotExecutable);
+        }
+        if read_u16(data, 18) != EM_X86_64 {
+            return Err(ElfError::WrongMachine);
+        }
+
+        let ph_count = usize::from(read_u16(data, 56));
+        if ph_count > 0 && usize::from(read_u16(data, 54)) != PROGRAM_HEADER_SIZE {
+            return Err(ElfError::UnsupportedFormat);
+        }
+        let ph_offset = read_u64(data, 32);
+        let ph_end = ph_offset.checked_add((ph_count * PROGRAM_HEADER_SIZE) as u64);
+        if !matches!(ph_end, Some(end) if end <= data.len() as u64) {
+            return Err(ElfError::Truncated);
+        }
+
+        let file = ElfFile {
+            data,
+            entry: read_u64(data, 24),
+            ph_offset: ph_offset as usize,
+            ph_count,
+        };
+        for ph in file.loadable_segments() {
+            let file_end = ph.offset.checked_add(ph.file_size);
+            let mem_end = ph.vaddr.checked_add(ph.mem_size);
+            if ph.file_size > ph.mem_size
+                || !matches!(file_end, Some(end) if end <= data.len() as u64)
+                || ph.vaddr < USER_SPACE_START
+                || !matches!(mem_end, Some(end) if end <= USER_SPACE_END)
+            {
+                return Err(ElfError::BadSegment);
+            }
+        }
+        if !file.loadable_segments().any(|ph| ph.flags & PF_X != 0 && ph.contains(file.entry)) {
+            return Err(ElfError::BadEntry);
+        }
+        Ok(file)
+    }
+
+    pub fn entry(&self) -> VirtAddr {
+        VirtAddr::new(self.entry)
+    }
+
+    pub fn program_headers(&self) -> impl Iterator<Item = ProgramHeader> + 'a {
+        let data = self.data;
+        let ph_offset = self.ph_offset;
+        (0..self.ph_count).map(move |i| {
+            let offset = ph_offset + i * PROGRAM_HEADER_SIZE;
+            ProgramHeader {
+                kind: read_u32(data, offset),
+                flags: read_u32(data, offset + 4),
+                offset: read_u64(data, offset + 8),
+                vaddr: read_u64(data, offset + 16),
+                file_size: read_u64(data, offset + 32),
+                mem_size: read_u64(data, offset + 40),
+            }
+        })
+    }
+
+    /// Returns the `PT_LOAD` segments that take up memory
+    pub fn loadable_segments(&self) -> impl Iterator<Item = ProgramHeader> + 'a {
+        self.program_headers()
+            .filter(|ph| ph.kind == PT_LOAD && ph.mem_size > 0)
+    }
+
+    /// Returns the bytes of the segment that are stored in the file
+    pub fn segment_data(&self, ph: &ProgramHeader) -> &'a [u8] {
+        &self.data[ph.offset as usize..(ph.offset + ph.file_size) as usize]
+    }
+}
+
+/// A program loaded by `load`, ready to be started
+#[derive(Debug)]
+pub struct LoadedProgram {
+    pub address_space: AddressSpace,
+    pub entry: VirtAddr,
+    /// initial stack pointer, pointing to `argc`
+    pub stack_pointer: VirtAddr,
+}
+
+/// Loads the executable in `data` into a new address space
+///
+/// The segments are mapped with the permissions from their program headers
+/// (`NO_EXECUTE` only if the CPU has it enabled). The stack is laid out as
+/// the System V ABI expects at `_start`: `argc`, the `argv` pointers, a null
+/// pointer, the `envp` pointers, a null pointer and an empty auxiliary vector.
+pub fn load(data: &[u8], argv: &[&str], envp: &[&str]) -> Result<LoadedProgram, LoadError> {
+    let elf = ElfFile::parse(data)?;
+    let (args, args_offset) = build_arguments(argv, envp)?;
+
+    // dropping it on an error frees everything mapped so far
+    let mut address_space = AddressSpace::new().ok_or(LoadError::OutOfMemory)?;
+    let no_execute = memory::no_execute();
+
+    for ph in elf.loadable_segments() {
+        let mut flags = PageTableFlags::empty();
+        if ph.flags & PF_W != 0 {
+            flags |= PageTableFlags::WRITABLE;
+        }
+        if ph.flags & PF_X == 0 {
+            flags |= no_execute;
+        }
+        address_space.map_user_region(
+            VirtAddr::new(ph.vaddr), ph.mem_size, elf.segment_data(&ph), flags,
+        )?;
+    }
+
+    let stack_flags = PageTableFlags::WRITABLE | no_execute;
+    let args_start = USER_STACK_TOP - ARGS_SIZE as u64;
+    address_space.map_user_region(
+        VirtAddr::new(USER_STACK_TOP - USER_STACK_SIZE),
+        USER_STACK_SIZE - ARGS_SIZE as u64,
+        &[],
+        stack_flags,
+    )?;
+    address_space.map_user_region(VirtAddr::new(args_start), ARGS_SIZE as u64, &args, stack_flags)?;
+
+    Ok(LoadedProgram {
+        address_space,
+        entry: elf.entry(),
+        stack_pointer: VirtAddr::new(args_start + args_offset as u64),
+    })
+}
+
+/// Loads the executable in `data` and starts it in a new task
+///
+/// The address space of the program is freed when it exits.
+pub fn spawn(data: &[u8], argv: &[&str], envp: &[&str]) -> Result<TaskId, LoadError> {
+    let program = load(data, argv, envp)?;
+    usermode::spawn_user_task(program.address_space, program.entry, program.stack_pointer)
+        .map_err(LoadError::Spawn)
+}
+
+/// Builds the top `ARGS_SIZE` bytes of the user stack
+///
+/// Returns the bytes and the offset of the initial stack pointer in them.
+fn build_arguments(argv: &[&str], envp: &[&str]) -> Result<([u8; ARGS_SIZE], usize), LoadError> {
+    let strings_size: usize = argv.iter().chain(envp).map(|s| s.len() + 1).sum();
+    // argc, two null pointers and the two words of the AT_NULL entry
+    let words = argv.len() + envp.len() + 5;
+    let strings_start = ARGS_SIZE
+        .checked_sub(strings_size)
+        .ok_or(LoadError::ArgumentsTooLarge)?;
+    let stack_pointer = strings_start
+        .checked_sub(words * 8)
+        .ok_or(LoadError::ArgumentsTooLarge)?
+        & !0xf;
+
+    let mut args = [0; ARGS_SIZE];
+    let base = USER_STACK_TOP - ARGS_SIZE as u64;
+    let mut word = stack_pointer;
+    let mut string = strings_start;
+    let mut push_word = |args: &mut [u8; ARGS_SIZE], value: u64| {
+        args[word..word + 8].copy_from_slice(&value.to_le_bytes());
+        word += 8;
+    };
+
+    push_word(&mut args, argv.len() as u64);
+    for list in [argv, envp] {
+        for s in list {
+            push_word(&mut args, base + string as u64);
+            // the terminating null byte is already there
+            args[string..string + s.len()].copy_from_slice(s.as_bytes());
+            string += s.len() + 1;
+        }
+        push_word(&mut args, 0);
+    }
+    push_word(&mut args, 0);
+    push_word(&mut args, 0);
+    Ok((args, stack_pointer))
+}
+
+#[test_case]
+fn test_parse_rejects_bad_headers() {
+    assert!(matches!(ElfFile::parse(&[0x7f, b'E', b'L']), Err(ElfError::Truncated)));
+    assert!(matches!(ElfFile::parse(&[0; ELF_HEADER_SIZE]), Err(ElfError::BadMagic)));
+
+    let mut header = [0; ELF_HEADER_SIZE];
+    header[0..4].copy_from_slice(&ELF_MAGIC);
+    header[4] = 1; // ELFCLASS32
+    header[5] = ELFDATA2LSB;
+    header[6] = EV_CURRENT;
+    assert!(matches!(ElfFile::parse(&header), Err(ElfError::UnsupportedFormat)));
+    header[4] = ELFCLASS64;
+    assert!(matches!(ElfFile::parse(&header), Err(ElfError::NotExecutable)));
+    header[16] = ET_EXEC as u8;
+    assert!(matches!(ElfFile::parse(&header), Err(ElfError::WrongMachine)));
+    header[18] = EM_X86_64 as u8;
+    // no segments, so nothing contains the entry point
+    assert!(matches!(ElfFile::parse(&header), Err(ElfError::BadEntry)));
+}
+
+#[test_case]
+fn test_parse_test_program() {
+    let elf = ElfFile::parse(include_bytes!("../tests/elf/data.elf")).expect("parse failed");
+    let writable = elf.loadable_segments().filter(|ph| ph.flags & PF_W != 0).count();
+    assert_eq!(writable, 1);
+    assert!(elf.entry().as_u64() >= USER_SPACE_START);
+}
+
+#[test_case]
+fn test_arguments_layout() {
+    let (args, sp) = build_arguments(&["prog", "x"], &["A=1"]).unwrap();
+    assert_eq!(sp % 16, 0);
+    let word = |i: usize| read_u64(&args, sp + i * 8);
+    assert_eq!(word(0), 2);
+    assert_eq!(word(3), 0);
+    assert_eq!(word(5), 0);
+    let base = USER_STACK_TOP - ARGS_SIZE as u64;
+    let env = (word(4) - base) as usize;
+    assert_eq!(&args[env..env + 4], b"A=1\0");
+    assert!(build_arguments(&["x"; 600], &[]).is_err());
+}
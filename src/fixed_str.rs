@@ -0,0 +1,53 @@
+//! Strings of at most `N` bytes, stored inline
+//!
+//! `FixedStr` is written to with `write!` and cuts off whatever does not fit,
+//! always at a character boundary, so it can be formatted into without an
+//! allocator and without failing.
+
+use core::fmt::{self, Write};
+
+#[derive(Clone, Copy)]
+pub struct FixedStr<const N: usize> {
+    bytes: [u8; N],
+    len: usize,
+}
+
+impl<const N: usize> FixedStr<N> {
+    pub const fn new() -> Self {
+        FixedStr { bytes: [0; N], len: 0 }
+    }
+
+    pub fn as_str(&self) -> &str {
+        // `write_str` only cuts at character boundaries
+        core::str::from_utf8(&self.bytes[..self.len]).unwrap_or("")
+    }
+}
+
+impl<const N: usize> Default for FixedStr<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> Write for FixedStr<N> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut end = s.len().min(N - self.len);
+        while !s.is_char_boundary(end) {
+            end -= 1;
+        }
+        self.bytes[self.len..self.len + end].copy_from_slice(&s.as_bytes()[..end]);
+        self.len += end;
+        Ok(())
+    }
+}
+
+#[test_case]
+fn test_cut_at_character_boundary() {
+    let mut text = FixedStr::<9>::new();
+    write!(text, "ab{}", 12).unwrap();
+    write!(text, "éé").unwrap();
+    assert_eq!(text.as_str(), "ab12éé");
+    // one byte is left, too few for "é"
+    write!(text, "xé").unwrap();
+    assert_eq!(text.as_str(), "ab12ééx");
+}
@@ -1,3 +1,5 @@
+use core::ptr::addr_of;
+
 use x86_64::VirtAddr;
 use x86_64::structures::gdt::{GlobalDescriptorTable, Descriptor, SegmentSelector};
 use x86_64::structures::tss::TaskStateSegment;
@@ -5,19 +7,29 @@ use lazy_static::lazy_static;
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
 
-lazy_static! {
-    static ref TSS: TaskStateSegment = {
-        let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
+/// The TSS is mutable because `privilege_stack_table[0]`, the stack the CPU
+/// switches to when an interrupt arrives in user mode, follows the running
+/// task (see `set_kernel_stack`).
+static mut TSS: TaskStateSegment = TaskStateSegment::new();
+
+fn init_tss() {
+    unsafe {
+        TSS.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
             const STACK_SIZE: usize = 4096 * 5;
             static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
 
-            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
+            let stack_start = VirtAddr::from_ptr(addr_of!(STACK));
             let stack_end = stack_start + STACK_SIZE;
             stack_end
         };
-        tss
-    };
+        // used until the scheduler switches to a task with its own stack
+        TSS.privilege_stack_table[0] = {
+            const STACK_SIZE: usize = 4096 * 5;
+            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+
+            VirtAddr::from_ptr(addr_of!(STACK)) + STACK_SIZE
+        };
+    }
 }
 
 lazy_static! {
@@ -27,25 +39,62 @@ lazy_static! {
         // gdt.add_entry(Descriptor::kernel_code_segment());
         // gdt.add_entry(Descriptor::tss_segment(&TSS));
         // gdt
+        // the order of the code and data segments is fixed by `syscall`/`sysret`:
+        // kernel data must follow kernel code, user code must follow user data
         let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
-        (gdt, Selectors { code_selector, tss_selector })
+        let data_selector = gdt.add_entry(Descriptor::kernel_data_segment());
+        let tss_selector = gdt.add_entry(Descriptor::tss_segment(unsafe { &*addr_of!(TSS) }));
+        let user_data_selector = gdt.add_entry(Descriptor::user_data_segment());
+        let user_code_selector = gdt.add_entry(Descriptor::user_code_segment());
+        (gdt, Selectors {
+            code_selector,
+            data_selector,
+            tss_selector,
+            user_data_selector,
+            user_code_selector,
+        })
     };
 }
 
 struct Selectors {
     code_selector: SegmentSelector,
+    data_selector: SegmentSelector,
     tss_selector: SegmentSelector,
+    user_data_selector: SegmentSelector,
+    user_code_selector: SegmentSelector,
 }
 
 pub fn init() {
     // GDT.load();
     use x86_64::instructions::tables::load_tss;
-    use x86_64::instructions::segmentation::{CS, Segment};
+    use x86_64::instructions::segmentation::{CS, DS, ES, SS, Segment};
 
+    init_tss();
     GDT.0.load();
     unsafe {
         CS::set_reg(GDT.1.code_selector);
+        // the bootloader's selectors index into its own GDT, not this one
+        SS::set_reg(GDT.1.data_selector);
+        DS::set_reg(GDT.1.data_selector);
+        ES::set_reg(GDT.1.data_selector);
         load_tss(GDT.1.tss_selector);
     }
-}
\ No newline at end of file
+}
+
+/// Returns the code and data selectors for ring 0
+pub fn kernel_selectors() -> (SegmentSelector, SegmentSelector) {
+    (GDT.1.code_selector, GDT.1.data_selector)
+}
+
+/// Returns the code and data selectors for ring 3, with the RPL set to 3
+pub fn user_selectors() -> (SegmentSelector, SegmentSelector) {
+    (GDT.1.user_code_selector, GDT.1.user_data_selector)
+}
+
+/// Sets the stack the CPU switches to when an interrupt arrives in user mode
+///
+/// The scheduler calls this with the top of the kernel stack of every task
+/// it switches to.
+pub fn set_kernel_stack(stack_top: VirtAddr) {
+    unsafe { TSS.privilege_stack_table[0] = stack_top };
+}
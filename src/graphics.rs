@@ -0,0 +1,439 @@
+//! VGA mode 13h, 320x200 pixels with 256 colours
+//!
+//! `enter` programs the VGA registers for mode 13h and returns a
+//! `Framebuffer` to draw on; dropping it programs the registers of the text
+//! mode the card was in before. Text output goes on meanwhile, to the
+//! consoles' own screens, and shows up again afterwards.
+//!
+//! In mode 13h each pixel is a byte at 0xa0000, chained across the four
+//! planes, which overwrites the text mode font in plane 2. The font is
+//! therefore saved on entry and restored on exit; it is also what
+//! `Framebuffer::draw_text` draws with. Colours 0 to 15 are set up like the
+//! text mode colours, so `Color as u8` works, followed by a 6x6x6 colour cube
+//! (see `cube_color`) and 24 shades of gray.
+
+use volatile::Volatile;
+use x86_64::instructions::port::Port;
+
+use crate::cp437;
+use crate::sync::SpinLock;
+use crate::vga_buffer;
+
+pub const WIDTH: usize = 320;
+pub const HEIGHT: usize = 200;
+/// Width of a character drawn by `draw_text`
+pub const GLYPH_WIDTH: usize = 8;
+/// Height of a character drawn by `draw_text`
+pub const GLYPH_HEIGHT: usize = 16;
+
+const MISC_WRITE: u16 = 0x3C2;
+const MISC_READ: u16 = 0x3CC;
+const SEQ_INDEX: u16 = 0x3C4;
+const SEQ_DATA: u16 = 0x3C5;
+const CRTC_INDEX: u16 = 0x3D4;
+const CRTC_DATA: u16 = 0x3D5;
+const GC_INDEX: u16 = 0x3CE;
+const GC_DATA: u16 = 0x3CF;
+/// Index and data port of the attribute controller, which alternate
+const AC_WRITE: u16 = 0x3C0;
+const AC_READ: u16 = 0x3C1;
+/// Reading the input status register resets the attribute controller to
+/// expect an index
+const INPUT_STATUS: u16 = 0x3DA;
+/// Bit of the attribute controller index that turns the display on
+const AC_VIDEO_ENABLE: u8 = 0x20;
+const DAC_READ_INDEX: u16 = 0x3C7;
+const DAC_WRITE_INDEX: u16 = 0x3C8;
+const DAC_DATA: u16 = 0x3C9;
+
+/// Bytes per character in the font plane, of which a 8x16 font uses 16
+const FONT_STRIDE: usize = 32;
+const FONT_SIZE: usize = 256 * FONT_STRIDE;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GraphicsError {
+    /// there is a `Framebuffer` already
+    AlreadyActive,
+}
+
+/// Values of the VGA registers that make up a mode
+#[derive(Debug, Clone, Copy)]
+struct Registers {
+    misc: u8,
+    sequencer: [u8; 5],
+    crtc: [u8; 25],
+    graphics: [u8; 9],
+    attribute: [u8; 21],
+}
+
+const MODE_13H: Registers = Registers {
+    misc: 0x63,
+    sequencer: [0x03, 0x01, 0x0F, 0x00, 0x0E],
+    crtc: [
+        0x5F, 0x4F, 0x50, 0x82, 0x54, 0x80, 0xBF, 0x1F, 0x00, 0x41, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x9C, 0x0E, 0x8F, 0x28, 0x40, 0x96, 0xB9, 0xA3, 0xFF,
+    ],
+    graphics: [0x00, 0x00, 0x00, 0x00, 0x00, 0x40, 0x05, 0x0F, 0xFF],
+    attribute: [
+        0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0A, 0x0B, 0x0C, 0x0D,
+        0x0E, 0x0F, 0x41, 0x00, 0x0F, 0x00, 0x00,
+    ],
+};
+
+fn read_port(port: u16) -> u8 {
+    let mut port: Port<u8> = Port::new(port);
+    unsafe { port.read() }
+}
+
+fn write_port(port: u16, value: u8) {
+    let mut port: Port<u8> = Port::new(port);
+    unsafe { port.write(value) }
+}
+
+fn read_indexed(index_port: u16, data_port: u16, index: u8) -> u8 {
+    write_port(index_port, index);
+    read_port(data_port)
+}
+
+fn write_indexed(index_port: u16, data_port: u16, index: u8, value: u8) {
+    write_port(index_port, index);
+    write_port(data_port, value);
+}
+
+impl Registers {
+    fn read() -> Registers {
+        let mut registers = Registers {
+            misc: read_port(MISC_READ),
+            sequencer: [0; 5],
+            crtc: [0; 25],
+            graphics: [0; 9],
+            attribute: [0; 21],
+        };
+        for (i, value) in registers.sequencer.iter_mut().enumerate() {
+            *value = read_indexed(SEQ_INDEX, SEQ_DATA, i as u8);
+        }
+        for (i, value) in registers.crtc.iter_mut().enumerate() {
+            *value = read_indexed(CRTC_INDEX, CRTC_DATA, i as u8);
+        }
+        for (i, value) in registers.graphics.iter_mut().enumerate() {
+            *value = read_indexed(GC_INDEX, GC_DATA, i as u8);
+        }
+        for (i, value) in registers.attribute.iter_mut().enumerate() {
+            read_port(INPUT_STATUS);
+            *value = read_indexed(AC_WRITE, AC_READ, i as u8);
+        }
+        read_port(INPUT_STATUS);
+        write_port(AC_WRITE, AC_VIDEO_ENABLE);
+        registers
+    }
+
+    fn write(&self) {
+        write_port(MISC_WRITE, self.misc);
+        for (i, &value) in self.sequencer.iter().enumerate() {
+            write_indexed(SEQ_INDEX, SEQ_DATA, i as u8, value);
+        }
+        // CRTC registers 0 to 7 are write protected by bit 7 of register 0x11
+        let mut crtc = self.crtc;
+        crtc[0x11] &= !0x80;
+        write_indexed(CRTC_INDEX, CRTC_DATA, 0x11, crtc[0x11]);
+        for (i, &value) in crtc.iter().enumerate() {
+            write_indexed(CRTC_INDEX, CRTC_DATA, i as u8, value);
+        }
+        for (i, &value) in self.graphics.iter().enumerate() {
+            write_indexed(GC_INDEX, GC_DATA, i as u8, value);
+        }
+        for (i, &value) in self.attribute.iter().enumerate() {
+            read_port(INPUT_STATUS);
+            write_indexed(AC_WRITE, AC_WRITE, i as u8, value);
+        }
+        read_port(INPUT_STATUS);
+        write_port(AC_WRITE, AC_VIDEO_ENABLE);
+    }
+}
+
+/// Runs `f` on plane 2, where text modes keep the font, mapped flat to
+/// 0xa0000; writes only go to plane 2
+fn with_font_plane<R>(f: impl FnOnce(&mut [Volatile<u8>; FONT_SIZE]) -> R) -> R {
+    let map_mask = read_indexed(SEQ_INDEX, SEQ_DATA, 2);
+    let memory_mode = read_indexed(SEQ_INDEX, SEQ_DATA, 4);
+    let read_map = read_indexed(GC_INDEX, GC_DATA, 4);
+    let graphics_mode = read_indexed(GC_INDEX, GC_DATA, 5);
+    let misc = read_indexed(GC_INDEX, GC_DATA, 6);
+
+    write_indexed(SEQ_INDEX, SEQ_DATA, 2, 1 << 2);
+    // sequential addressing instead of odd/even
+    write_indexed(SEQ_INDEX, SEQ_DATA, 4, memory_mode | 0x04);
+    write_indexed(GC_INDEX, GC_DATA, 4, 2);
+    write_indexed(GC_INDEX, GC_DATA, 5, graphics_mode & !0x13);
+    // 64 KiB at 0xa0000 without chaining
+    write_indexed(GC_INDEX, GC_DATA, 6, (misc & !0x0E) | 0x04);
+
+    let plane = unsafe { &mut *(0xa0000 as *mut [Volatile<u8>; FONT_SIZE]) };
+    let result = f(plane);
+
+    write_indexed(SEQ_INDEX, SEQ_DATA, 2, map_mask);
+    write_indexed(SEQ_INDEX, SEQ_DATA, 4, memory_mode);
+    write_indexed(GC_INDEX, GC_DATA, 4, read_map);
+    write_indexed(GC_INDEX, GC_DATA, 5, graphics_mode);
+    write_indexed(GC_INDEX, GC_DATA, 6, misc);
+    result
+}
+
+fn read_palette(palette: &mut [[u8; 3]; 256]) {
+    write_port(DAC_READ_INDEX, 0);
+    for color in palette.iter_mut() {
+        for component in color.iter_mut() {
+            *component = read_port(DAC_DATA);
+        }
+    }
+}
+
+fn write_palette(palette: &[[u8; 3]; 256]) {
+    write_port(DAC_WRITE_INDEX, 0);
+    for color in palette.iter() {
+        for &component in color.iter() {
+            write_port(DAC_DATA, component);
+        }
+    }
+}
+
+/// Colours 0 to 15 of the palette, those of text mode, in 6 bit components
+const TEXT_COLORS: [[u8; 3]; 16] = [
+    [0, 0, 0],
+    [0, 0, 42],
+    [0, 42, 0],
+    [0, 42, 42],
+    [42, 0, 0],
+    [42, 0, 42],
+    [42, 21, 0],
+    [42, 42, 42],
+    [21, 21, 21],
+    [21, 21, 63],
+    [21, 63, 21],
+    [21, 63, 63],
+    [63, 21, 21],
+    [63, 21, 63],
+    [63, 63, 21],
+    [63, 63, 63],
+];
+
+/// First colour of the 6x6x6 colour cube
+const CUBE_START: u8 = 16;
+/// First of the 24 shades of gray
+const GRAY_START: u8 = CUBE_START + 216;
+
+fn default_palette() -> [[u8; 3]; 256] {
+    let mut palette = [[0; 3]; 256];
+    palette[..16].copy_from_slice(&TEXT_COLORS);
+    let cube = &mut palette[usize::from(CUBE_START)..usize::from(GRAY_START)];
+    for (i, color) in cube.iter_mut().enumerate() {
+        let level = |step: usize| (step * 63 / 5) as u8;
+        *color = [level(i / 36), level(i / 6 % 6), level(i % 6)];
+    }
+    for (i, color) in palette[usize::from(GRAY_START)..].iter_mut().enumerate() {
+        let level = (i * 63 / 23) as u8;
+        *color = [level; 3];
+    }
+    palette
+}
+
+/// Returns the colour of the colour cube with the given red, green and blue
+/// levels, each from 0 to 5
+pub fn cube_color(red: u8, green: u8, blue: u8) -> u8 {
+    CUBE_START + red.min(5) * 36 + green.min(5) * 6 + blue.min(5)
+}
+
+/// What `enter` saves to restore text mode
+struct Saved {
+    active: bool,
+    registers: Option<Registers>,
+    palette: [[u8; 3]; 256],
+    font: [u8; FONT_SIZE],
+}
+
+static SAVED: SpinLock<Saved> = SpinLock::new(Saved {
+    active: false,
+    registers: None,
+    palette: [[0; 3]; 256],
+    font: [0; FONT_SIZE],
+});
+
+/// Switches to mode 13h, cleared to colour 0
+///
+/// Fails if there is a `Framebuffer` already.
+pub fn enter() -> Result<Framebuffer, GraphicsError> {
+    let mut saved = SAVED.lock();
+    if saved.active {
+        return Err(GraphicsError::AlreadyActive);
+    }
+    saved.active = true;
+    vga_buffer::detach_screen();
+
+    saved.registers = Some(Registers::read());
+    read_palette(&mut saved.palette);
+    let font = &mut saved.font;
+    with_font_plane(|plane| {
+        for (byte, cell) in font.iter_mut().zip(plane.iter()) {
+            *byte = cell.read();
+        }
+    });
+
+    MODE_13H.write();
+    write_palette(&default_palette());
+    let mut framebuffer = Framebuffer {
+        pixels: unsafe { &mut *(0xa0000 as *mut [[Volatile<u8>; WIDTH]; HEIGHT]) },
+    };
+    framebuffer.clear(0);
+    Ok(framebuffer)
+}
+
+/// Goes back to the text mode saved by `enter`
+fn leave() {
+    let mut saved = SAVED.lock();
+    if let Some(registers) = saved.registers {
+        registers.write();
+    }
+    let font = &saved.font;
+    with_font_plane(|plane| {
+        for (&byte, cell) in font.iter().zip(plane.iter_mut()) {
+            cell.write(byte);
+        }
+    });
+    write_palette(&saved.palette);
+    vga_buffer::attach_screen();
+    saved.active = false;
+}
+
+/// The screen in mode 13h, one byte per pixel; text mode comes back when it
+/// is dropped
+///
+/// Drawing is clipped to the screen.
+pub struct Framebuffer {
+    pixels: &'static mut [[Volatile<u8>; WIDTH]; HEIGHT],
+}
+
+impl Framebuffer {
+    /// Fills the whole screen with `color`
+    pub fn clear(&mut self, color: u8) {
+        for row in self.pixels.iter_mut() {
+            for pixel in row.iter_mut() {
+                pixel.write(color);
+            }
+        }
+    }
+
+    pub fn set_pixel(&mut self, x: usize, y: usize, color: u8) {
+        if x < WIDTH && y < HEIGHT {
+            self.pixels[y][x].write(color);
+        }
+    }
+
+    /// Returns the colour of a pixel, `None` if it is off the screen
+    pub fn pixel(&self, x: usize, y: usize) -> Option<u8> {
+        if x < WIDTH && y < HEIGHT {
+            Some(self.pixels[y][x].read())
+        } else {
+            None
+        }
+    }
+
+    /// Draws a line from `(x0, y0)` to `(x1, y1)`, both ends included
+    ///
+    /// The ends may be off the screen.
+    pub fn draw_line(&mut self, x0: isize, y0: isize, x1: isize, y1: isize, color: u8) {
+        // Bresenham's algorithm, for all octants
+        let (dx, dy) = ((x1 - x0).abs(), -(y1 - y0).abs());
+        let (step_x, step_y) = (if x0 < x1 { 1 } else { -1 }, if y0 < y1 { 1 } else { -1 });
+        let (mut x, mut y, mut error) = (x0, y0, dx + dy);
+        loop {
+            if x >= 0 && y >= 0 {
+                self.set_pixel(x as usize, y as usize, color);
+            }
+            if x == x1 && y == y1 {
+                break;
+            }
+            if 2 * error >= dy {
+                error += dy;
+                x += step_x;
+            }
+            if 2 * error <= dx {
+                error += dx;
+                y += step_y;
+            }
+        }
+    }
+
+    /// Draws the outline of a `width` by `height` rectangle with its top left
+    /// corner at `(x, y)`
+    pub fn draw_rect(&mut self, x: usize, y: usize, width: usize, height: usize, color: u8) {
+        if width == 0 || height == 0 {
+            return;
+        }
+        let (right, bottom) = (x + width - 1, y + height - 1);
+        self.fill_rect(x, y, width, 1, color);
+        self.fill_rect(x, bottom, width, 1, color);
+        self.fill_rect(x, y, 1, height, color);
+        self.fill_rect(right, y, 1, height, color);
+    }
+
+    /// Fills a `width` by `height` rectangle with its top left corner at
+    /// `(x, y)`
+    pub fn fill_rect(&mut self, x: usize, y: usize, width: usize, height: usize, color: u8) {
+        for row in self.pixels.iter_mut().skip(y).take(height) {
+            for pixel in row.iter_mut().skip(x).take(width) {
+                pixel.write(color);
+            }
+        }
+    }
+
+    /// Draws `s` with its top left corner at `(x, y)` in the text mode font,
+    /// `GLYPH_WIDTH` by `GLYPH_HEIGHT` pixels per character
+    ///
+    /// `background` fills the rest of each character cell, or leaves it alone
+    /// if `None`. Characters are mapped like on the text screen, see `cp437`;
+    /// the text does not wrap.
+    pub fn draw_text(&mut self, x: usize, y: usize, s: &str, color: u8, background: Option<u8>) {
+        let saved = SAVED.lock();
+        for (i, c) in s.chars().enumerate() {
+            let glyph = usize::from(cp437::encode(c).unwrap_or(cp437::REPLACEMENT));
+            let left = x + i * GLYPH_WIDTH;
+            if left >= WIDTH {
+                break;
+            }
+            let rows = &saved.font[glyph * FONT_STRIDE..glyph * FONT_STRIDE + GLYPH_HEIGHT];
+            for (dy, &bits) in rows.iter().enumerate() {
+                for dx in 0..GLYPH_WIDTH {
+                    // the leftmost pixel is the highest bit
+                    match (bits & (0x80 >> dx) != 0, background) {
+                        (true, _) => self.set_pixel(left + dx, y + dy, color),
+                        (false, Some(background)) => self.set_pixel(left + dx, y + dy, background),
+                        (false, None) => {}
+                    }
+                }
+            }
+        }
+    }
+
+    /// Returns the rows of the font's glyph for code page 437 character
+    /// `glyph`, the top one first and the leftmost pixel in the highest bit
+    pub fn glyph(&self, glyph: u8) -> [u8; GLYPH_HEIGHT] {
+        let start = usize::from(glyph) * FONT_STRIDE;
+        let mut rows = [0; GLYPH_HEIGHT];
+        rows.copy_from_slice(&SAVED.lock().font[start..start + GLYPH_HEIGHT]);
+        rows
+    }
+
+    /// Sets colour `index` of the palette; the components go from 0 to 63
+    pub fn set_palette(&mut self, index: u8, red: u8, green: u8, blue: u8) {
+        write_port(DAC_WRITE_INDEX, index);
+        for &component in [red, green, blue].iter() {
+            write_port(DAC_DATA, component.min(63));
+        }
+    }
+}
+
+impl Drop for Framebuffer {
+    fn drop(&mut self) {
+        leave();
+    }
+}
@@ -1,13 +1,17 @@
 use x86_64::structures::idt::PageFaultErrorCode;
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
 
-use crate::{print, hlt_loop};
-use crate::println;
+use crate::hlt_loop;
+use log::{error, warn};
 use crate::gdt;
+use crate::sync::SpinLock;
+use crate::signal::{self, Signal};
+use crate::{address_space, shell, status_bar, syscall, task, usermode, vga_buffer};
+use x86_64::{PrivilegeLevel, VirtAddr};
+use core::sync::atomic::{AtomicU64, AtomicU8, Ordering};
 use lazy_static::lazy_static;
 
 use pic8259::ChainedPics;
-use spin;
 
 pub const PIC_1_OFFSET: u8 = 32;
 pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
@@ -23,8 +27,82 @@ pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
 /// Secondary ATA ----> |____________|   Parallel Port 1----> |____________|
 
 
-pub static PICS: spin::Mutex<ChainedPics> =
-    spin::Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
+pub static PICS: SpinLock<ChainedPics> =
+    SpinLock::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
+
+/// Number of timer interrupts since boot
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// Returns the number of timer interrupts since boot
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Input clock of the programmable interval timer, in Hz
+const PIT_BASE_FREQUENCY: u64 = 1_193_182;
+/// The PIT is left at its default divisor, which gives about 18.2 ticks per second
+const PIT_DIVISOR: u64 = 65536;
+
+/// Converts timer ticks to milliseconds
+pub fn ticks_to_ms(ticks: u64) -> u64 {
+    ticks * PIT_DIVISOR * 1000 / PIT_BASE_FREQUENCY
+}
+
+/// Converts milliseconds to timer ticks, rounding up
+pub fn ms_to_ticks(ms: u64) -> u64 {
+    let divisor = PIT_DIVISOR * 1000;
+    ms.saturating_mul(PIT_BASE_FREQUENCY).saturating_add(divisor - 1) / divisor
+}
+
+/// Returns the time since boot in milliseconds
+pub fn uptime_ms() -> u64 {
+    ticks_to_ms(ticks())
+}
+
+/// Number of PIC interrupt lines
+pub const IRQ_LINES: usize = 16;
+
+/// Number of interrupts received on each PIC line since boot
+static IRQ_COUNTS: [AtomicU64; IRQ_LINES] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const ZERO: AtomicU64 = AtomicU64::new(0);
+    [ZERO; IRQ_LINES]
+};
+
+/// Returns the number of interrupts received on PIC line `irq` since boot
+pub fn irq_count(irq: usize) -> u64 {
+    IRQ_COUNTS[irq].load(Ordering::Relaxed)
+}
+
+/// Bits in `MODIFIERS` of the left and right shift keys
+const SHIFT: u8 = 0b0000_0011;
+/// Bits in `MODIFIERS` of the left and right alt keys
+const ALT: u8 = 0b0000_1100;
+
+/// Modifier keys held down; the keyboard decoder keeps track of them too,
+/// but does not tell
+static MODIFIERS: AtomicU8 = AtomicU8::new(0);
+
+fn update_modifiers(event: &pc_keyboard::KeyEvent) {
+    use pc_keyboard::{KeyCode, KeyState};
+
+    let bit = match event.code {
+        KeyCode::ShiftLeft => 1 << 0,
+        KeyCode::ShiftRight => 1 << 1,
+        KeyCode::AltLeft => 1 << 2,
+        KeyCode::AltRight => 1 << 3,
+        _ => return,
+    };
+    match event.state {
+        KeyState::Down => MODIFIERS.fetch_or(bit, Ordering::Relaxed),
+        KeyState::Up => MODIFIERS.fetch_and(!bit, Ordering::Relaxed),
+    };
+}
+
+/// Returns whether any of the modifier keys in `mask` is held down
+fn modifier_held(mask: u8) -> bool {
+    MODIFIERS.load(Ordering::Relaxed) & mask != 0
+}
 
 #[derive(Debug, Clone, Copy)]
 #[repr(u8)]
@@ -32,6 +110,10 @@ pub enum InterruptIndex {
     Timer = PIC_1_OFFSET,
     // 无需显示指定对应值，默认情况下，对应值是上一个枚举对应值加一。
     Keyboard, // new
+    /// COM2 and COM4
+    Serial2 = PIC_1_OFFSET + 3,
+    /// COM1 and COM3
+    Serial1,
 }
 impl InterruptIndex {
     fn as_u8(self) -> u8 {
@@ -41,6 +123,11 @@ impl InterruptIndex {
     fn as_usize(self) -> usize {
         usize::from(self.as_u8())
     }
+
+    /// Counts an interrupt on the PIC line of this index
+    fn count(self) {
+        IRQ_COUNTS[usize::from(self.as_u8() - PIC_1_OFFSET)].fetch_add(1, Ordering::Relaxed);
+    }
 }
 
 // static mut IDT: InterruptDescriptorTable = InterruptDescriptorTable::new();
@@ -68,7 +155,22 @@ lazy_static! {
         idt[InterruptIndex::Keyboard.as_usize()]
             .set_handler_fn(keyboard_interrupt_handler); // new for keyboard on PIC 8259
 
+        idt[InterruptIndex::Serial1.as_usize()]
+            .set_handler_fn(serial1_interrupt_handler);
+        idt[InterruptIndex::Serial2.as_usize()]
+            .set_handler_fn(serial2_interrupt_handler);
+
         idt.page_fault.set_handler_fn(page_fault_handler);  // new for page_fault handler
+        idt.general_protection_fault.set_handler_fn(general_protection_fault_handler);
+        idt.invalid_opcode.set_handler_fn(invalid_opcode_handler);
+        idt.divide_error.set_handler_fn(divide_error_handler);
+
+        // the system call gate must be reachable from ring 3
+        unsafe {
+            idt[usize::from(syscall::SYSCALL_INTERRUPT)]
+                .set_handler_addr(VirtAddr::new(syscall::int80_entry_addr()))
+                .set_privilege_level(PrivilegeLevel::Ring3);
+        }
         idt
     };
 }
@@ -77,10 +179,30 @@ pub fn init_idt() {
     IDT.load()
 }
 
+/// Unmasks PIC line `irq`, which the firmware may have left masked
+pub fn enable_irq(irq: u8) {
+    use x86_64::instructions::port::Port;
+
+    let unmask = |port: u16, line: u8| unsafe {
+        let mut mask: Port<u8> = Port::new(port);
+        let masked = mask.read();
+        mask.write(masked & !(1 << line));
+    };
+    // keep the PICs locked so nobody else changes the masks meanwhile
+    let _pics = PICS.lock();
+    if irq < 8 {
+        unmask(0x21, irq);
+    } else {
+        unmask(0xa1, irq - 8);
+        // the secondary PIC is connected to line 2 of the primary one
+        unmask(0x21, 2);
+    }
+}
+
 extern "x86-interrupt" fn breakpoint_handler(
     stack_frame: InterruptStackFrame)
 {
-    println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
+    warn!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
 }
 
 extern "x86-interrupt" fn double_fault_handler(
@@ -91,21 +213,28 @@ extern "x86-interrupt" fn double_fault_handler(
 }
 
 extern "x86-interrupt" fn timer_interrupt_handler(
-    _stack_frame: InterruptStackFrame)
+    mut stack_frame: InterruptStackFrame)
 {
-    print!(".");
+    InterruptIndex::Timer.count();
+    let now = TICKS.fetch_add(1, Ordering::Relaxed) + 1;
 
     unsafe {
         PICS.lock()
             .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
     }
+
+    status_bar::timer_tick(now);
+    // may switch to another task, so the end of interrupt must be sent first
+    task::timer_tick(now);
+    signal::check_on_interrupt(&mut stack_frame);
 }
 
 extern "x86-interrupt" fn keyboard_interrupt_handler(
     _stack_frame: InterruptStackFrame)
 {
     // print!("k");
-    use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
+    InterruptIndex::Keyboard.count();
+    use pc_keyboard::{layouts, DecodedKey, HandleControl, KeyCode, Keyboard, ScancodeSet1};
     use spin::Mutex;
     use x86_64::instructions::port::Port;
 
@@ -147,11 +276,32 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(
 
     let scancode: u8 = unsafe { port.read() };
     if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
+        update_modifiers(&key_event);
         // 要处理KeyEvent，还需要将其传入process_keyevent函数，将其转换为人类可读的字符，有必要的话还需处理大小写。
         if let Some(key) = keyboard.process_keyevent(key_event) {
+            // a page is a screen less one line, which stays for context
+            let page = vga_buffer::BUFFER_HEIGHT - 1;
+            const CONSOLE_KEYS: [KeyCode; vga_buffer::CONSOLES] =
+                [KeyCode::F1, KeyCode::F2, KeyCode::F3, KeyCode::F4, KeyCode::F5, KeyCode::F6];
             match key {
-                DecodedKey::Unicode(character) => print!("{}", character),
-                DecodedKey::RawKey(key) => print!("{:?}", key),
+                DecodedKey::RawKey(KeyCode::PageUp) if modifier_held(SHIFT) => {
+                    vga_buffer::with_shown_console(|writer| writer.scroll_back(page))
+                }
+                DecodedKey::RawKey(KeyCode::PageDown) if modifier_held(SHIFT) => {
+                    vga_buffer::with_shown_console(|writer| writer.scroll_forward(page))
+                }
+                DecodedKey::RawKey(code) if modifier_held(ALT) && CONSOLE_KEYS.contains(&code) => {
+                    let index = CONSOLE_KEYS.iter().position(|&c| c == code).unwrap();
+                    vga_buffer::switch_console(index);
+                }
+                // the shell task echoes the keys it uses, on console 0, so
+                // it only gets them while that is shown
+                key if vga_buffer::shown_console() == 0 => {
+                    if let Some(key) = shell::Key::from_decoded(key) {
+                        shell::push_key(key);
+                    }
+                }
+                _ => {}
             }
         }
     }
@@ -162,19 +312,76 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(
     }
 }
 
+extern "x86-interrupt" fn serial1_interrupt_handler(
+    _stack_frame: InterruptStackFrame)
+{
+    serial_interrupt(InterruptIndex::Serial1);
+}
+
+extern "x86-interrupt" fn serial2_interrupt_handler(
+    _stack_frame: InterruptStackFrame)
+{
+    serial_interrupt(InterruptIndex::Serial2);
+}
+
+fn serial_interrupt(index: InterruptIndex) {
+    index.count();
+    crate::serial::receive_interrupt(index.as_u8() - PIC_1_OFFSET);
+
+    unsafe {
+        PICS.lock()
+            .notify_end_of_interrupt(index.as_u8());
+    }
+}
+
 extern "x86-interrupt" fn page_fault_handler(
-    stack_frame: InterruptStackFrame,
+    mut stack_frame: InterruptStackFrame,
     error_code: PageFaultErrorCode,
 ) {
     use x86_64::registers::control::Cr2;
 
-    println!("EXCEPTION: PAGE FAULT");
-    println!("Accessed Address: {:?}", Cr2::read());
-    println!("Error Code: {:?}", error_code);
-    println!("{:#?}", stack_frame);
+    if address_space::handle_cow_fault(Cr2::read(), error_code) {
+        return;
+    }
+    if usermode::handle_user_fault("PAGE FAULT", Signal::SegmentationFault, &mut stack_frame) {
+        return;
+    }
+
+    error!("EXCEPTION: PAGE FAULT");
+    error!("Accessed Address: {:?}", Cr2::read());
+    error!("Error Code: {:?}", error_code);
+    error!("{:#?}", stack_frame);
     hlt_loop();
 }
 
+extern "x86-interrupt" fn general_protection_fault_handler(
+    mut stack_frame: InterruptStackFrame, error_code: u64)
+{
+    let signal = Signal::SegmentationFault;
+    if usermode::handle_user_fault("GENERAL PROTECTION FAULT", signal, &mut stack_frame) {
+        return;
+    }
+    panic!("EXCEPTION: GENERAL PROTECTION FAULT ({:#x})\n{:#?}", error_code, stack_frame);
+}
+
+extern "x86-interrupt" fn invalid_opcode_handler(
+    mut stack_frame: InterruptStackFrame)
+{
+    if usermode::handle_user_fault("INVALID OPCODE", Signal::IllegalInstruction, &mut stack_frame) {
+        return;
+    }
+    panic!("EXCEPTION: INVALID OPCODE\n{:#?}", stack_frame);
+}
+
+extern "x86-interrupt" fn divide_error_handler(
+    mut stack_frame: InterruptStackFrame)
+{
+    if usermode::handle_user_fault("DIVIDE ERROR", Signal::ArithmeticError, &mut stack_frame) {
+        return;
+    }
+    panic!("EXCEPTION: DIVIDE ERROR\n{:#?}", stack_frame);
+}
+
 #[test_case]
 fn test_breakpoint_exception() {
     // invoke a breakpoint exception
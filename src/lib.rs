@@ -8,10 +8,28 @@
 use core::panic::PanicInfo;
 
 pub mod serial;
+pub mod ansi;
+pub mod cp437;
 pub mod vga_buffer;
+pub mod graphics;
 pub mod interrupts;
 pub mod gdt;
 pub mod memory;
+pub mod address_space;
+pub mod sync;
+pub mod task;
+pub mod syscall;
+pub mod usermode;
+pub mod elf;
+pub mod process;
+pub mod channel;
+pub mod shm;
+pub mod signal;
+pub mod shell;
+pub mod fixed_str;
+pub mod logger;
+pub mod log_buffer;
+pub mod status_bar;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u32)]
@@ -38,8 +56,12 @@ pub fn hlt_loop() -> ! {
 /// follow code block is for interrupt
 pub fn init() {
     gdt::init(); // new
+    syscall::init();
     interrupts::init_idt();
     unsafe { interrupts::PICS.lock().initialize() }; // new for PIC 8259
+    serial::init();
+    logger::init(logger::DEFAULT_LEVEL).expect("logger initialized twice");
+    log_buffer::init();
     x86_64::instructions::interrupts::enable();  // enable interrupt for CPU
 }
 
@@ -66,6 +88,8 @@ pub fn test_runner(tests: &[&dyn Testable]) {
 }
 
 pub fn test_panic_handler(info: &PanicInfo) -> ! {
+    // the panic may come from a deadlock check on the serial port itself
+    unsafe { serial::force_unlock(serial::route(serial::Output::Print)) };
     serial_println!("[failed]\n");
     serial_println!("Error: {}\n", info);
     exit_qemu(QemuExitCode::Failed);
@@ -73,16 +97,6 @@ pub fn test_panic_handler(info: &PanicInfo) -> ! {
     hlt_loop();  // new
 }
 
-/// Entry point for `cargo test`
-#[cfg(test)]
-#[no_mangle]
-pub extern "C" fn _start() -> ! {
-    init();  // new
-    test_main();
-    // loop {}
-    hlt_loop();  // new
-}
-
 #[cfg(test)]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
@@ -97,9 +111,14 @@ entry_point!(test_kernel_main);
 
 /// Entry point for cargo test
 #[cfg(test)]
-fn test_kernel_main(_boot_info: &'static BootInfo) -> ! {
+fn test_kernel_main(boot_info: &'static BootInfo) -> ! {
     // like before 
     init();
+    // `entry_point!` replaced the old `_start`, so tests can use memory too
+    unsafe {
+        memory::init(x86_64::VirtAddr::new(boot_info.physical_memory_offset));
+        memory::init_frame_allocator(&boot_info.memory_map);
+    }
     test_main();
     hlt_loop();
 }
\ No newline at end of file
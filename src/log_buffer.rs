@@ -0,0 +1,191 @@
+//! Ring buffer of kernel log records
+//!
+//! The logger copies every record that passes the global and target filters
+//! into a fixed number of slots, overwriting the oldest one when all are in
+//! use. Each record gets a sequence number, so a reader that asks for record
+//! `n` and gets a later one knows how many it missed. The buffer can be read
+//! with the `dmesg` shell command and is dumped to serial on panic.
+
+use core::fmt::{self, Write};
+
+use log::Level;
+
+use crate::fixed_str::FixedStr;
+use crate::serial::{self, Output};
+use crate::shell::{self, Console};
+use crate::sync::SpinLock;
+
+/// Number of records kept
+pub const CAPACITY: usize = 128;
+/// Maximum length of a record's text in bytes, longer ones are cut off
+pub const MAX_TEXT_LEN: usize = 160;
+
+/// A log record in the buffer
+#[derive(Clone, Copy)]
+pub struct Entry {
+    pub seq: u64,
+    /// time since boot in milliseconds
+    pub timestamp_ms: u64,
+    pub level: Level,
+    text: FixedStr<MAX_TEXT_LEN>,
+}
+
+impl Entry {
+    const EMPTY: Entry = Entry {
+        seq: 0,
+        timestamp_ms: 0,
+        level: Level::Trace,
+        text: FixedStr::new(),
+    };
+
+    /// Returns the target and message of the record
+    pub fn text(&self) -> &str {
+        self.text.as_str()
+    }
+}
+
+impl fmt::Display for Entry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "<{}> [{:>5}.{:03}] {:<5} {}",
+            self.seq,
+            self.timestamp_ms / 1000,
+            self.timestamp_ms % 1000,
+            self.level,
+            self.text()
+        )
+    }
+}
+
+struct LogBuffer {
+    entries: [Entry; CAPACITY],
+    /// sequence number of the next record, records `next_seq - CAPACITY`
+    /// (or 0) to `next_seq - 1` are in the buffer
+    next_seq: u64,
+}
+
+impl LogBuffer {
+    const fn new() -> Self {
+        LogBuffer { entries: [Entry::EMPTY; CAPACITY], next_seq: 0 }
+    }
+
+    fn push(&mut self, timestamp_ms: u64, level: Level, args: fmt::Arguments) {
+        let seq = self.next_seq;
+        let entry = &mut self.entries[(seq % CAPACITY as u64) as usize];
+        *entry = Entry { seq, timestamp_ms, level, ..Entry::EMPTY };
+        entry.text.write_fmt(args).ok();
+        self.next_seq += 1;
+    }
+
+    fn first_seq(&self) -> u64 {
+        self.next_seq.saturating_sub(CAPACITY as u64)
+    }
+
+    /// Returns the oldest record with a sequence number of at least `seq`
+    fn read(&self, seq: u64) -> Option<Entry> {
+        let seq = seq.max(self.first_seq());
+        if seq >= self.next_seq {
+            return None;
+        }
+        Some(self.entries[(seq % CAPACITY as u64) as usize])
+    }
+}
+
+static BUFFER: SpinLock<LogBuffer> = SpinLock::new(LogBuffer::new());
+
+/// Adds a record, called by the logger
+pub(crate) fn record(timestamp_ms: u64, level: Level, args: fmt::Arguments) {
+    BUFFER.lock().push(timestamp_ms, level, args);
+}
+
+/// Returns the oldest record with a sequence number of at least `seq`, `None`
+/// if there is none yet
+///
+/// If the result has a larger sequence number than `seq`, the records in
+/// between have been overwritten.
+pub fn read(seq: u64) -> Option<Entry> {
+    BUFFER.lock().read(seq)
+}
+
+/// Returns the sequence number the next record will get
+pub fn next_seq() -> u64 {
+    BUFFER.lock().next_seq
+}
+
+/// Calls `f` for every record from `seq` on, with the number of records
+/// overwritten before it
+pub fn for_each_from(mut seq: u64, mut f: impl FnMut(&Entry, u64)) {
+    // the buffer is unlocked while `f` runs, so it can print or log
+    while let Some(entry) = read(seq) {
+        f(&entry, entry.seq - seq);
+        seq = entry.seq + 1;
+    }
+}
+
+/// Forcibly unlocks the buffer, for panic handlers that may have interrupted a
+/// record being added
+///
+/// # Safety
+///
+/// The current holder of the lock, if any, must never run again.
+pub unsafe fn force_unlock() {
+    BUFFER.force_unlock();
+}
+
+/// Writes all records to the serial port kernel logs are routed to
+pub fn dump_to_serial() {
+    serial::print_to(Output::Log, format_args!("--- kernel log ---\n"));
+    for_each_from(0, |entry, dropped| {
+        if dropped > 0 {
+            serial::print_to(Output::Log, format_args!("... {} records dropped\n", dropped));
+        }
+        serial::print_to(Output::Log, format_args!("{}\n", entry));
+    });
+}
+
+/// Registers the `dmesg` shell command
+pub fn init() {
+    shell::register("dmesg", "show the kernel log, from sequence number <seq> on", dmesg)
+        .expect("failed to register dmesg");
+}
+
+fn dmesg(console: &mut Console, args: &str) {
+    let seq = match args {
+        "" => 0,
+        seq => match seq.parse() {
+            Ok(seq) => seq,
+            Err(_) => {
+                writeln!(console, "usage: dmesg [seq]").ok();
+                return;
+            }
+        },
+    };
+    for_each_from(seq, |entry, dropped| {
+        // records older than the buffer are expected when reading everything
+        if dropped > 0 && seq != 0 {
+            writeln!(console, "... {} records dropped", dropped).ok();
+        }
+        writeln!(console, "{}", entry).ok();
+    });
+}
+
+#[test_case]
+fn test_wraps_around_and_reports_dropped_records() {
+    let mut buffer = LogBuffer::new();
+    assert!(buffer.read(0).is_none());
+    for i in 0..CAPACITY as u64 + 3 {
+        buffer.push(i, Level::Info, format_args!("record {}", i));
+    }
+    assert_eq!(buffer.first_seq(), 3);
+    // record 1 has been overwritten, so the next one that is left is returned
+    let entry = buffer.read(1).unwrap();
+    assert_eq!((entry.seq, entry.text()), (3, "record 3"));
+    let last = buffer.read(CAPACITY as u64 + 2).unwrap();
+    assert_eq!(last.timestamp_ms, CAPACITY as u64 + 2);
+    assert!(buffer.read(CAPACITY as u64 + 3).is_none());
+
+    // longer text is cut off
+    buffer.push(0, Level::Info, format_args!("{:>200}", "x"));
+    assert_eq!(buffer.read(CAPACITY as u64 + 3).unwrap().text().len(), MAX_TEXT_LEN);
+}
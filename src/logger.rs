@@ -0,0 +1,179 @@
+//! Kernel logger for the `log` crate
+//!
+//! Records are stamped with the time since boot and sent to three sinks, the
+//! VGA screen, the serial port kernel logs are routed to (see
+//! `serial::Output::Log`) and the in-memory `log_buffer`, each with its own
+//! level filter. By default the screen only shows warnings and errors while
+//! the others get everything.
+//!
+//! Records are filtered in three places:
+//!
+//! - at compile time through the `max_level_*` and `release_max_level_*`
+//!   features of `log` (release builds drop `debug!` and `trace!`, see
+//!   Cargo.toml)
+//! - at boot time by the level passed to `init`, and by `set_target_level`
+//!   for single modules
+//! - per sink by `set_sink_level`
+
+use core::fmt;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+use crate::serial::{self, Output};
+use crate::sync::SpinLock;
+use crate::vga_buffer::Color;
+use crate::{interrupts, log_buffer, vga_buffer};
+
+/// Level `crate::init` starts the logger with
+pub const DEFAULT_LEVEL: LevelFilter = LevelFilter::Trace;
+/// Maximum number of targets with their own level
+pub const MAX_TARGET_LEVELS: usize = 8;
+
+/// Where log records are written to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Sink {
+    Vga = 0,
+    Serial = 1,
+    /// the ring buffer read by `dmesg`
+    Buffer = 2,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoggerError {
+    AlreadyInitialized,
+    TooManyTargets,
+}
+
+/// Level filter of each sink, as `LevelFilter as usize`
+static SINK_LEVELS: [AtomicUsize; 3] = [
+    AtomicUsize::new(LevelFilter::Warn as usize),
+    AtomicUsize::new(LevelFilter::Trace as usize),
+    AtomicUsize::new(LevelFilter::Trace as usize),
+];
+
+/// Targets whose records are filtered by their own level; the longest
+/// matching prefix wins
+static TARGET_LEVELS: SpinLock<[Option<(&str, LevelFilter)>; MAX_TARGET_LEVELS]> =
+    SpinLock::new([None; MAX_TARGET_LEVELS]);
+
+const LEVEL_FILTERS: [LevelFilter; 6] = [
+    LevelFilter::Off,
+    LevelFilter::Error,
+    LevelFilter::Warn,
+    LevelFilter::Info,
+    LevelFilter::Debug,
+    LevelFilter::Trace,
+];
+
+/// Sets the level filter of a sink
+pub fn set_sink_level(sink: Sink, level: LevelFilter) {
+    SINK_LEVELS[sink as usize].store(level as usize, Ordering::Relaxed);
+}
+
+/// Returns the level filter of a sink
+pub fn sink_level(sink: Sink) -> LevelFilter {
+    LEVEL_FILTERS[SINK_LEVELS[sink as usize].load(Ordering::Relaxed)]
+}
+
+/// Filters the records of `target` and its submodules by `level` instead of
+/// the global level
+///
+/// The global level set by `init` still applies on top of this.
+pub fn set_target_level(target: &'static str, level: LevelFilter) -> Result<(), LoggerError> {
+    let mut targets = TARGET_LEVELS.lock();
+    let slot = targets
+        .iter()
+        .position(|t| matches!(t, Some((name, _)) if *name == target))
+        .or_else(|| targets.iter().position(|t| t.is_none()))
+        .ok_or(LoggerError::TooManyTargets)?;
+    targets[slot] = Some((target, level));
+    Ok(())
+}
+
+/// Returns the level filter for `target`, `None` if it has no level of its own
+fn target_level(target: &str) -> Option<LevelFilter> {
+    TARGET_LEVELS
+        .lock()
+        .iter()
+        .flatten()
+        .filter(|&&(name, _)| {
+            target.starts_with(name)
+                && (target.len() == name.len() || target[name.len()..].starts_with("::"))
+        })
+        .max_by_key(|&&(name, _)| name.len())
+        .map(|&(_, level)| level)
+}
+
+struct KernelLogger;
+
+static LOGGER: KernelLogger = KernelLogger;
+
+impl Log for KernelLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        let (target, level) = (metadata.target(), metadata.level());
+        [Sink::Vga, Sink::Serial, Sink::Buffer]
+            .iter()
+            .any(|&sink| would_log(sink, target, level))
+    }
+
+    fn log(&self, record: &Record) {
+        let (target, level) = (record.target(), record.level());
+        let ms = interrupts::uptime_ms();
+        let write_to = |print: fn(fmt::Arguments)| {
+            print(format_args!(
+                "[{:>5}.{:03}] {:<5} {}: {}\n",
+                ms / 1000,
+                ms % 1000,
+                level,
+                target,
+                record.args()
+            ))
+        };
+        if would_log(Sink::Serial, target, level) {
+            write_to(|line| serial::print_to(Output::Log, line));
+        }
+        if would_log(Sink::Vga, target, level) {
+            match level {
+                Level::Error => write_to(|line| vga_buffer::_print_color(Color::LightRed, line)),
+                _ => write_to(vga_buffer::_print),
+            }
+        }
+        if would_log(Sink::Buffer, target, level) {
+            log_buffer::record(ms, level, format_args!("{}: {}", target, record.args()));
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Installs the kernel logger and sets the global level filter
+pub fn init(level: LevelFilter) -> Result<(), LoggerError> {
+    log::set_logger(&LOGGER).map_err(|_| LoggerError::AlreadyInitialized)?;
+    log::set_max_level(level);
+    Ok(())
+}
+
+/// Returns whether a record of `level` from `target` would be written to `sink`
+pub fn would_log(sink: Sink, target: &str, level: Level) -> bool {
+    level <= log::max_level()
+        && level <= sink_level(sink)
+        && level <= target_level(target).unwrap_or(LevelFilter::Trace)
+}
+
+#[test_case]
+fn test_filters() {
+    set_target_level("blog_os::test_filters", LevelFilter::Error).unwrap();
+    set_target_level("blog_os::test_filters::verbose", LevelFilter::Debug).unwrap();
+
+    assert!(would_log(Sink::Serial, "blog_os::task", Level::Trace));
+    assert!(!would_log(Sink::Vga, "blog_os::task", Level::Info));
+    assert!(would_log(Sink::Vga, "blog_os::task", Level::Warn));
+    assert!(!would_log(Sink::Serial, "blog_os::test_filters", Level::Warn));
+    assert!(would_log(Sink::Serial, "blog_os::test_filters::verbose::more", Level::Debug));
+    // only whole path segments match
+    assert!(would_log(Sink::Serial, "blog_os::test_filters_other", Level::Warn));
+
+    set_target_level("blog_os::test_filters", LevelFilter::Trace).unwrap();
+    assert!(would_log(Sink::Serial, "blog_os::test_filters", Level::Warn));
+}
@@ -7,7 +7,8 @@
 #![reexport_test_harness_main = "test_main"]
 
 use core::panic::PanicInfo;
-use blog_os::{println, memory::{translate_addr, self, BootInfoFrameAllocator}};
+use blog_os::{println, memory::{translate_addr, self, GlobalFrameAllocator}};
+use blog_os::{shell::{self, Console}, task};
 use bootloader::{BootInfo, entry_point};
 use x86_64::structures::paging::{Translate, Page};
 
@@ -36,9 +37,13 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     // new: initialize a mapper
     let mut mapper = unsafe { memory::init(phys_mem_offset) };
     // let mut frame_allocator = memory::EmptyFrameAllocator;
-    let mut frame_allocator = unsafe {
-        BootInfoFrameAllocator::init(&boot_info.memory_map)
-    };
+    // let mut frame_allocator = unsafe {
+    //     BootInfoFrameAllocator::init(&boot_info.memory_map)
+    // };
+    // the frame allocator is shared with the rest of the kernel now
+    unsafe { memory::init_frame_allocator(&boot_info.memory_map) };
+    blog_os::status_bar::init();
+    let mut frame_allocator = GlobalFrameAllocator;
 
     // map the unused page
     let page = Page::containing_address(VirtAddr::new(0));
@@ -93,6 +98,9 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     test_main();
 
     println!("it did not crash!");
+    for &console in [Console::Screen, Console::Serial].iter() {
+        task::spawn(shell::run, console as usize).expect("failed to start the shell");
+    }
     blog_os::hlt_loop();
 }
 
@@ -172,7 +180,19 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
 #[panic_handler]
 // This function cannot return, diverging function, 'never' type
 fn panic(info: &PanicInfo) -> ! {
-    println!("{}", info);
+    use blog_os::{log_buffer, println_color, serial::{self, Output}, vga_buffer::Color};
+
+    // the panic may come from a deadlock check on the writer itself
+    unsafe { blog_os::vga_buffer::force_unlock() };
+    blog_os::vga_buffer::switch_console(0);
+    println_color!(Color::LightRed, "{}", info);
+    // the screen may have scrolled, so keep the whole log on the host
+    unsafe {
+        log_buffer::force_unlock();
+        serial::force_unlock(serial::route(Output::Log));
+    }
+    serial::print_to(Output::Log, format_args!("{}\n", info));
+    log_buffer::dump_to_serial();
     // loop {}
     blog_os::hlt_loop(); // new
 }
@@ -1,7 +1,58 @@
+use core::sync::atomic::{AtomicU64, AtomicU8, AtomicUsize, Ordering};
+
 use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
 use x86_64::{
     structures::paging::{PageTable, page_table::FrameError, OffsetPageTable, FrameAllocator, Size4KiB, PhysFrame, Mapper, Page},
-    VirtAddr, PhysAddr, registers::control::Cr3,
+    structures::paging::{PageTableFlags, mapper::MapToError, FrameDeallocator},
+    VirtAddr, PhysAddr, registers::control::{Cr0, Cr0Flags, Cr3},
+    registers::model_specific::{Efer, EferFlags},
+};
+
+use crate::sync::SpinLock;
+
+/// Start of the part of the virtual address space reserved for user programs
+/// (level 4 entries 128 to 255; the bootloader puts the kernel, its stack and
+/// the physical memory mapping in the first few entries)
+pub const USER_SPACE_START: u64 = 0x0000_4000_0000_0000;
+/// End (exclusive) of the user part of the virtual address space
+pub const USER_SPACE_END: u64 = 0x0000_8000_0000_0000;
+
+/// Page table flag (one of the bits left to the OS) marking a user page that
+/// is shared read-only after a fork and copied on the first write
+pub const COPY_ON_WRITE: PageTableFlags = PageTableFlags::BIT_9;
+/// Page table flag marking a user page that maps shared memory (see `shm`),
+/// which stays shared after a fork
+pub const SHARED: PageTableFlags = PageTableFlags::BIT_10;
+
+/// `GlobalFrameAllocator` only hands out frames below this address, so that
+/// `FRAME_REFS` can cover all of them
+pub const MAX_PHYSICAL_MEMORY: u64 = 4 << 30;
+const MAX_FRAMES: usize = (MAX_PHYSICAL_MEMORY / 4096) as usize;
+
+/// The `physical_memory_offset` passed to `init`
+static PHYSICAL_MEMORY_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+/// The level 4 table active when `init` was called, used by kernel tasks
+static KERNEL_PAGE_TABLE: AtomicU64 = AtomicU64::new(0);
+
+/// The frame allocator shared by the whole kernel, see `init_frame_allocator`
+static FRAME_ALLOCATOR: SpinLock<Option<BootInfoFrameAllocator>> = SpinLock::new(None);
+
+/// Frames given back through `GlobalFrameAllocator`, see `FreeFrameList`
+static FREE_FRAMES: SpinLock<FreeFrameList> = SpinLock::new(FreeFrameList { head: None });
+
+/// Number of frames handed out by `GlobalFrameAllocator` and not freed yet
+static FRAMES_IN_USE: AtomicUsize = AtomicUsize::new(0);
+
+/// Number of usable frames in the memory map given to `init_frame_allocator`
+static USABLE_FRAMES: AtomicUsize = AtomicUsize::new(0);
+
+/// Number of page table entries (or other owners) using each frame handed
+/// out by `GlobalFrameAllocator`, indexed by frame number
+static FRAME_REFS: [AtomicU8; MAX_FRAMES] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const UNUSED: AtomicU8 = AtomicU8::new(0);
+    [UNUSED; MAX_FRAMES]
 };
 
 /// 返回一个对活动的4级页表的可变引用
@@ -68,16 +119,216 @@ fn translate_addr_inner(addr: VirtAddr, physical_memory_offset: VirtAddr)
     Some(frame.start_address() + u64::from(addr.page_offset()))
 }
 
+/// Returns whether user mode may access all of `[start, start + len)` in the
+/// active page table, for writing too if `write` is set
+///
+/// A page only counts as user accessible if every level of the page table
+/// allows it, and only addresses in user space are accepted.
+pub fn is_user_accessible(start: VirtAddr, len: u64, write: bool) -> bool {
+    let end = match start.as_u64().checked_add(len) {
+        Some(end) => end,
+        None => return false,
+    };
+    if start.as_u64() < USER_SPACE_START || end > USER_SPACE_END {
+        return false;
+    }
+    if len == 0 {
+        return true;
+    }
+
+    let required = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    let first_page: Page = Page::containing_address(start);
+    let last_page: Page = Page::containing_address(VirtAddr::new(end - 1));
+    Page::range_inclusive(first_page, last_page).all(|page| {
+        let flags = effective_flags(page.start_address());
+        // a write to a copy-on-write page gets it copied, see `address_space`
+        flags.contains(required)
+            && (!write || flags.intersects(PageTableFlags::WRITABLE | COPY_ON_WRITE))
+    })
+}
+
+/// Returns the flags that apply to `addr` in the active page table: a flag
+/// is only set if it is set on every level, empty if `addr` is not mapped
+///
+/// `COPY_ON_WRITE` is taken from the entry that maps the page alone.
+fn effective_flags(addr: VirtAddr) -> PageTableFlags {
+    let (level_4_table_frame, _) = Cr3::read();
+    let table_indexes = [
+        addr.p4_index(), addr.p3_index(), addr.p2_index(), addr.p1_index()
+    ];
+    let mut frame = level_4_table_frame;
+    let mut flags = PageTableFlags::all();
+
+    for (level, &index) in table_indexes.iter().enumerate() {
+        let table_ptr: *const PageTable = phys_to_virt(frame.start_address()).as_ptr();
+        let entry = &unsafe { &*table_ptr }[index];
+        flags &= entry.flags() | COPY_ON_WRITE;
+        frame = match entry.frame() {
+            Ok(frame) if level < 3 => frame,
+            Err(FrameError::FrameNotPresent) => return PageTableFlags::empty(),
+            // either the last level or a huge page, which is the last level too
+            _ => return flags & (entry.flags() | !COPY_ON_WRITE),
+        };
+    }
+    unreachable!("the level 1 entry ends the walk")
+}
+
 /// 初始化一个新的OffsetPageTable
 /// 
 /// 这个函数是不安全的，因为调用者必须保证完整的物理内存在
 /// 传递的`physical_memory_offset`处被映射到虚拟内存。
 /// 另外这个函数必须只被调用一次，以避免别名&mut引用
 pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
+    PHYSICAL_MEMORY_OFFSET.store(physical_memory_offset.as_u64(), Ordering::Relaxed);
+    KERNEL_PAGE_TABLE.store(Cr3::read().0.start_address().as_u64(), Ordering::Relaxed);
+    // make read-only pages read-only for the kernel too, which copy-on-write
+    // relies on when the kernel writes to user memory
+    Cr0::update(|flags| flags.insert(Cr0Flags::WRITE_PROTECT));
     let level_4_table = active_level_4_table(physical_memory_offset);
     OffsetPageTable::new(level_4_table, physical_memory_offset)
 }
 
+/// Sets up the kernel-wide frame allocator used by `GlobalFrameAllocator`
+///
+/// # Safety
+///
+/// Same as `BootInfoFrameAllocator::init`. In addition, no other allocator
+/// may hand out frames from the same memory map.
+pub unsafe fn init_frame_allocator(memory_map: &'static MemoryMap) {
+    let allocator = BootInfoFrameAllocator::init(memory_map);
+    USABLE_FRAMES.store(allocator.usable_frames().count(), Ordering::Relaxed);
+    *FRAME_ALLOCATOR.lock() = Some(allocator);
+}
+
+/// Returns the virtual address at which the given physical address can be accessed
+///
+/// Panics if `init` has not been called.
+pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
+    let offset = PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed);
+    assert!(offset != 0, "memory::init has not been called");
+    VirtAddr::new(offset + addr.as_u64())
+}
+
+/// Returns the level 4 table of the kernel, or `None` before `init`
+///
+/// Kernel tasks run on it; it maps no user space.
+pub fn kernel_page_table() -> Option<PhysFrame> {
+    match KERNEL_PAGE_TABLE.load(Ordering::Relaxed) {
+        0 => None,
+        addr => Some(PhysFrame::containing_address(PhysAddr::new(addr))),
+    }
+}
+
+/// Returns the number of frames allocated through `GlobalFrameAllocator`
+/// that have not been freed
+pub fn frames_in_use() -> usize {
+    FRAMES_IN_USE.load(Ordering::Relaxed)
+}
+
+/// Returns the number of frames `GlobalFrameAllocator` can still hand out,
+/// 0 before `init_frame_allocator`
+pub fn free_frames() -> usize {
+    USABLE_FRAMES.load(Ordering::Relaxed).saturating_sub(frames_in_use())
+}
+
+/// Returns a mapper for the page table hierarchy with the given level 4 table
+///
+/// # Safety
+///
+/// `init` must have been called, `level_4_frame` must hold a valid level 4
+/// table, and no other mapper for the same table may be used at the same time.
+pub unsafe fn mapper_for(level_4_frame: PhysFrame) -> OffsetPageTable<'static> {
+    let virt = phys_to_virt(level_4_frame.start_address());
+    let level_4_table: *mut PageTable = virt.as_mut_ptr();
+    OffsetPageTable::new(&mut *level_4_table, phys_to_virt(PhysAddr::new(0)))
+}
+
+/// Creates a level 4 table with an empty user space and the kernel half of
+/// the active page table
+///
+/// Only the level 4 entries are copied, so kernel mappings added later under
+/// an existing entry show up in every table, but new level 4 entries do not.
+/// Returns `None` if no frame is left.
+pub fn new_user_page_table() -> Option<PhysFrame> {
+    let frame = GlobalFrameAllocator.allocate_frame()?;
+    let (active_frame, _) = Cr3::read();
+    let active: *const PageTable = phys_to_virt(active_frame.start_address()).as_ptr();
+    let table: *mut PageTable = phys_to_virt(frame.start_address()).as_mut_ptr();
+
+    let user_entries = usize::from(VirtAddr::new(USER_SPACE_START).p4_index())
+        ..=usize::from(VirtAddr::new(USER_SPACE_END - 1).p4_index());
+    unsafe {
+        let table = &mut *table;
+        table.zero();
+        for (i, entry) in (*active).iter().enumerate() {
+            if !user_entries.contains(&i) {
+                table[i] = entry.clone();
+            }
+        }
+    }
+    Some(frame)
+}
+
+/// Returns `NO_EXECUTE` if the CPU has it enabled, no flags otherwise
+pub fn no_execute() -> PageTableFlags {
+    if Efer::read().contains(EferFlags::NO_EXECUTE_ENABLE) {
+        PageTableFlags::NO_EXECUTE
+    } else {
+        PageTableFlags::empty()
+    }
+}
+
+/// Maps fresh frames for `size` bytes of user memory starting at `start`
+///
+/// `data` is copied to `start`, the rest of the range is zeroed. The pages
+/// are always `PRESENT | USER_ACCESSIBLE` in addition to `flags`. The data is
+/// written through the physical memory mapping, so `mapper` does not need to
+/// be the active page table and the pages may be read-only.
+pub fn map_user_region(
+    mapper: &mut OffsetPageTable,
+    start: VirtAddr,
+    size: u64,
+    data: &[u8],
+    flags: PageTableFlags,
+) -> Result<(), MapToError<Size4KiB>> {
+    assert!(data.len() as u64 <= size, "more data than memory");
+    assert!(
+        start.as_u64() >= USER_SPACE_START && start.as_u64() + size <= USER_SPACE_END,
+        "user region outside of user space"
+    );
+    if size == 0 {
+        return Ok(());
+    }
+
+    let flags = flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    let first_page: Page = Page::containing_address(start);
+    let last_page: Page = Page::containing_address(start + (size - 1));
+    for page in Page::range_inclusive(first_page, last_page) {
+        let frame = GlobalFrameAllocator
+            .allocate_frame()
+            .ok_or(MapToError::FrameAllocationFailed)?;
+
+        // copy the part of `data` that falls into this page, zero the rest
+        let frame_ptr: *mut u8 = phys_to_virt(frame.start_address()).as_mut_ptr();
+        unsafe { frame_ptr.write_bytes(0, page.size() as usize) };
+        let page_start = page.start_address().as_u64();
+        let data_start = start.as_u64();
+        let data_end = data_start + data.len() as u64;
+        let copy_start = data_start.max(page_start);
+        let copy_end = data_end.min(page_start + page.size());
+        if copy_start < copy_end {
+            let src = &data[(copy_start - data_start) as usize..(copy_end - data_start) as usize];
+            unsafe {
+                let dst = frame_ptr.add((copy_start - page_start) as usize);
+                core::ptr::copy_nonoverlapping(src.as_ptr(), dst, src.len());
+            }
+        }
+
+        unsafe { mapper.map_to(page, frame, flags, &mut GlobalFrameAllocator)?.flush() };
+    }
+    Ok(())
+}
+
 /// 为给定的页面创建一个实例映射到框架`0xb8000`
 pub fn create_example_mapping(
     page: Page,
@@ -142,4 +393,99 @@ unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
         self.next += 1;
         frame
     }
+}
+
+/// A stack of free frames, linked through their first eight bytes
+///
+/// `BootInfoFrameAllocator` cannot take frames back, so freed frames are
+/// kept here and handed out again first.
+struct FreeFrameList {
+    head: Option<PhysFrame>,
+}
+
+impl FreeFrameList {
+    fn push(&mut self, frame: PhysFrame) {
+        let next = self.head.map_or(0, |head| head.start_address().as_u64());
+        let ptr: *mut u64 = phys_to_virt(frame.start_address()).as_mut_ptr();
+        unsafe { ptr.write(next) };
+        self.head = Some(frame);
+    }
+
+    fn pop(&mut self) -> Option<PhysFrame> {
+        let frame = self.head?;
+        let ptr: *const u64 = phys_to_virt(frame.start_address()).as_ptr();
+        self.head = match unsafe { ptr.read() } {
+            0 => None,
+            next => Some(PhysFrame::containing_address(PhysAddr::new(next))),
+        };
+        Some(frame)
+    }
+}
+
+/// A FrameAllocator that hands out frames from the kernel-wide allocator
+///
+/// Returns `None` until `init_frame_allocator` has been called. Frames may
+/// be given back with `deallocate_frame`.
+pub struct GlobalFrameAllocator;
+
+unsafe impl FrameAllocator<Size4KiB> for GlobalFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        let frame = match FREE_FRAMES.lock().pop() {
+            Some(frame) => frame,
+            None => {
+                let mut allocator = FRAME_ALLOCATOR.lock();
+                let allocator = allocator.as_mut()?;
+                loop {
+                    let frame = allocator.allocate_frame()?;
+                    if frame.start_address().as_u64() < MAX_PHYSICAL_MEMORY {
+                        break frame;
+                    }
+                }
+            }
+        };
+        frame_refs(frame).store(1, Ordering::Relaxed);
+        FRAMES_IN_USE.fetch_add(1, Ordering::Relaxed);
+        Some(frame)
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for GlobalFrameAllocator {
+    /// The frame must have come from `allocate_frame` and must not be used
+    /// anymore, whatever its reference count
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        frame_refs(frame).store(0, Ordering::Relaxed);
+        FRAMES_IN_USE.fetch_sub(1, Ordering::Relaxed);
+        FREE_FRAMES.lock().push(frame);
+    }
+}
+
+fn frame_refs(frame: PhysFrame) -> &'static AtomicU8 {
+    &FRAME_REFS[(frame.start_address().as_u64() / 4096) as usize]
+}
+
+/// Returns how many owners the frame has, 0 if it is free or was not
+/// allocated by `GlobalFrameAllocator`
+///
+/// A frame starts with one owner when it is allocated.
+pub fn frame_ref_count(frame: PhysFrame) -> usize {
+    frame_refs(frame).load(Ordering::Relaxed).into()
+}
+
+/// Adds an owner to a frame allocated by `GlobalFrameAllocator`
+pub fn acquire_frame(frame: PhysFrame) {
+    let old = frame_refs(frame).fetch_add(1, Ordering::Relaxed);
+    assert!(old != 0 && old != u8::MAX, "bad reference count {} for {:?}", old, frame);
+}
+
+/// Removes an owner from a frame and frees it if that was the last one
+///
+/// # Safety
+///
+/// The caller must own a reference to the frame and not use it afterwards.
+pub unsafe fn release_frame(frame: PhysFrame) {
+    let old = frame_refs(frame).fetch_sub(1, Ordering::Relaxed);
+    assert!(old != 0, "released free frame {:?}", frame);
+    if old == 1 {
+        GlobalFrameAllocator.deallocate_frame(frame);
+    }
 }
\ No newline at end of file
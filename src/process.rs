@@ -0,0 +1,335 @@
+//! Processes: user programs with a PID, a parent and an exit status
+//!
+//! Every process runs in one task with an address space of its own. When the
+//! task exits, the process becomes a zombie that keeps its exit code until
+//! its parent collects it with `wait`. Processes started by kernel code have
+//! no parent process and are waited for by kernel code.
+//!
+//! When a process exits, its zombie children are reaped at once and its
+//! running children are detached: nobody waits for them anymore, and they
+//! are removed from the table as soon as they exit.
+//!
+//! `fork` starts a copy of the calling process. The copy shares the frames
+//! of the parent until one of them writes to a page, see `address_space`,
+//! and gets a copy of its channel handles and signal actions.
+
+use crate::channel;
+use crate::elf::{self, LoadError};
+use crate::signal::{Signal, SignalState};
+use crate::sync::{SpinLock, WaitQueue};
+use crate::syscall::SyscallFrame;
+use crate::task::{self, TaskId, MAX_TASKS};
+use crate::usermode;
+
+/// Maximum number of processes, including zombies
+pub const MAX_PROCESSES: usize = MAX_TASKS;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Pid(u64);
+
+impl Pid {
+    pub fn from_u64(pid: u64) -> Self {
+        Pid(pid)
+    }
+
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProcessState {
+    Running,
+    /// exited with the given code, waiting for the parent to collect it
+    Zombie(i64),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProcessError {
+    TooManyProcesses,
+    Load(LoadError),
+    OutOfMemory,
+    /// `fork` was called by a task without a user address space
+    NoAddressSpace,
+}
+
+impl From<LoadError> for ProcessError {
+    fn from(error: LoadError) -> Self {
+        ProcessError::Load(error)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WaitError {
+    NoSuchProcess,
+    /// the process belongs to another parent
+    NotAChild,
+    /// `wait_any` was called without any children to wait for
+    NoChildren,
+    /// a signal arrived before a child exited
+    Interrupted,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Process {
+    pid: Pid,
+    parent: Option<Pid>,
+    task: TaskId,
+    state: ProcessState,
+    /// the parent exited, nobody will wait for this process
+    orphaned: bool,
+    signals: SignalState,
+}
+
+struct ProcessTable {
+    processes: [Option<Process>; MAX_PROCESSES],
+    next_pid: u64,
+}
+
+impl ProcessTable {
+    fn slot_of(&self, pid: Pid) -> Option<usize> {
+        self.processes.iter().position(|p| matches!(p, Some(process) if process.pid == pid))
+    }
+
+    fn slot_of_task(&self, id: TaskId) -> Option<usize> {
+        self.processes.iter().position(|p| matches!(p, Some(process) if process.task == id))
+    }
+
+    fn pid_of_task(&self, id: TaskId) -> Option<Pid> {
+        self.slot_of_task(id).map(|slot| self.processes[slot].unwrap().pid)
+    }
+
+    /// Returns whether the process of task `id` has a signal that should end
+    /// a blocking call, see `signal::interrupted`
+    fn interrupted(&self, id: TaskId) -> bool {
+        self.slot_of_task(id)
+            .map(|slot| self.processes[slot].unwrap().signals.interrupts_blocking())
+            .unwrap_or(false)
+    }
+
+    fn free_slot(&self) -> Option<usize> {
+        self.processes.iter().position(|p| p.is_none())
+    }
+
+    /// Adds a running process for `task` in the free `slot`
+    fn insert(&mut self, slot: usize, parent: Option<Pid>, task: TaskId, signals: SignalState) -> Pid {
+        let pid = Pid(self.next_pid);
+        self.next_pid += 1;
+        self.processes[slot] = Some(Process {
+            pid,
+            parent,
+            task,
+            state: ProcessState::Running,
+            orphaned: false,
+            signals,
+        });
+        pid
+    }
+}
+
+static PROCESSES: SpinLock<ProcessTable> = SpinLock::new(ProcessTable {
+    processes: [None; MAX_PROCESSES],
+    next_pid: 1,
+});
+
+/// Woken whenever a process exits
+static EXITED: WaitQueue = WaitQueue::new();
+
+/// Loads the executable in `data` and starts it as a child of the current process
+///
+/// `argv` and `envp` are passed as described in `elf::load`.
+pub fn spawn_elf(data: &[u8], argv: &[&str], envp: &[&str]) -> Result<Pid, ProcessError> {
+    let program = elf::load(data, argv, envp)?;
+    // holding the lock keeps interrupts off, so the process is in the table
+    // before its task can run and exit
+    let mut table = PROCESSES.lock();
+    let slot = table.free_slot().ok_or(ProcessError::TooManyProcesses)?;
+    let parent = table.pid_of_task(task::current_id());
+    let task = usermode::spawn_user_task(program.address_space, program.entry, program.stack_pointer)
+        .map_err(|_| ProcessError::TooManyProcesses)?;
+    Ok(table.insert(slot, parent, task, SignalState::new()))
+}
+
+/// Starts a copy of the current process that continues in user mode with
+/// `registers`, and returns its PID
+///
+/// The system call passes the registers of the caller with `rax` set to 0,
+/// so `fork` returns 0 in the child.
+pub fn fork(registers: &SyscallFrame) -> Result<Pid, ProcessError> {
+    let mut table = PROCESSES.lock();
+    let slot = table.free_slot().ok_or(ProcessError::TooManyProcesses)?;
+    let parent = table.pid_of_task(task::current_id());
+    let address_space = task::with_current_address_space(|space| match space {
+        Some(space) => space.fork().ok_or(ProcessError::OutOfMemory),
+        None => Err(ProcessError::NoAddressSpace),
+    })?;
+    let task = usermode::spawn_user_task_with(address_space, registers)
+        .map_err(|_| ProcessError::TooManyProcesses)?;
+    // interrupts are still off, so the child has not run yet
+    channel::inherit_handles(task::current_id(), task);
+    let signals = match table.slot_of_task(task::current_id()) {
+        Some(slot) => table.processes[slot].unwrap().signals.forked(),
+        None => SignalState::new(),
+    };
+    Ok(table.insert(slot, parent, task, signals))
+}
+
+/// Starts the executable in `data` as a child process and waits for its exit code
+pub fn run_elf(data: &[u8], argv: &[&str], envp: &[&str]) -> Result<i64, ProcessError> {
+    let pid = spawn_elf(data, argv, envp)?;
+    Ok(wait(pid).expect("freshly spawned child vanished"))
+}
+
+/// Returns the PID of the process the current task belongs to
+pub fn current_pid() -> Option<Pid> {
+    PROCESSES.lock().pid_of_task(task::current_id())
+}
+
+/// Returns the parent of the given process, `None` for processes started by
+/// the kernel and for unknown PIDs
+pub fn parent_of(pid: Pid) -> Option<Pid> {
+    let table = PROCESSES.lock();
+    let slot = table.slot_of(pid)?;
+    table.processes[slot].unwrap().parent
+}
+
+/// Returns the state of the given process, `None` once it has been waited for
+pub fn state_of(pid: Pid) -> Option<ProcessState> {
+    let table = PROCESSES.lock();
+    table.slot_of(pid).map(|slot| table.processes[slot].unwrap().state)
+}
+
+/// Returns the task of the given process, `None` once it has been waited for
+pub(crate) fn task_of(pid: Pid) -> Option<TaskId> {
+    let table = PROCESSES.lock();
+    table.slot_of(pid).map(|slot| table.processes[slot].unwrap().task)
+}
+
+/// Calls `f` with the signal state of the given process, `None` if there
+/// is no such process
+pub(crate) fn with_signals<R>(pid: Pid, f: impl FnOnce(&mut SignalState) -> R) -> Option<R> {
+    let mut table = PROCESSES.lock();
+    let slot = table.slot_of(pid)?;
+    Some(f(&mut table.processes[slot].as_mut().unwrap().signals))
+}
+
+/// Calls `f` with the signal state of the current process, `None` if the
+/// current task is no process
+pub(crate) fn with_current_signals<R>(f: impl FnOnce(&mut SignalState) -> R) -> Option<R> {
+    let mut table = PROCESSES.lock();
+    let slot = table.slot_of_task(task::current_id())?;
+    Some(f(&mut table.processes[slot].as_mut().unwrap().signals))
+}
+
+/// Returns the number of processes in the table, including zombies
+pub fn process_count() -> usize {
+    PROCESSES.lock().processes.iter().filter(|p| p.is_some()).count()
+}
+
+/// Waits for the given child of the current process to exit and returns its
+/// exit code. Afterwards the PID is unknown.
+pub fn wait(pid: Pid) -> Result<i64, WaitError> {
+    let me = current_pid();
+    let mut result = Err(WaitError::NoSuchProcess);
+    EXITED.wait_until(|| {
+        let mut table = PROCESSES.lock();
+        let slot = match table.slot_of(pid) {
+            Some(slot) => slot,
+            None => return true,
+        };
+        let process = table.processes[slot].unwrap();
+        if process.parent != me || process.orphaned {
+            result = Err(WaitError::NotAChild);
+            return true;
+        }
+        match reap(&mut table, slot) {
+            Some(code) => {
+                result = Ok(code);
+                true
+            }
+            None if table.interrupted(task::current_id()) => {
+                result = Err(WaitError::Interrupted);
+                true
+            }
+            None => false,
+        }
+    });
+    result
+}
+
+/// Waits for any child of the current process to exit and returns its PID
+/// and exit code
+pub fn wait_any() -> Result<(Pid, i64), WaitError> {
+    let me = current_pid();
+    let mut result = Err(WaitError::NoChildren);
+    EXITED.wait_until(|| {
+        let mut table = PROCESSES.lock();
+        let mut has_children = false;
+        for slot in 0..MAX_PROCESSES {
+            let process = match table.processes[slot] {
+                Some(process) if process.parent == me && !process.orphaned => process,
+                _ => continue,
+            };
+            has_children = true;
+            if let Some(code) = reap(&mut table, slot) {
+                result = Ok((process.pid, code));
+                return true;
+            }
+        }
+        if has_children && table.interrupted(task::current_id()) {
+            result = Err(WaitError::Interrupted);
+            return true;
+        }
+        !has_children
+    });
+    result
+}
+
+/// Removes the process in `slot` from the table if it is a zombie and
+/// returns its exit code
+fn reap(table: &mut ProcessTable, slot: usize) -> Option<i64> {
+    let process = table.processes[slot]?;
+    match process.state {
+        ProcessState::Zombie(code) => {
+            table.processes[slot] = None;
+            // the task has exited already, this only frees its slot
+            task::join(process.task);
+            Some(code)
+        }
+        ProcessState::Running => None,
+    }
+}
+
+/// Called by `task::exit` for every task before it ends
+pub(crate) fn task_exited(id: TaskId, code: i64) {
+    let mut table = PROCESSES.lock();
+    let slot = match table.slot_of_task(id) {
+        Some(slot) => slot,
+        None => return,
+    };
+    let process = table.processes[slot].as_mut().unwrap();
+    let pid = process.pid;
+    let parent = process.parent;
+    if process.orphaned {
+        // its task was detached when it was orphaned
+        table.processes[slot] = None;
+    } else {
+        process.state = ProcessState::Zombie(code);
+        if let Some(parent_slot) = parent.and_then(|parent| table.slot_of(parent)) {
+            table.processes[parent_slot].as_mut().unwrap().signals.raise(Signal::Child);
+        }
+    }
+
+    for slot in 0..MAX_PROCESSES {
+        let child = match table.processes[slot] {
+            Some(child) if child.parent == Some(pid) && !child.orphaned => child,
+            _ => continue,
+        };
+        if reap(&mut table, slot).is_none() {
+            table.processes[slot].as_mut().unwrap().orphaned = true;
+            task::detach(child.task);
+        }
+    }
+    drop(table);
+    EXITED.wake_all();
+}
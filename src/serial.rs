@@ -1,24 +1,326 @@
-// use crate::SERIAL1;
-use uart_16550::SerialPort;
-use spin::Mutex;
-use lazy_static::lazy_static;
-
-lazy_static! {
-    pub static ref SERIAL1: Mutex<SerialPort> = {
-        let mut serial_port = unsafe { SerialPort::new(0x3F8) };
-        serial_port.init();
-        Mutex::new(serial_port)
-    };
+//! Serial ports
+//!
+//! A small driver for the 16550 UARTs of COM1 to COM4 with configurable baud
+//! rates. Received bytes are buffered by the interrupt handlers of IRQ 4
+//! (COM1 and COM3) and IRQ 3 (COM2 and COM4).
+//!
+//! `serial_print!` and kernel logs are routed to ports separately (see
+//! `Output`), so logs can be kept out of the test results on COM1.
+
+use core::convert::TryFrom;
+use core::fmt;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use x86_64::instructions::port::Port;
+
+use crate::interrupts;
+use crate::sync::{SpinLock, SpinLockGuard, WaitQueue};
+
+/// Baud rate ports are initialized with unless `configure`d otherwise
+pub const DEFAULT_BAUD_RATE: u32 = 38400;
+/// Input clock of the UART divided by 16, the baud rate for a divisor of 1
+const MAX_BAUD_RATE: u32 = 115_200;
+
+// offsets of the UART registers from the base port
+const DATA: u16 = 0;
+const INTERRUPT_ENABLE: u16 = 1;
+const FIFO_CONTROL: u16 = 2;
+const LINE_CONTROL: u16 = 3;
+const MODEM_CONTROL: u16 = 4;
+const LINE_STATUS: u16 = 5;
+const SCRATCH: u16 = 7;
+
+/// Line status bit set while a received byte can be read
+const DATA_READY: u8 = 1;
+/// Line status bit set when another byte can be sent
+const TRANSMIT_EMPTY: u8 = 1 << 5;
+/// Number of received bytes per port that can be buffered before new ones are dropped
+const RECEIVE_BUFFER_SIZE: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ComPort {
+    Com1,
+    Com2,
+    Com3,
+    Com4,
 }
 
-#[doc(hidden)]
-pub fn _print(args: ::core::fmt::Arguments) {
-    use core::fmt::Write;
-    use x86_64::instructions::interrupts;  // same for vga_buffer, used to prevent the deadlock
+impl ComPort {
+    pub const ALL: [ComPort; 4] = [ComPort::Com1, ComPort::Com2, ComPort::Com3, ComPort::Com4];
+
+    /// Returns the first I/O port of the UART
+    pub const fn base(self) -> u16 {
+        match self {
+            ComPort::Com1 => 0x3F8,
+            ComPort::Com2 => 0x2F8,
+            ComPort::Com3 => 0x3E8,
+            ComPort::Com4 => 0x2E8,
+        }
+    }
+
+    /// Returns the PIC line of the port, which COM1/COM3 and COM2/COM4 share
+    pub const fn irq(self) -> u8 {
+        match self {
+            ComPort::Com1 | ComPort::Com3 => 4,
+            ComPort::Com2 | ComPort::Com4 => 3,
+        }
+    }
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SerialError {
+    /// the baud rate does not divide 115200, or is below 2
+    InvalidBaudRate,
+    /// no UART answers at the port
+    NotPresent,
+}
+
+/// Returns the divisor latch value for `baud_rate`
+fn divisor(baud_rate: u32) -> Result<u16, SerialError> {
+    if MAX_BAUD_RATE.checked_rem(baud_rate) != Some(0) {
+        return Err(SerialError::InvalidBaudRate);
+    }
+    // the divisor latch has 16 bits, too few for the lowest rates
+    u16::try_from(MAX_BAUD_RATE / baud_rate).map_err(|_| SerialError::InvalidBaudRate)
+}
+
+/// A 16550 UART
+pub struct Uart {
+    base: u16,
+    /// `None` until the UART has been initialized
+    baud_rate: Option<u32>,
+}
+
+impl Uart {
+    const fn new(port: ComPort) -> Self {
+        Uart { base: port.base(), baud_rate: None }
+    }
+
+    fn register(&self, offset: u16) -> Port<u8> {
+        Port::new(self.base + offset)
+    }
+
+    /// Checks for a UART by writing to its scratch register and reading it back
+    fn is_present(&mut self) -> bool {
+        let mut scratch = self.register(SCRATCH);
+        unsafe {
+            scratch.write(0xae);
+            scratch.read() == 0xae
+        }
+    }
+
+    /// Sets the baud rate and 8N1 framing and enables the "data received" interrupt
+    fn init(&mut self, baud_rate: u32) -> Result<(), SerialError> {
+        let divisor = divisor(baud_rate)?;
+        unsafe {
+            self.register(INTERRUPT_ENABLE).write(0x00);
+            // the divisor latch replaces the first two registers while bit 7 is set
+            self.register(LINE_CONTROL).write(0x80);
+            self.register(DATA).write(divisor as u8);
+            self.register(INTERRUPT_ENABLE).write((divisor >> 8) as u8);
+            self.register(LINE_CONTROL).write(0x03);
+            // enable and clear the FIFOs, interrupt once 14 bytes are waiting
+            self.register(FIFO_CONTROL).write(0xc7);
+            // DTR, RTS and OUT2, which connects the interrupt line
+            self.register(MODEM_CONTROL).write(0x0b);
+            self.register(INTERRUPT_ENABLE).write(0x01);
+        }
+        self.baud_rate = Some(baud_rate);
+        Ok(())
+    }
+
+    /// Returns the baud rate, `None` if the UART has not been initialized
+    pub fn baud_rate(&self) -> Option<u32> {
+        self.baud_rate
+    }
+
+    /// Sends a byte as is, waiting until the UART can take it
+    pub fn send(&mut self, byte: u8) {
+        let mut line_status = self.register(LINE_STATUS);
+        while unsafe { line_status.read() } & TRANSMIT_EMPTY == 0 {
+            core::hint::spin_loop();
+        }
+        unsafe { self.register(DATA).write(byte) };
+    }
+
+    /// Returns the next received byte, if there is one
+    pub fn try_receive(&mut self) -> Option<u8> {
+        if unsafe { self.register(LINE_STATUS).read() } & DATA_READY == 0 {
+            return None;
+        }
+        Some(unsafe { self.register(DATA).read() })
+    }
+}
+
+impl fmt::Write for Uart {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            self.send(byte);
+        }
+        Ok(())
+    }
+}
+
+static PORTS: [SpinLock<Uart>; 4] = [
+    SpinLock::new(Uart::new(ComPort::Com1)),
+    SpinLock::new(Uart::new(ComPort::Com2)),
+    SpinLock::new(Uart::new(ComPort::Com3)),
+    SpinLock::new(Uart::new(ComPort::Com4)),
+];
+
+/// Locks a port, initializing it with `DEFAULT_BAUD_RATE` on first use
+pub fn port(port: ComPort) -> SpinLockGuard<'static, Uart> {
+    let mut uart = PORTS[port.index()].lock();
+    if uart.baud_rate.is_none() {
+        uart.init(DEFAULT_BAUD_RATE).unwrap();
+    }
+    uart
+}
+
+/// Forcibly unlocks a port, for panic handlers that may have interrupted a print
+///
+/// # Safety
+///
+/// The current holder of the lock, if any, must never run again.
+pub unsafe fn force_unlock(port: ComPort) {
+    PORTS[port.index()].force_unlock();
+}
 
-    interrupts::without_interrupts(|| {
-        SERIAL1.lock().write_fmt(args).expect("Printing to serial failed");
+/// (Re)initializes a port with the given baud rate and unmasks its interrupt
+pub fn configure(port: ComPort, baud_rate: u32) -> Result<(), SerialError> {
+    divisor(baud_rate)?;
+    {
+        let mut uart = PORTS[port.index()].lock();
+        // COM1 is assumed to exist, everything prints to it by default
+        if port != ComPort::Com1 && !uart.is_present() {
+            return Err(SerialError::NotPresent);
+        }
+        uart.init(baud_rate)?;
+    }
+    interrupts::enable_irq(port.irq());
+    Ok(())
+}
+
+/// Initializes COM1 for input and sends kernel logs to COM2 if there is one
+pub fn init() {
+    configure(ComPort::Com1, DEFAULT_BAUD_RATE).unwrap();
+    if configure(ComPort::Com2, DEFAULT_BAUD_RATE).is_ok() {
+        set_route(Output::Log, ComPort::Com2);
+    }
+}
+
+/// A kind of output that can be routed to its own port
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Output {
+    /// `serial_print!`, which carries the test results
+    Print = 0,
+    /// kernel log messages
+    Log = 1,
+}
+
+/// Index of the port each `Output` goes to
+static ROUTES: [AtomicUsize; 2] = [AtomicUsize::new(0), AtomicUsize::new(0)];
+
+/// Sends `output` to `port` from now on
+pub fn set_route(output: Output, port: ComPort) {
+    ROUTES[output as usize].store(port.index(), Ordering::Relaxed);
+}
+
+/// Returns the port `output` goes to
+pub fn route(output: Output) -> ComPort {
+    ComPort::ALL[ROUTES[output as usize].load(Ordering::Relaxed)]
+}
+
+/// Bytes received on a port that have not been read yet
+struct ReceiveBuffer {
+    bytes: [u8; RECEIVE_BUFFER_SIZE],
+    head: usize,
+    len: usize,
+}
+
+impl ReceiveBuffer {
+    const fn new() -> Self {
+        ReceiveBuffer { bytes: [0; RECEIVE_BUFFER_SIZE], head: 0, len: 0 }
+    }
+
+    fn push(&mut self, byte: u8) -> bool {
+        if self.len == RECEIVE_BUFFER_SIZE {
+            return false;
+        }
+        self.bytes[(self.head + self.len) % RECEIVE_BUFFER_SIZE] = byte;
+        self.len += 1;
+        true
+    }
+
+    fn pop(&mut self) -> Option<u8> {
+        if self.len == 0 {
+            return None;
+        }
+        let byte = self.bytes[self.head];
+        self.head = (self.head + 1) % RECEIVE_BUFFER_SIZE;
+        self.len -= 1;
+        Some(byte)
+    }
+}
+
+static RECEIVED: [SpinLock<ReceiveBuffer>; 4] = [
+    SpinLock::new(ReceiveBuffer::new()),
+    SpinLock::new(ReceiveBuffer::new()),
+    SpinLock::new(ReceiveBuffer::new()),
+    SpinLock::new(ReceiveBuffer::new()),
+];
+static DATA_AVAILABLE: WaitQueue = WaitQueue::new();
+
+/// Moves all bytes waiting in the UARTs on PIC line `irq` into their receive buffers
+///
+/// Called by the serial interrupt handlers.
+pub(crate) fn receive_interrupt(irq: u8) {
+    let mut received = false;
+    for &com in ComPort::ALL.iter().filter(|com| com.irq() == irq) {
+        let mut uart = PORTS[com.index()].lock();
+        if uart.baud_rate.is_none() {
+            continue;
+        }
+        while let Some(byte) = uart.try_receive() {
+            // bytes that don't fit are dropped, like a UART does on overrun
+            received |= RECEIVED[com.index()].lock().push(byte);
+        }
+    }
+    if received {
+        DATA_AVAILABLE.wake_all();
+    }
+}
+
+/// Returns the next byte received on `port`, if there is one
+pub fn try_read_byte(port: ComPort) -> Option<u8> {
+    RECEIVED[port.index()].lock().pop()
+}
+
+/// Blocks until a byte has been received on `port` and returns it
+pub fn read_byte(port: ComPort) -> u8 {
+    let mut byte = None;
+    DATA_AVAILABLE.wait_until(|| {
+        byte = try_read_byte(port);
+        byte.is_some()
     });
+    byte.unwrap()
+}
+
+/// Prints to the port `output` is routed to
+pub fn print_to(output: Output, args: fmt::Arguments) {
+    use core::fmt::Write;
+
+    // interrupts stay disabled while the port is locked, see vga_buffer
+    port(route(output)).write_fmt(args).expect("Printing to serial failed");
+}
+
+#[doc(hidden)]
+pub fn _print(args: fmt::Arguments) {
+    print_to(Output::Print, args);
 }
 
 /// Prints to the host through the serial interface
@@ -38,4 +340,32 @@ macro_rules! serial_println {
     ($fmt:expr, $($arg:tt)*) => ($crate::serial_print!(
         concat!($fmt, "\n"), $($arg)*
     ));
-}
\ No newline at end of file
+}
+
+#[test_case]
+fn test_receive_buffer() {
+    let mut buffer = ReceiveBuffer::new();
+    for i in 0..RECEIVE_BUFFER_SIZE {
+        assert!(buffer.push(i as u8));
+    }
+    assert!(!buffer.push(0));
+    assert_eq!(buffer.pop(), Some(0));
+    assert!(buffer.push(42));
+    for i in 1..RECEIVE_BUFFER_SIZE {
+        assert_eq!(buffer.pop(), Some(i as u8));
+    }
+    assert_eq!(buffer.pop(), Some(42));
+    assert_eq!(buffer.pop(), None);
+}
+
+#[test_case]
+fn test_baud_rate_divisors() {
+    assert_eq!(divisor(115_200), Ok(1));
+    assert_eq!(divisor(DEFAULT_BAUD_RATE), Ok(3));
+    assert_eq!(divisor(300), Ok(384));
+    assert_eq!(divisor(0), Err(SerialError::InvalidBaudRate));
+    assert_eq!(divisor(2), Ok(57_600));
+    assert_eq!(divisor(1), Err(SerialError::InvalidBaudRate));
+    assert_eq!(divisor(56_000), Err(SerialError::InvalidBaudRate));
+    assert_eq!(configure(ComPort::Com1, 12345), Err(SerialError::InvalidBaudRate));
+}
@@ -0,0 +1,672 @@
+//! Interactive kernel shell
+//!
+//! A shell task (`run`) reads keys from its `Console`, edits the current line
+//! with a `LineEditor` and, once Enter is pressed, runs the command named by
+//! the first word of the line. Besides the built-in commands, other modules
+//! can add their own with `register`.
+//!
+//! There are two consoles: the screen, which gets its keys from the keyboard
+//! interrupt handler through `push_key`, and COM1, whose bytes are decoded by
+//! a `TerminalDecoder`. The latter lets the host drive the kernel headlessly,
+//! e.g. with `-serial stdio`.
+//!
+//! The editor only uses printable ASCII, backspace (`0x08`, which moves one
+//! column left without erasing) and `\n` to redraw the line, so it works on
+//! any output that understands these, not just the VGA buffer.
+
+use core::fmt::{self, Write};
+
+use pc_keyboard::{DecodedKey, KeyCode};
+use x86_64::instructions::port::Port;
+
+use crate::serial::{self, ComPort};
+use crate::sync::{SpinLock, WaitQueue};
+use crate::vga_buffer::WRITER;
+use crate::{interrupts, memory, task};
+
+/// Maximum length of a line in bytes; together with the prompt it fits into
+/// one row of the screen
+pub const MAX_LINE: usize = 76;
+/// Number of lines kept in the history
+pub const HISTORY_SIZE: usize = 16;
+/// Maximum number of commands added with `register`
+pub const MAX_COMMANDS: usize = 16;
+
+const PROMPT: &str = "> ";
+/// Number of keys that can be queued before the shell task reads them
+const QUEUE_SIZE: usize = 32;
+
+/// A key the shell knows how to handle
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Key {
+    /// a printable ASCII character
+    Char(char),
+    Enter,
+    Backspace,
+    Delete,
+    Left,
+    Right,
+    Up,
+    Down,
+    Home,
+    End,
+}
+
+impl Key {
+    /// Translates a key from the keyboard decoder, `None` for keys the shell ignores
+    pub fn from_decoded(key: DecodedKey) -> Option<Key> {
+        match key {
+            DecodedKey::Unicode('\n') => Some(Key::Enter),
+            DecodedKey::Unicode('\u{8}') => Some(Key::Backspace),
+            DecodedKey::Unicode('\u{7f}') => Some(Key::Delete),
+            DecodedKey::Unicode(c) if c == ' ' || c.is_ascii_graphic() => Some(Key::Char(c)),
+            DecodedKey::RawKey(KeyCode::ArrowLeft) => Some(Key::Left),
+            DecodedKey::RawKey(KeyCode::ArrowRight) => Some(Key::Right),
+            DecodedKey::RawKey(KeyCode::ArrowUp) => Some(Key::Up),
+            DecodedKey::RawKey(KeyCode::ArrowDown) => Some(Key::Down),
+            DecodedKey::RawKey(KeyCode::Home) => Some(Key::Home),
+            DecodedKey::RawKey(KeyCode::End) => Some(Key::End),
+            _ => None,
+        }
+    }
+}
+
+/// FIFO of keys received but not yet handled by the shell task
+struct KeyQueue {
+    keys: [Option<Key>; QUEUE_SIZE],
+    head: usize,
+    len: usize,
+}
+
+impl KeyQueue {
+    fn push(&mut self, key: Key) -> bool {
+        if self.len == QUEUE_SIZE {
+            return false;
+        }
+        self.keys[(self.head + self.len) % QUEUE_SIZE] = Some(key);
+        self.len += 1;
+        true
+    }
+
+    fn pop(&mut self) -> Option<Key> {
+        if self.len == 0 {
+            return None;
+        }
+        let key = self.keys[self.head].take();
+        self.head = (self.head + 1) % QUEUE_SIZE;
+        self.len -= 1;
+        key
+    }
+}
+
+static KEYS: SpinLock<KeyQueue> = SpinLock::new(KeyQueue {
+    keys: [None; QUEUE_SIZE],
+    head: 0,
+    len: 0,
+});
+static KEY_AVAILABLE: WaitQueue = WaitQueue::new();
+
+/// Queues a key for the shell task; the key is dropped if the queue is full
+///
+/// This never blocks, so it is safe to call from interrupt handlers.
+pub fn push_key(key: Key) {
+    if KEYS.lock().push(key) {
+        KEY_AVAILABLE.wake_one();
+    }
+}
+
+/// Blocks until a key is queued and returns it
+pub fn read_key() -> Key {
+    let mut key = None;
+    KEY_AVAILABLE.wait_until(|| {
+        key = KEYS.lock().pop();
+        key.is_some()
+    });
+    key.unwrap()
+}
+
+/// Turns the bytes sent by a terminal into keys, including the VT100 escape
+/// sequences of the cursor keys
+#[derive(Debug)]
+pub struct TerminalDecoder {
+    state: DecoderState,
+    /// the last byte was `\r`, so a following `\n` belongs to the same Enter
+    after_return: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DecoderState {
+    Ground,
+    /// after ESC
+    Escape,
+    /// after ESC [ or ESC O, with the numeric parameter read so far
+    Sequence(u8),
+}
+
+impl TerminalDecoder {
+    pub const fn new() -> Self {
+        TerminalDecoder { state: DecoderState::Ground, after_return: false }
+    }
+
+    /// Feeds one byte, returns a key once one is complete
+    pub fn feed(&mut self, byte: u8) -> Option<Key> {
+        let after_return = core::mem::replace(&mut self.after_return, byte == b'\r');
+        match self.state {
+            DecoderState::Ground => match byte {
+                0x1b => {
+                    self.state = DecoderState::Escape;
+                    None
+                }
+                b'\n' if after_return => None,
+                b'\r' | b'\n' => Some(Key::Enter),
+                0x08 | 0x7f => Some(Key::Backspace),
+                0x20..=0x7e => Some(Key::Char(char::from(byte))),
+                _ => None,
+            },
+            DecoderState::Escape => {
+                self.state = match byte {
+                    b'[' | b'O' => DecoderState::Sequence(0),
+                    _ => DecoderState::Ground,
+                };
+                None
+            }
+            DecoderState::Sequence(parameter) => {
+                if byte.is_ascii_digit() {
+                    let parameter = parameter.saturating_mul(10).saturating_add(byte - b'0');
+                    self.state = DecoderState::Sequence(parameter);
+                    return None;
+                }
+                self.state = DecoderState::Ground;
+                match (byte, parameter) {
+                    (b'A', _) => Some(Key::Up),
+                    (b'B', _) => Some(Key::Down),
+                    (b'C', _) => Some(Key::Right),
+                    (b'D', _) => Some(Key::Left),
+                    (b'H', _) | (b'~', 1) | (b'~', 7) => Some(Key::Home),
+                    (b'F', _) | (b'~', 4) | (b'~', 8) => Some(Key::End),
+                    (b'~', 3) => Some(Key::Delete),
+                    _ => None,
+                }
+            }
+        }
+    }
+}
+
+impl Default for TerminalDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Where a shell reads its keys from and writes its output to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(usize)]
+pub enum Console {
+    /// the keyboard and the VGA text buffer
+    Screen = 0,
+    /// COM1, see `serial`
+    Serial = 1,
+}
+
+impl Console {
+    /// Clears the screen, or the terminal connected to the serial port
+    pub fn clear(&mut self) {
+        match self {
+            Console::Screen => WRITER.lock().clear_screen(),
+            Console::Serial => {
+                self.write_str("\x1b[2J\x1b[H").ok();
+            }
+        }
+    }
+}
+
+impl Write for Console {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        match self {
+            Console::Screen => WRITER.lock().write_string(s),
+            Console::Serial => {
+                let mut serial = serial::port(ComPort::Com1);
+                // terminals need a carriage return to get back to the first column
+                for (i, part) in s.split('\n').enumerate() {
+                    if i > 0 {
+                        serial.write_str("\r\n")?;
+                    }
+                    serial.write_str(part)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Line {
+    bytes: [u8; MAX_LINE],
+    len: usize,
+}
+
+impl Line {
+    const EMPTY: Line = Line { bytes: [0; MAX_LINE], len: 0 };
+
+    fn as_str(&self) -> &str {
+        // only printable ASCII is ever inserted
+        core::str::from_utf8(&self.bytes[..self.len]).unwrap_or("")
+    }
+}
+
+/// Editor for a single line of input with a history of previous lines
+pub struct LineEditor {
+    line: Line,
+    /// position of the cursor in `line`
+    cursor: usize,
+    /// previous lines, oldest first
+    history: [Line; HISTORY_SIZE],
+    history_len: usize,
+    /// index of the history entry being shown, `history_len` if none is
+    browsing: usize,
+    /// the line that was being typed before browsing the history
+    draft: Line,
+}
+
+impl LineEditor {
+    pub const fn new() -> Self {
+        LineEditor {
+            line: Line::EMPTY,
+            cursor: 0,
+            history: [Line::EMPTY; HISTORY_SIZE],
+            history_len: 0,
+            browsing: 0,
+            draft: Line::EMPTY,
+        }
+    }
+
+    /// Returns the line being edited
+    pub fn line(&self) -> &str {
+        self.line.as_str()
+    }
+
+    /// Returns the position of the cursor in the line
+    pub fn cursor(&self) -> usize {
+        self.cursor
+    }
+
+    /// Applies `key` to the line and echoes the change to `out`
+    ///
+    /// Returns the finished line when `key` is Enter. Lines that are not
+    /// blank are added to the history unless they repeat the latest entry.
+    pub fn handle_key<W: Write>(&mut self, key: Key, out: &mut W) -> Option<&str> {
+        match key {
+            Key::Char(c) if self.line.len < MAX_LINE && (c == ' ' || c.is_ascii_graphic()) => {
+                let (cursor, len) = (self.cursor, self.line.len);
+                self.line.bytes.copy_within(cursor..len, cursor + 1);
+                self.line.bytes[cursor] = c as u8;
+                self.line.len += 1;
+                self.cursor += 1;
+                self.redraw_from(cursor, 0, out);
+            }
+            Key::Backspace if self.cursor > 0 => {
+                self.cursor -= 1;
+                self.remove_at_cursor();
+                out.write_char('\u{8}').ok();
+                self.redraw_from(self.cursor, 1, out);
+            }
+            Key::Delete if self.cursor < self.line.len => {
+                self.remove_at_cursor();
+                self.redraw_from(self.cursor, 1, out);
+            }
+            Key::Left if self.cursor > 0 => {
+                self.cursor -= 1;
+                out.write_char('\u{8}').ok();
+            }
+            Key::Right if self.cursor < self.line.len => {
+                out.write_char(char::from(self.line.bytes[self.cursor])).ok();
+                self.cursor += 1;
+            }
+            Key::Home => self.move_to(0, out),
+            Key::End => self.move_to(self.line.len, out),
+            Key::Up if self.browsing > 0 => {
+                if self.browsing == self.history_len {
+                    self.draft = self.line;
+                }
+                self.browsing -= 1;
+                self.replace_line(self.history[self.browsing], out);
+            }
+            Key::Down if self.browsing < self.history_len => {
+                self.browsing += 1;
+                let line = if self.browsing == self.history_len {
+                    self.draft
+                } else {
+                    self.history[self.browsing]
+                };
+                self.replace_line(line, out);
+            }
+            Key::Enter => {
+                out.write_char('\n').ok();
+                let line = core::mem::replace(&mut self.line, Line::EMPTY);
+                self.cursor = 0;
+                if line.as_str().trim().is_empty() {
+                    self.browsing = self.history_len;
+                    return Some("");
+                }
+                let latest = self.history_len.checked_sub(1).map(|i| &self.history[i]);
+                if latest.map(|l| l.as_str()) != Some(line.as_str()) {
+                    if self.history_len == HISTORY_SIZE {
+                        self.history.copy_within(1.., 0);
+                        self.history_len -= 1;
+                    }
+                    self.history[self.history_len] = line;
+                    self.history_len += 1;
+                }
+                self.browsing = self.history_len;
+                return Some(self.history[self.history_len - 1].as_str());
+            }
+            _ => {}
+        }
+        None
+    }
+
+    fn remove_at_cursor(&mut self) {
+        let (cursor, len) = (self.cursor, self.line.len);
+        self.line.bytes.copy_within(cursor + 1..len, cursor);
+        self.line.len -= 1;
+    }
+
+    /// Rewrites the line from `from` to its end, blanks `erase` columns after
+    /// it and moves back to the cursor
+    fn redraw_from<W: Write>(&self, from: usize, erase: usize, out: &mut W) {
+        out.write_str(&self.line.as_str()[from..]).ok();
+        for _ in 0..erase {
+            out.write_char(' ').ok();
+        }
+        for _ in self.cursor..self.line.len + erase {
+            out.write_char('\u{8}').ok();
+        }
+    }
+
+    fn move_to<W: Write>(&mut self, position: usize, out: &mut W) {
+        if position < self.cursor {
+            for _ in position..self.cursor {
+                out.write_char('\u{8}').ok();
+            }
+        } else {
+            out.write_str(&self.line.as_str()[self.cursor..position]).ok();
+        }
+        self.cursor = position;
+    }
+
+    fn replace_line<W: Write>(&mut self, line: Line, out: &mut W) {
+        self.move_to(0, out);
+        let old_len = self.line.len;
+        self.line = line;
+        self.cursor = line.len;
+        self.redraw_from(0, old_len.saturating_sub(line.len), out);
+    }
+}
+
+impl Default for LineEditor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A shell command
+#[derive(Debug, Clone, Copy)]
+pub struct Command {
+    /// the word that runs the command
+    pub name: &'static str,
+    /// one line description shown by `help`
+    pub help: &'static str,
+    /// called with the console of the shell and the rest of the line, trimmed
+    pub run: fn(&mut Console, &str),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShellError {
+    /// the name is empty or contains whitespace
+    InvalidName,
+    AlreadyExists,
+    TooManyCommands,
+}
+
+const BUILTINS: &[Command] = &[
+    Command { name: "help", help: "list the available commands", run: help },
+    Command { name: "meminfo", help: "show the number of frames in use", run: meminfo },
+    Command { name: "uptime", help: "show the time since boot", run: uptime },
+    Command { name: "irqstats", help: "show the number of interrupts per IRQ line", run: irqstats },
+    Command { name: "clear", help: "clear the screen", run: clear },
+    Command { name: "reboot", help: "restart the machine", run: reboot },
+    Command { name: "echo", help: "print the arguments", run: echo },
+];
+
+static COMMANDS: SpinLock<[Option<Command>; MAX_COMMANDS]> = SpinLock::new([None; MAX_COMMANDS]);
+
+/// Adds a command to the shell
+pub fn register(
+    name: &'static str,
+    help: &'static str,
+    run: fn(&mut Console, &str),
+) -> Result<(), ShellError> {
+    if name.is_empty() || name.contains(char::is_whitespace) {
+        return Err(ShellError::InvalidName);
+    }
+    let mut commands = COMMANDS.lock();
+    if BUILTINS.iter().chain(commands.iter().flatten()).any(|c| c.name == name) {
+        return Err(ShellError::AlreadyExists);
+    }
+    let slot = commands
+        .iter()
+        .position(|c| c.is_none())
+        .ok_or(ShellError::TooManyCommands)?;
+    commands[slot] = Some(Command { name, help, run });
+    Ok(())
+}
+
+fn find(name: &str) -> Option<Command> {
+    BUILTINS
+        .iter()
+        .copied()
+        .chain(COMMANDS.lock().iter().flatten().copied())
+        .find(|c| c.name == name)
+}
+
+/// Runs the command named by the first word of `line`
+pub fn execute(console: &mut Console, line: &str) {
+    let line = line.trim();
+    if line.is_empty() {
+        return;
+    }
+    let (name, args) = match line.find(char::is_whitespace) {
+        Some(end) => (&line[..end], line[end..].trim_start()),
+        None => (line, ""),
+    };
+    match find(name) {
+        Some(command) => (command.run)(console, args),
+        None => {
+            writeln!(console, "unknown command: {} (try `help`)", name).ok();
+        }
+    }
+}
+
+/// Entry point of a shell task, to be started with `task::spawn` and a
+/// `Console` as argument
+pub fn run(console: usize) {
+    let mut console = match console {
+        0 => Console::Screen,
+        _ => Console::Serial,
+    };
+    let mut decoder = TerminalDecoder::new();
+    let mut editor = LineEditor::new();
+    loop {
+        console.write_str(PROMPT).ok();
+        loop {
+            let key = match console {
+                Console::Screen => read_key(),
+                Console::Serial => match decoder.feed(serial::read_byte(ComPort::Com1)) {
+                    Some(key) => key,
+                    None => continue,
+                },
+            };
+            if let Some(line) = editor.handle_key(key, &mut console) {
+                execute(&mut console, line);
+                break;
+            }
+        }
+    }
+}
+
+fn help(console: &mut Console, _: &str) {
+    let registered = *COMMANDS.lock();
+    for command in BUILTINS.iter().chain(registered.iter().flatten()) {
+        writeln!(console, "{:<10} {}", command.name, command.help).ok();
+    }
+}
+
+fn meminfo(console: &mut Console, _: &str) {
+    let frames = memory::frames_in_use();
+    writeln!(console, "frames in use: {} ({} KiB)", frames, frames * 4).ok();
+    writeln!(console, "tasks: {}", task::task_count()).ok();
+}
+
+fn uptime(console: &mut Console, _: &str) {
+    let ms = interrupts::uptime_ms();
+    let seconds = ms / 1000;
+    writeln!(
+        console,
+        "up {}:{:02}:{:02}.{:03} ({} ticks)",
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60,
+        ms % 1000,
+        interrupts::ticks()
+    )
+    .ok();
+}
+
+fn irqstats(console: &mut Console, _: &str) {
+    const NAMES: [&str; interrupts::IRQ_LINES] = [
+        "timer", "keyboard", "cascade", "COM2", "COM1", "LPT2", "floppy", "LPT1",
+        "RTC", "free", "free", "free", "mouse", "FPU", "ATA1", "ATA2",
+    ];
+    for (irq, name) in NAMES.iter().enumerate() {
+        let count = interrupts::irq_count(irq);
+        if count > 0 {
+            writeln!(console, "{:>2} {:<9} {}", irq, name, count).ok();
+        }
+    }
+}
+
+fn clear(console: &mut Console, _: &str) {
+    console.clear();
+}
+
+fn reboot(console: &mut Console, _: &str) {
+    // pulse the reset line through the keyboard controller once its input
+    // buffer is empty
+    let mut status: Port<u8> = Port::new(0x64);
+    unsafe {
+        while status.read() & 0x02 != 0 {}
+        status.write(0xfe);
+    }
+    writeln!(console, "reboot failed").ok();
+}
+
+fn echo(console: &mut Console, args: &str) {
+    writeln!(console, "{}", args).ok();
+}
+
+/// Applies the editor's output to a single row, like a terminal would
+#[cfg(test)]
+struct TestRow {
+    chars: [u8; 80],
+    column: usize,
+}
+
+#[cfg(test)]
+impl Write for TestRow {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            match byte {
+                0x08 => self.column -= 1,
+                byte => {
+                    self.chars[self.column] = byte;
+                    self.column += 1;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[test_case]
+fn test_line_editing() {
+    let mut row = TestRow { chars: [b' '; 80], column: 0 };
+    let mut editor = LineEditor::new();
+    let keys = [
+        Key::Char('e'), Key::Char('c'), Key::Char('o'), Key::Left, Key::Char('h'),
+        Key::End, Key::Char('x'), Key::Backspace, Key::Home, Key::Delete, Key::Char('E'),
+    ];
+    for &key in keys.iter() {
+        assert_eq!(editor.handle_key(key, &mut row), None);
+    }
+    assert_eq!(editor.line(), "Echo");
+    assert_eq!(editor.cursor(), 1);
+    assert_eq!(&row.chars[..6], b"Echo  ");
+    assert_eq!(row.column, 1);
+    assert_eq!(editor.handle_key(Key::Enter, &mut row), Some("Echo"));
+    assert_eq!(editor.line(), "");
+}
+
+#[test_case]
+fn test_history() {
+    let mut row = TestRow { chars: [b' '; 80], column: 0 };
+    let mut editor = LineEditor::new();
+    for line in ["first", "second", "second", " "].iter() {
+        for c in line.chars() {
+            editor.handle_key(Key::Char(c), &mut row);
+        }
+        editor.handle_key(Key::Enter, &mut row);
+        row = TestRow { chars: [b' '; 80], column: 0 };
+    }
+    editor.handle_key(Key::Char('x'), &mut row);
+    editor.handle_key(Key::Up, &mut row);
+    editor.handle_key(Key::Up, &mut row);
+    // there are only two entries since repeated and blank lines are skipped
+    editor.handle_key(Key::Up, &mut row);
+    assert_eq!(editor.line(), "first");
+    editor.handle_key(Key::Down, &mut row);
+    assert_eq!(editor.line(), "second");
+    assert_eq!(&row.chars[..7], b"second ");
+    editor.handle_key(Key::Down, &mut row);
+    assert_eq!(editor.line(), "x");
+    assert_eq!(&row.chars[..7], b"x      ");
+    assert_eq!(row.column, 1);
+}
+
+#[test_case]
+fn test_terminal_decoder() {
+    let mut decoder = TerminalDecoder::new();
+    let mut keys = [Key::Enter; 8];
+    let mut len = 0;
+    for &byte in b"a\x7f\r\n\n\x1b[D\x1b[3~\x1bOH\x1b[5~".iter() {
+        if let Some(key) = decoder.feed(byte) {
+            keys[len] = key;
+            len += 1;
+        }
+    }
+    let expected = [
+        Key::Char('a'), Key::Backspace, Key::Enter, Key::Enter, Key::Left, Key::Delete, Key::Home,
+    ];
+    assert_eq!(&keys[..len], &expected[..]);
+}
+
+#[test_case]
+fn test_register() {
+    fn nothing(_: &mut Console, _: &str) {}
+
+    assert_eq!(register("test-command", "does nothing", nothing), Ok(()));
+    assert_eq!(register("test-command", "does nothing", nothing), Err(ShellError::AlreadyExists));
+    assert_eq!(register("help", "shadows a builtin", nothing), Err(ShellError::AlreadyExists));
+    assert_eq!(register("two words", "invalid", nothing), Err(ShellError::InvalidName));
+    assert!(find("test-command").is_some());
+    execute(&mut Console::Serial, "  test-command with arguments ");
+}
@@ -0,0 +1,219 @@
+//! Named shared memory
+//!
+//! A shared memory object is a set of zeroed frames with a name. Mapping it
+//! into an address space maps the same frames there, marked `SHARED` so that
+//! a fork keeps them shared instead of copying them on write. The object and
+//! every mapping each own a reference to the frames (see
+//! `memory::acquire_frame`), so after `unlink` removes the name the frames are
+//! freed once the last mapping is gone.
+
+use x86_64::structures::paging::mapper::MapToError;
+use x86_64::structures::paging::{FrameAllocator, PageTableFlags, PhysFrame, Size4KiB};
+use x86_64::{align_up, VirtAddr};
+
+use crate::address_space::AddressSpace;
+use crate::memory::{self, GlobalFrameAllocator, SHARED, USER_SPACE_END, USER_SPACE_START};
+use crate::sync::SpinLock;
+
+/// Maximum number of shared memory objects
+pub const MAX_OBJECTS: usize = 8;
+/// Maximum length of the name of an object in bytes
+pub const MAX_NAME_LEN: usize = 32;
+/// Maximum size of an object in pages
+pub const MAX_PAGES: usize = 16;
+
+const PAGE_SIZE: u64 = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShmError {
+    /// the name is empty or longer than `MAX_NAME_LEN`
+    InvalidName,
+    /// the size is 0 or larger than `MAX_PAGES` pages
+    InvalidSize,
+    AlreadyExists,
+    TooManyObjects,
+    NotFound,
+    OutOfMemory,
+    /// the address is not page aligned or the object does not fit into user space
+    InvalidAddress,
+    /// part of the range is mapped already
+    AddressInUse,
+}
+
+impl From<MapToError<Size4KiB>> for ShmError {
+    fn from(error: MapToError<Size4KiB>) -> Self {
+        match error {
+            MapToError::FrameAllocationFailed => ShmError::OutOfMemory,
+            MapToError::PageAlreadyMapped(_) | MapToError::ParentEntryHugePage => ShmError::AddressInUse,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Object {
+    name: [u8; MAX_NAME_LEN],
+    name_len: usize,
+    frames: [Option<PhysFrame>; MAX_PAGES],
+}
+
+impl Object {
+    fn name(&self) -> &[u8] {
+        &self.name[..self.name_len]
+    }
+
+    fn frames(&self) -> impl Iterator<Item = PhysFrame> + '_ {
+        self.frames.iter().flatten().copied()
+    }
+
+    fn size(&self) -> u64 {
+        self.frames().count() as u64 * PAGE_SIZE
+    }
+
+    /// Gives up the references of the object to its frames
+    fn release_frames(&self) {
+        for frame in self.frames() {
+            unsafe { memory::release_frame(frame) };
+        }
+    }
+}
+
+static OBJECTS: SpinLock<[Option<Object>; MAX_OBJECTS]> = SpinLock::new([None; MAX_OBJECTS]);
+
+fn find(objects: &[Option<Object>; MAX_OBJECTS], name: &str) -> Option<usize> {
+    objects
+        .iter()
+        .position(|o| matches!(o, Some(object) if object.name() == name.as_bytes()))
+}
+
+/// Creates an object of `size` bytes, rounded up to whole pages, filled with zeros
+pub fn create(name: &str, size: u64) -> Result<(), ShmError> {
+    if name.is_empty() || name.len() > MAX_NAME_LEN {
+        return Err(ShmError::InvalidName);
+    }
+    if size == 0 || size > MAX_PAGES as u64 * PAGE_SIZE {
+        return Err(ShmError::InvalidSize);
+    }
+    let mut objects = OBJECTS.lock();
+    if find(&objects, name).is_some() {
+        return Err(ShmError::AlreadyExists);
+    }
+    let slot = objects
+        .iter()
+        .position(|o| o.is_none())
+        .ok_or(ShmError::TooManyObjects)?;
+
+    let mut object = Object {
+        name: [0; MAX_NAME_LEN],
+        name_len: name.len(),
+        frames: [None; MAX_PAGES],
+    };
+    object.name[..name.len()].copy_from_slice(name.as_bytes());
+    let pages = (align_up(size, PAGE_SIZE) / PAGE_SIZE) as usize;
+    for frame in object.frames.iter_mut().take(pages) {
+        let new_frame = match GlobalFrameAllocator.allocate_frame() {
+            Some(frame) => frame,
+            None => {
+                object.release_frames();
+                return Err(ShmError::OutOfMemory);
+            }
+        };
+        let ptr: *mut u8 = memory::phys_to_virt(new_frame.start_address()).as_mut_ptr();
+        unsafe { ptr.write_bytes(0, PAGE_SIZE as usize) };
+        *frame = Some(new_frame);
+    }
+    objects[slot] = Some(object);
+    Ok(())
+}
+
+/// Returns the size of an object in bytes
+pub fn size_of(name: &str) -> Option<u64> {
+    let objects = OBJECTS.lock();
+    find(&objects, name).map(|slot| objects[slot].unwrap().size())
+}
+
+/// Maps the object `name` into `address_space` at `start` and returns its size
+///
+/// Only `WRITABLE` and `NO_EXECUTE` are taken from `flags`.
+pub fn map(
+    address_space: &mut AddressSpace,
+    name: &str,
+    start: VirtAddr,
+    flags: PageTableFlags,
+) -> Result<u64, ShmError> {
+    let objects = OBJECTS.lock();
+    let object = find(&objects, name).map(|slot| objects[slot].unwrap()).ok_or(ShmError::NotFound)?;
+    let size = object.size();
+    if !start.is_aligned(PAGE_SIZE)
+        || start.as_u64() < USER_SPACE_START
+        || USER_SPACE_END - start.as_u64() < size
+    {
+        return Err(ShmError::InvalidAddress);
+    }
+    let flags = flags & (PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE) | SHARED;
+    address_space.map_frames(start, object.frames(), flags)?;
+    Ok(size)
+}
+
+/// Removes the name of an object
+///
+/// Existing mappings stay valid; the frames are freed when the last of them
+/// is gone.
+pub fn unlink(name: &str) -> Result<(), ShmError> {
+    let object = {
+        let mut objects = OBJECTS.lock();
+        let slot = find(&objects, name).ok_or(ShmError::NotFound)?;
+        objects[slot].take().unwrap()
+    };
+    object.release_frames();
+    Ok(())
+}
+
+#[test_case]
+fn test_create_checks_arguments() {
+    assert_eq!(create("", 4096), Err(ShmError::InvalidName));
+    assert_eq!(create("too large", MAX_PAGES as u64 * PAGE_SIZE + 1), Err(ShmError::InvalidSize));
+    assert_eq!(create("test", 100), Ok(()));
+    assert_eq!(create("test", 100), Err(ShmError::AlreadyExists));
+    assert_eq!(size_of("test"), Some(PAGE_SIZE));
+    assert_eq!(unlink("test"), Ok(()));
+    assert_eq!(unlink("test"), Err(ShmError::NotFound));
+}
+
+#[test_case]
+fn test_mappings_share_frames() {
+    use x86_64::instructions::interrupts;
+    use x86_64::registers::control::Cr3;
+
+    let before = memory::frames_in_use();
+    let addr = VirtAddr::new(USER_SPACE_START);
+    let mut first = AddressSpace::new().expect("out of frames");
+    let mut second = AddressSpace::new().expect("out of frames");
+    create("shared", 2 * PAGE_SIZE).unwrap();
+    assert_eq!(map(&mut first, "shared", addr, PageTableFlags::WRITABLE), Ok(2 * PAGE_SIZE));
+    assert_eq!(map(&mut second, "shared", addr + PAGE_SIZE, PageTableFlags::empty()), Ok(2 * PAGE_SIZE));
+    assert_eq!(map(&mut second, "shared", addr, PageTableFlags::empty()), Err(ShmError::AddressInUse));
+    // a fork keeps the pages shared instead of copying them
+    let third = first.fork().expect("fork failed");
+    unlink("shared").unwrap();
+
+    let ptr: *mut u64 = (addr + PAGE_SIZE).as_mut_ptr();
+    let seen = interrupts::without_interrupts(|| unsafe {
+        let kernel = memory::kernel_page_table().unwrap();
+        let (_, flags) = Cr3::read();
+        first.activate();
+        ptr.write_volatile(0xfeed);
+        second.activate();
+        let in_second = (addr + 2 * PAGE_SIZE).as_ptr::<u64>().read_volatile();
+        third.activate();
+        let in_third = ptr.read_volatile();
+        Cr3::write(kernel, flags);
+        (in_second, in_third)
+    });
+    assert_eq!(seen, (0xfeed, 0xfeed));
+
+    assert_eq!(second.unmap_region(addr + PAGE_SIZE, 2 * PAGE_SIZE), 2);
+    drop(first);
+    drop(second);
+    drop(third);
+    assert_eq!(memory::frames_in_use(), before);
+}
@@ -0,0 +1,448 @@
+//! Signals: asynchronous notifications to processes
+//!
+//! A signal is sent with `send` (the `kill` system call) or raised by the
+//! kernel, for example for a fault in user mode. It stays pending until the
+//! process returns to user mode, from a system call or from a timer
+//! interrupt. Then the action registered with `set_action` runs: the default
+//! action (ending the process or nothing, see `Signal::default_action`),
+//! nothing, or a handler in the program. A process blocked in a system call
+//! is woken, and the call fails with `Interrupted` unless the signal is
+//! ignored.
+//!
+//! A handler is called as `handler(signal, context)` on the user stack, with
+//! `context` pointing to the saved registers (a `SyscallFrame`) and the
+//! return address set to the restorer registered with it. The restorer calls
+//! `sigreturn`, which continues where the signal interrupted the program.
+//! Signals raised for a fault are delivered at once; if the program has no
+//! handler for them, the task ends with `USER_FAULT_EXIT_CODE` as before.
+//!
+//! A timer interrupt or a fault only has the interrupt stack frame of the
+//! program, not its general purpose registers. To deliver a signal there,
+//! the handler returns to `signal_entry` in ring 0 instead, which saves all
+//! registers like the system call entry code and returns to the program
+//! with `iretq` afterwards.
+
+use core::arch::global_asm;
+use core::mem::size_of;
+
+use x86_64::registers::rflags::RFlags;
+use x86_64::structures::idt::InterruptStackFrame;
+use x86_64::VirtAddr;
+
+use crate::memory::{self, USER_SPACE_END};
+use crate::process::{self, Pid};
+use crate::syscall::{self, SyscallFrame};
+use crate::{gdt, task, usermode};
+
+/// Number of signal numbers, including the unused 0
+pub const MAX_SIGNAL: usize = 32;
+
+/// Bytes below the user stack pointer that are left alone when a handler
+/// frame is pushed (the red zone of the System V ABI)
+const RED_ZONE: u64 = 128;
+
+/// Signals, numbered as on Linux
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Signal {
+    Hangup = 1,
+    Interrupt = 2,
+    IllegalInstruction = 4,
+    ArithmeticError = 8,
+    /// ends the process, cannot be handled or ignored
+    Kill = 9,
+    User1 = 10,
+    SegmentationFault = 11,
+    User2 = 12,
+    BrokenPipe = 13,
+    Terminate = 15,
+    /// sent to the parent when a child process exits
+    Child = 17,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DefaultAction {
+    Terminate,
+    Ignore,
+}
+
+impl Signal {
+    pub fn from_number(number: u64) -> Option<Signal> {
+        use Signal::*;
+        [
+            Hangup,
+            Interrupt,
+            IllegalInstruction,
+            ArithmeticError,
+            Kill,
+            User1,
+            SegmentationFault,
+            User2,
+            BrokenPipe,
+            Terminate,
+            Child,
+        ]
+        .iter()
+        .copied()
+        .find(|signal| signal.number() == number)
+    }
+
+    pub fn number(self) -> u64 {
+        self as u64
+    }
+
+    pub fn default_action(self) -> DefaultAction {
+        match self {
+            Signal::Child => DefaultAction::Ignore,
+            _ => DefaultAction::Terminate,
+        }
+    }
+
+    /// Exit code of a process ended by this signal
+    pub fn exit_code(self) -> i64 {
+        -128 - self as i64
+    }
+
+    fn bit(self) -> u32 {
+        1 << self as u32
+    }
+}
+
+/// What a process does when it gets a signal
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Default,
+    Ignore,
+    /// call `handler`, which returns to `restorer`
+    Handler { handler: VirtAddr, restorer: VirtAddr },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SignalError {
+    NoSuchProcess,
+    /// `Kill` cannot be handled or ignored
+    CannotCatch,
+    /// the handler or the restorer is outside of user space
+    InvalidHandler,
+    /// the current task is no process
+    NoProcess,
+}
+
+/// Pending signals and actions of a process, kept in the process table
+#[derive(Debug, Clone, Copy)]
+pub struct SignalState {
+    pending: u32,
+    actions: [Action; MAX_SIGNAL],
+}
+
+impl SignalState {
+    pub const fn new() -> Self {
+        SignalState {
+            pending: 0,
+            actions: [Action::Default; MAX_SIGNAL],
+        }
+    }
+
+    /// Returns the state of a forked child: the same actions, nothing pending
+    pub fn forked(&self) -> Self {
+        SignalState {
+            pending: 0,
+            actions: self.actions,
+        }
+    }
+
+    pub fn raise(&mut self, signal: Signal) {
+        self.pending |= signal.bit();
+    }
+
+    pub fn is_pending(&self) -> bool {
+        self.pending != 0
+    }
+
+    /// Returns whether a pending signal does something when delivered, which
+    /// ends blocking system calls early
+    pub fn interrupts_blocking(&self) -> bool {
+        (1..MAX_SIGNAL as u64)
+            .filter(|&number| self.pending & 1 << number != 0)
+            .filter_map(Signal::from_number)
+            .any(|signal| match self.actions[signal.number() as usize] {
+                Action::Ignore => false,
+                Action::Default => signal.default_action() != DefaultAction::Ignore,
+                Action::Handler { .. } => true,
+            })
+    }
+
+    /// Removes the lowest pending signal and returns it with its action
+    fn take_pending(&mut self) -> Option<(Signal, Action)> {
+        while self.pending != 0 {
+            let number = self.pending.trailing_zeros();
+            self.pending &= !(1 << number);
+            if let Some(signal) = Signal::from_number(number.into()) {
+                return Some((signal, self.actions[number as usize]));
+            }
+        }
+        None
+    }
+}
+
+impl Default for SignalState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Sends `signal` to the process `pid`
+///
+/// A process blocked in a system call is woken, so that the call can end
+/// with `SyscallError::Interrupted` and the signal is delivered.
+pub fn send(pid: Pid, signal: Signal) -> Result<(), SignalError> {
+    let task = process::with_signals(pid, |signals| signals.raise(signal))
+        .and_then(|()| process::task_of(pid))
+        .ok_or(SignalError::NoSuchProcess)?;
+    task::unblock(task);
+    Ok(())
+}
+
+/// Returns whether the current process has a pending signal that should end
+/// a blocking call, see `SignalState::interrupts_blocking`
+pub fn interrupted() -> bool {
+    process::with_current_signals(|signals| signals.interrupts_blocking()).unwrap_or(false)
+}
+
+/// Sets the action of the current process for `signal` and returns the old one
+pub fn set_action(signal: Signal, action: Action) -> Result<Action, SignalError> {
+    if signal == Signal::Kill {
+        return Err(SignalError::CannotCatch);
+    }
+    if let Action::Handler { handler, restorer } = action {
+        if handler.as_u64() >= USER_SPACE_END || restorer.as_u64() >= USER_SPACE_END {
+            return Err(SignalError::InvalidHandler);
+        }
+    }
+    process::with_current_signals(|signals| {
+        let slot = &mut signals.actions[signal.number() as usize];
+        core::mem::replace(slot, action)
+    })
+    .ok_or(SignalError::NoProcess)
+}
+
+/// Runs the actions of the pending signals of the current process before
+/// it returns to user mode with the registers in `frame`
+///
+/// Ends the process for a signal whose action is to terminate. For a signal
+/// with a handler, `frame` is changed to enter the handler; further signals
+/// are delivered when it returns.
+pub fn deliver(frame: &mut SyscallFrame) {
+    loop {
+        let taken = process::with_current_signals(SignalState::take_pending).flatten();
+        let (signal, action) = match taken {
+            Some(taken) => taken,
+            None => return,
+        };
+        match action {
+            Action::Ignore => {}
+            Action::Default if signal.default_action() == DefaultAction::Ignore => {}
+            Action::Default => task::exit(signal.exit_code()),
+            Action::Handler { handler, restorer } => {
+                if !push_handler_frame(frame, signal, handler, restorer) {
+                    // the program has no stack left to handle it
+                    task::exit(signal.exit_code());
+                }
+                return;
+            }
+        }
+    }
+}
+
+/// Saves `frame` on the user stack and changes it to call `handler`,
+/// returns false if the stack is not writable
+fn push_handler_frame(
+    frame: &mut SyscallFrame,
+    signal: Signal,
+    handler: VirtAddr,
+    restorer: VirtAddr,
+) -> bool {
+    let context_size = size_of::<SyscallFrame>() as u64;
+    let context = match frame.rsp.checked_sub(RED_ZONE + context_size) {
+        // aligned like the stack pointer before a call
+        Some(addr) => addr & !0xf,
+        None => return false,
+    };
+    let return_address = context - 8;
+    if !memory::is_user_accessible(VirtAddr::new(return_address), 8 + context_size, true) {
+        return false;
+    }
+    unsafe {
+        (context as *mut SyscallFrame).write_unaligned(*frame);
+        (return_address as *mut u64).write_unaligned(restorer.as_u64());
+    }
+    frame.rip = handler.as_u64();
+    frame.rsp = return_address;
+    frame.rdi = signal.number();
+    frame.rsi = context;
+    frame.rflags &= !RFlags::DIRECTION_FLAG.bits();
+    true
+}
+
+/// Returns from a signal handler to the registers saved at the user stack
+/// pointer in `frame`, which is where the restorer calls `sigreturn`
+///
+/// Only returns if the saved registers are invalid.
+pub fn sigreturn(frame: &SyscallFrame) -> SignalError {
+    let size = size_of::<SyscallFrame>() as u64;
+    let valid = VirtAddr::try_new(frame.rsp)
+        .map(|addr| memory::is_user_accessible(addr, size, false))
+        .unwrap_or(false);
+    if !valid {
+        return SignalError::InvalidHandler;
+    }
+    let context = unsafe { (frame.rsp as *const SyscallFrame).read_unaligned() };
+    // `iretq` faults in ring 0 for non-canonical addresses
+    if context.rip >= USER_SPACE_END || context.rsp >= USER_SPACE_END {
+        return SignalError::InvalidHandler;
+    }
+    // `sysretq` cannot restore `rcx` and `r11`, which a signal from an
+    // interrupt has to keep
+    x86_64::instructions::interrupts::disable();
+    unsafe { usermode::resume_user_mode(&context) }
+}
+
+/// Raises `signal` for a fault of the current process in user mode and
+/// makes the interrupted handler return into its signal handler
+///
+/// Returns false if the process has no handler for it.
+pub fn raise_fault(signal: Signal, stack_frame: &mut InterruptStackFrame) -> bool {
+    let handled = process::with_current_signals(|signals| {
+        let handled = matches!(signals.actions[signal.number() as usize], Action::Handler { .. });
+        if handled {
+            signals.raise(signal);
+        }
+        handled
+    })
+    .unwrap_or(false);
+    if handled {
+        enter_signal_entry(stack_frame);
+    }
+    handled
+}
+
+/// Makes an interrupt from user mode return through `signal_entry` if the
+/// current process has pending signals, called at the end of the timer
+/// interrupt handler
+pub fn check_on_interrupt(stack_frame: &mut InterruptStackFrame) {
+    if !usermode::from_user_mode(stack_frame) {
+        return;
+    }
+    let pending = process::with_current_signals(|signals| signals.is_pending()).unwrap_or(false);
+    if pending {
+        enter_signal_entry(stack_frame);
+    }
+}
+
+/// The user mode part of the interrupt stack frame of the signal that is
+/// being delivered through `signal_entry`: `rip, cs, rflags, rsp, ss`
+#[no_mangle]
+static mut SIGNAL_USER_FRAME: [u64; 5] = [0; 5];
+
+fn enter_signal_entry(stack_frame: &mut InterruptStackFrame) {
+    let (code_selector, data_selector) = gdt::kernel_selectors();
+    unsafe {
+        let mut frame = stack_frame.as_mut();
+        let user = frame.read();
+        SIGNAL_USER_FRAME = [
+            user.instruction_pointer.as_u64(),
+            user.code_segment,
+            user.cpu_flags,
+            user.stack_pointer.as_u64(),
+            user.stack_segment,
+        ];
+        frame.update(|frame| {
+            frame.instruction_pointer = VirtAddr::new(signal_entry as *const () as u64);
+            frame.code_segment = code_selector.0.into();
+            // interrupts stay disabled until the user frame is copied
+            frame.cpu_flags = RFlags::empty().bits();
+            // the interrupt handler is done with the kernel stack by then
+            frame.stack_pointer = syscall::kernel_stack();
+            frame.stack_segment = data_selector.0.into();
+        });
+    }
+}
+
+// Entered in ring 0 with `iretq` from an interrupt handler, see
+// `enter_signal_entry`. Builds the same frame as `int80_entry` on the kernel
+// stack, so the user registers can be changed for the signal handler.
+global_asm!(
+    ".global signal_entry",
+    "signal_entry:",
+    "push qword ptr [rip + SIGNAL_USER_FRAME + 32]",
+    "push qword ptr [rip + SIGNAL_USER_FRAME + 24]",
+    "push qword ptr [rip + SIGNAL_USER_FRAME + 16]",
+    "push qword ptr [rip + SIGNAL_USER_FRAME + 8]",
+    "push qword ptr [rip + SIGNAL_USER_FRAME]",
+    "push rax",
+    "push rbx",
+    "push rcx",
+    "push rdx",
+    "push rsi",
+    "push rdi",
+    "push rbp",
+    "push r8",
+    "push r9",
+    "push r10",
+    "push r11",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "mov rdi, rsp",
+    "call signal_entry_handler",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop r11",
+    "pop r10",
+    "pop r9",
+    "pop r8",
+    "pop rbp",
+    "pop rdi",
+    "pop rsi",
+    "pop rdx",
+    "pop rcx",
+    "pop rbx",
+    "pop rax",
+    "iretq",
+);
+
+extern "C" {
+    fn signal_entry();
+}
+
+#[no_mangle]
+extern "C" fn signal_entry_handler(frame: &mut SyscallFrame) {
+    deliver(frame);
+}
+
+#[test_case]
+fn test_pending_signals_in_order() {
+    let mut signals = SignalState::new();
+    signals.actions[Signal::User2.number() as usize] = Action::Ignore;
+    signals.raise(Signal::User2);
+    signals.raise(Signal::Interrupt);
+    assert!(signals.is_pending());
+    assert!(signals.interrupts_blocking());
+    assert_eq!(signals.take_pending(), Some((Signal::Interrupt, Action::Default)));
+    assert!(!signals.interrupts_blocking());
+    assert_eq!(signals.forked().take_pending(), None);
+    assert_eq!(signals.take_pending(), Some((Signal::User2, Action::Ignore)));
+    assert_eq!(signals.take_pending(), None);
+}
+
+#[test_case]
+fn test_kill_cannot_be_caught() {
+    assert_eq!(set_action(Signal::Kill, Action::Ignore), Err(SignalError::CannotCatch));
+    // the test runner is no process
+    assert_eq!(set_action(Signal::User1, Action::Ignore), Err(SignalError::NoProcess));
+    assert_eq!(Signal::from_number(9), Some(Signal::Kill));
+    assert_eq!(Signal::from_number(3), None);
+}
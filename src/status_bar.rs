@@ -0,0 +1,134 @@
+//! Status line on the top row of the VGA screen
+//!
+//! Once enabled with `init`, the top row of every console is kept out of the
+//! text area and shows the uptime, free memory, the number of tasks, the
+//! interrupt rates of the timer, keyboard and serial ports, and the console
+//! shown. The timer interrupt handler redraws it about once a second.
+
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::fixed_str::FixedStr;
+use crate::interrupts::{self, IRQ_LINES};
+use crate::sync::SpinLock;
+use crate::vga_buffer::{self, Color, ColorCode, BUFFER_WIDTH, CONSOLES};
+use crate::{memory, task};
+
+/// Screen row of the status line
+pub const ROW: usize = 0;
+/// Time between redraws in milliseconds
+pub const UPDATE_INTERVAL_MS: u64 = 1000;
+
+const COLOR: ColorCode = ColorCode::new(Color::Black, Color::LightGray);
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Interrupt counts at the last redraw, to compute rates from
+struct Sample {
+    ms: u64,
+    irq_counts: [u64; IRQ_LINES],
+}
+
+static LAST_SAMPLE: SpinLock<Sample> = SpinLock::new(Sample { ms: 0, irq_counts: [0; IRQ_LINES] });
+
+/// What the status line shows
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Status {
+    pub uptime_ms: u64,
+    pub free_frames: usize,
+    pub tasks: usize,
+    /// interrupts per second of the timer, the keyboard and both serial
+    /// port lines together
+    pub irq_rates: [u64; 3],
+    /// index of the console shown
+    pub console: usize,
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let seconds = self.uptime_ms / 1000;
+        write!(
+            f,
+            " up {}:{:02}:{:02} | {} KiB free | {} tasks | irq/s timer {} kbd {} com {} | tty{}",
+            seconds / 3600,
+            seconds / 60 % 60,
+            seconds % 60,
+            self.free_frames * 4,
+            self.tasks,
+            self.irq_rates[0],
+            self.irq_rates[1],
+            self.irq_rates[2],
+            self.console + 1
+        )
+    }
+}
+
+/// A row of text, cut off at the screen width
+type Line = FixedStr<BUFFER_WIDTH>;
+
+/// Reserves the status line on every console and starts updating it
+pub fn init() {
+    for index in 0..CONSOLES {
+        vga_buffer::console(index).lock().set_reserved_rows(ROW + 1);
+    }
+    ENABLED.store(true, Ordering::Relaxed);
+    update(interrupts::uptime_ms());
+}
+
+/// Redraws the status line if it is enabled and due, called by the timer
+/// interrupt handler
+pub(crate) fn timer_tick(ticks: u64) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    let ms = interrupts::ticks_to_ms(ticks);
+    if ms.saturating_sub(LAST_SAMPLE.lock().ms) >= UPDATE_INTERVAL_MS {
+        update(ms);
+    }
+}
+
+/// Redraws the status line of the console shown
+fn update(ms: u64) {
+    let mut irq_counts = [0; IRQ_LINES];
+    for (irq, count) in irq_counts.iter_mut().enumerate() {
+        *count = interrupts::irq_count(irq);
+    }
+    let previous = core::mem::replace(&mut *LAST_SAMPLE.lock(), Sample { ms, irq_counts });
+    let elapsed = ms.saturating_sub(previous.ms).max(1);
+    let rate = |irqs: &[usize]| -> u64 {
+        let count: u64 = irqs.iter().map(|&irq| irq_counts[irq] - previous.irq_counts[irq]).sum();
+        count * 1000 / elapsed
+    };
+    let status = Status {
+        uptime_ms: ms,
+        free_frames: memory::free_frames(),
+        tasks: task::task_count(),
+        irq_rates: [rate(&[0]), rate(&[1]), rate(&[3, 4])],
+        console: vga_buffer::shown_console(),
+    };
+    let mut line = Line::new();
+    write!(line, "{}", status).ok();
+    vga_buffer::with_shown_console(|writer| writer.write_reserved(ROW, line.as_str(), COLOR));
+}
+
+#[test_case]
+fn test_status_line() {
+    let status = Status {
+        uptime_ms: 3_723_500,
+        free_frames: 1000,
+        tasks: 3,
+        irq_rates: [18, 2, 0],
+        console: 1,
+    };
+    let mut line = Line::new();
+    write!(line, "{}", status).unwrap();
+    assert_eq!(
+        line.as_str(),
+        " up 1:02:03 | 4000 KiB free | 3 tasks | irq/s timer 18 kbd 2 com 0 | tty2"
+    );
+
+    // longer lines are cut off at the screen width
+    let mut line = Line::new();
+    write!(line, "{:>100}", "x").unwrap();
+    assert_eq!(line.as_str().len(), BUFFER_WIDTH);
+}
@@ -0,0 +1,669 @@
+//! Synchronization primitives
+//!
+//! `SpinLock` protects data that is also used by interrupt handlers: it keeps
+//! interrupts disabled while held, so a handler can never spin on a lock held
+//! by the code it interrupted. `Mutex`, `Semaphore`, `Condvar` and `RwLock`
+//! put a contended task to sleep on a `WaitQueue` instead of spinning; they
+//! may only be used from tasks, never from interrupt handlers.
+
+use core::cell::UnsafeCell;
+use core::mem::ManuallyDrop;
+use core::ops::{Deref, DerefMut};
+use core::panic::Location;
+use core::sync::atomic::{AtomicBool, AtomicIsize, AtomicUsize, Ordering};
+#[cfg(debug_assertions)]
+use core::sync::atomic::{AtomicPtr, AtomicU64};
+
+use x86_64::instructions::interrupts;
+
+use crate::task::{self, TaskId, MAX_TASKS};
+
+/// A spin lock that detects deadlocks in debug builds
+///
+/// In debug builds the lock remembers which task took it and where. Taking it
+/// again from the holding task (for example from an interrupt handler that
+/// interrupted the holder), or with interrupts disabled so that the holder
+/// can never run again, panics with both acquisition sites instead of
+/// spinning forever. In release builds it is a plain spin lock.
+pub struct DebugLock<T> {
+    inner: spin::Mutex<T>,
+    #[cfg(debug_assertions)]
+    holder_task: AtomicU64,
+    #[cfg(debug_assertions)]
+    holder_location: AtomicPtr<Location<'static>>,
+}
+
+pub struct DebugLockGuard<'a, T> {
+    lock: &'a DebugLock<T>,
+    guard: spin::MutexGuard<'a, T>,
+}
+
+#[cfg(debug_assertions)]
+const NO_HOLDER: u64 = u64::MAX;
+
+impl<T> DebugLock<T> {
+    pub const fn new(data: T) -> Self {
+        DebugLock {
+            inner: spin::Mutex::new(data),
+            #[cfg(debug_assertions)]
+            holder_task: AtomicU64::new(NO_HOLDER),
+            #[cfg(debug_assertions)]
+            holder_location: AtomicPtr::new(core::ptr::null_mut()),
+        }
+    }
+
+    #[track_caller]
+    pub fn lock(&self) -> DebugLockGuard<'_, T> {
+        let location = Location::caller();
+        #[cfg(debug_assertions)]
+        {
+            if let Some(guard) = self.inner.try_lock() {
+                return self.guard(guard, location);
+            }
+            self.check_deadlock(location);
+        }
+        let guard = self.inner.lock();
+        self.guard(guard, location)
+    }
+
+    #[track_caller]
+    pub fn try_lock(&self) -> Option<DebugLockGuard<'_, T>> {
+        let location = Location::caller();
+        self.inner.try_lock().map(|guard| self.guard(guard, location))
+    }
+
+    /// Releases the lock without a guard.
+    ///
+    /// # Safety
+    ///
+    /// The current holder may still be using the data. This is meant for
+    /// panic handlers that must print no matter what.
+    pub unsafe fn force_unlock(&self) {
+        self.set_holder(None);
+        self.inner.force_unlock();
+    }
+
+    fn guard<'a>(&'a self, guard: spin::MutexGuard<'a, T>, location: &'static Location<'static>)
+        -> DebugLockGuard<'a, T>
+    {
+        self.set_holder(Some(location));
+        DebugLockGuard { lock: self, guard }
+    }
+
+    #[cfg(debug_assertions)]
+    fn set_holder(&self, location: Option<&'static Location<'static>>) {
+        let (task, location) = match location {
+            Some(location) => (task::current_id().as_u64(), location as *const _ as *mut _),
+            None => (NO_HOLDER, core::ptr::null_mut()),
+        };
+        self.holder_task.store(task, Ordering::Relaxed);
+        self.holder_location.store(location, Ordering::Relaxed);
+    }
+
+    #[cfg(not(debug_assertions))]
+    fn set_holder(&self, _location: Option<&'static Location<'static>>) {}
+
+    /// Panics if waiting for the lock at `location` can never succeed
+    #[cfg(debug_assertions)]
+    fn check_deadlock(&self, location: &'static Location<'static>) {
+        let holder = self.holder_task.load(Ordering::Relaxed);
+        let same_task = holder == task::current_id().as_u64();
+        // on a single CPU nothing can make the holder release the lock
+        // while we spin with interrupts disabled
+        if !same_task && interrupts::are_enabled() {
+            return;
+        }
+
+        let held_at = self.holder_location.load(Ordering::Relaxed);
+        if held_at.is_null() {
+            // the holder was interrupted before it could record itself
+            panic!("deadlock: lock taken at {} is already held", location);
+        }
+        panic!(
+            "deadlock: lock taken at {} is already held by task {} since {}",
+            location,
+            holder,
+            unsafe { &*held_at }
+        );
+    }
+}
+
+impl<'a, T> Deref for DebugLockGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<'a, T> DerefMut for DebugLockGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<'a, T> Drop for DebugLockGuard<'a, T> {
+    fn drop(&mut self) {
+        // the inner guard is dropped after this, so the lock is still ours
+        self.lock.set_holder(None);
+    }
+}
+
+/// A spin lock that disables interrupts while it is held
+///
+/// Built on `DebugLock`, so misuse panics in debug builds.
+pub struct SpinLock<T> {
+    inner: DebugLock<T>,
+}
+
+pub struct SpinLockGuard<'a, T> {
+    guard: ManuallyDrop<DebugLockGuard<'a, T>>,
+    interrupts_were_enabled: bool,
+}
+
+impl<T> SpinLock<T> {
+    pub const fn new(data: T) -> Self {
+        SpinLock { inner: DebugLock::new(data) }
+    }
+
+    #[track_caller]
+    pub fn lock(&self) -> SpinLockGuard<'_, T> {
+        let interrupts_were_enabled = interrupts::are_enabled();
+        interrupts::disable();
+        SpinLockGuard {
+            guard: ManuallyDrop::new(self.inner.lock()),
+            interrupts_were_enabled,
+        }
+    }
+
+    #[track_caller]
+    pub fn try_lock(&self) -> Option<SpinLockGuard<'_, T>> {
+        let interrupts_were_enabled = interrupts::are_enabled();
+        interrupts::disable();
+        match self.inner.try_lock() {
+            Some(guard) => Some(SpinLockGuard {
+                guard: ManuallyDrop::new(guard),
+                interrupts_were_enabled,
+            }),
+            None => {
+                if interrupts_were_enabled {
+                    interrupts::enable();
+                }
+                None
+            }
+        }
+    }
+
+    /// Releases the lock without a guard.
+    ///
+    /// # Safety
+    ///
+    /// See `DebugLock::force_unlock`.
+    pub unsafe fn force_unlock(&self) {
+        self.inner.force_unlock();
+    }
+}
+
+impl<'a, T> Deref for SpinLockGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<'a, T> DerefMut for SpinLockGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<'a, T> Drop for SpinLockGuard<'a, T> {
+    fn drop(&mut self) {
+        // release the lock before an interrupt can come in
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+        if self.interrupts_were_enabled {
+            interrupts::enable();
+        }
+    }
+}
+
+/// FIFO list of blocked tasks
+struct Waiters {
+    ids: [Option<TaskId>; MAX_TASKS],
+    len: usize,
+}
+
+impl Waiters {
+    fn push(&mut self, id: TaskId) {
+        // a task can only wait on one queue at a time, so this never overflows
+        self.ids[self.len] = Some(id);
+        self.len += 1;
+    }
+
+    fn remove(&mut self, id: TaskId) {
+        if let Some(i) = self.ids[..self.len].iter().position(|&waiter| waiter == Some(id)) {
+            self.ids.copy_within(i + 1..self.len, i);
+            self.len -= 1;
+            self.ids[self.len] = None;
+        }
+    }
+
+    fn pop(&mut self) -> Option<TaskId> {
+        if self.len == 0 {
+            return None;
+        }
+        let id = self.ids[0].take();
+        self.ids.copy_within(1..self.len, 0);
+        self.len -= 1;
+        self.ids[self.len] = None;
+        id
+    }
+}
+
+/// A queue of tasks waiting for some condition
+pub struct WaitQueue {
+    waiters: SpinLock<Waiters>,
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        WaitQueue {
+            waiters: SpinLock::new(Waiters { ids: [None; MAX_TASKS], len: 0 }),
+        }
+    }
+
+    /// Blocks the current task until `condition` returns true.
+    ///
+    /// The condition is checked with interrupts disabled, so a wake-up cannot
+    /// get lost between checking it and going to sleep.
+    pub fn wait_until<F: FnMut() -> bool>(&self, mut condition: F) {
+        interrupts::without_interrupts(|| {
+            while !condition() {
+                self.sleep();
+            }
+        });
+    }
+
+    /// Adds the current task to the queue and blocks it until woken.
+    ///
+    /// Must be called with interrupts disabled.
+    fn sleep(&self) {
+        self.waiters.lock().push(task::current_id());
+        task::block();
+        // still queued if something else woke it, like a signal
+        self.waiters.lock().remove(task::current_id());
+    }
+
+    /// Wakes the task that has been waiting the longest, returns whether there was one
+    pub fn wake_one(&self) -> bool {
+        match self.waiters.lock().pop() {
+            Some(id) => {
+                task::unblock(id);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Wakes all waiting tasks and returns how many there were
+    pub fn wake_all(&self) -> usize {
+        let mut woken = 0;
+        while self.wake_one() {
+            woken += 1;
+        }
+        woken
+    }
+}
+
+impl Default for WaitQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A mutual exclusion lock that blocks instead of spinning
+pub struct Mutex<T> {
+    locked: AtomicBool,
+    waiters: WaitQueue,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Send for Mutex<T> {}
+unsafe impl<T: Send> Sync for Mutex<T> {}
+
+pub struct MutexGuard<'a, T> {
+    mutex: &'a Mutex<T>,
+}
+
+impl<T> Mutex<T> {
+    pub const fn new(data: T) -> Self {
+        Mutex {
+            locked: AtomicBool::new(false),
+            waiters: WaitQueue::new(),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        self.waiters.wait_until(|| self.acquire());
+        MutexGuard { mutex: self }
+    }
+
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        if self.acquire() {
+            Some(MutexGuard { mutex: self })
+        } else {
+            None
+        }
+    }
+
+    fn acquire(&self) -> bool {
+        !self.locked.swap(true, Ordering::Acquire)
+    }
+}
+
+impl<'a, T> Deref for MutexGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<'a, T> DerefMut for MutexGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+impl<'a, T> Drop for MutexGuard<'a, T> {
+    fn drop(&mut self) {
+        self.mutex.locked.store(false, Ordering::Release);
+        self.mutex.waiters.wake_one();
+    }
+}
+
+/// A counting semaphore
+pub struct Semaphore {
+    count: AtomicUsize,
+    waiters: WaitQueue,
+}
+
+impl Semaphore {
+    pub const fn new(count: usize) -> Self {
+        Semaphore {
+            count: AtomicUsize::new(count),
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    /// Takes one unit, blocking while the count is zero
+    pub fn acquire(&self) {
+        self.waiters.wait_until(|| self.try_acquire());
+    }
+
+    pub fn try_acquire(&self) -> bool {
+        self.count
+            .fetch_update(Ordering::Acquire, Ordering::Relaxed, |count| count.checked_sub(1))
+            .is_ok()
+    }
+
+    /// Returns one unit and wakes a waiter.
+    ///
+    /// This never blocks, so interrupt handlers may use it to signal tasks.
+    pub fn release(&self) {
+        self.count.fetch_add(1, Ordering::Release);
+        self.waiters.wake_one();
+    }
+
+    pub fn count(&self) -> usize {
+        self.count.load(Ordering::Relaxed)
+    }
+}
+
+/// A condition variable used together with `Mutex`
+pub struct Condvar {
+    waiters: WaitQueue,
+}
+
+impl Condvar {
+    pub const fn new() -> Self {
+        Condvar { waiters: WaitQueue::new() }
+    }
+
+    /// Unlocks the mutex, waits for a notification and locks the mutex again
+    pub fn wait<'a, T>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        let mutex = guard.mutex;
+        interrupts::without_interrupts(|| {
+            // with interrupts disabled no notification can come in between
+            // unlocking the mutex and blocking
+            drop(guard);
+            self.waiters.sleep();
+        });
+        mutex.lock()
+    }
+
+    /// Waits until `condition` returns false for the protected data
+    pub fn wait_while<'a, T, F>(&self, mut guard: MutexGuard<'a, T>, mut condition: F) -> MutexGuard<'a, T>
+    where
+        F: FnMut(&mut T) -> bool,
+    {
+        while condition(&mut *guard) {
+            guard = self.wait(guard);
+        }
+        guard
+    }
+
+    pub fn notify_one(&self) {
+        self.waiters.wake_one();
+    }
+
+    pub fn notify_all(&self) {
+        self.waiters.wake_all();
+    }
+}
+
+impl Default for Condvar {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A reader-writer lock that blocks instead of spinning
+pub struct RwLock<T> {
+    /// number of readers, or -1 while a writer holds the lock
+    state: AtomicIsize,
+    waiters: WaitQueue,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Send for RwLock<T> {}
+unsafe impl<T: Send + Sync> Sync for RwLock<T> {}
+
+pub struct RwLockReadGuard<'a, T> {
+    lock: &'a RwLock<T>,
+}
+
+pub struct RwLockWriteGuard<'a, T> {
+    lock: &'a RwLock<T>,
+}
+
+impl<T> RwLock<T> {
+    pub const fn new(data: T) -> Self {
+        RwLock {
+            state: AtomicIsize::new(0),
+            waiters: WaitQueue::new(),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    pub fn read(&self) -> RwLockReadGuard<'_, T> {
+        self.waiters.wait_until(|| self.acquire_read());
+        RwLockReadGuard { lock: self }
+    }
+
+    pub fn write(&self) -> RwLockWriteGuard<'_, T> {
+        self.waiters.wait_until(|| self.acquire_write());
+        RwLockWriteGuard { lock: self }
+    }
+
+    fn acquire_read(&self) -> bool {
+        self.state
+            .fetch_update(Ordering::Acquire, Ordering::Relaxed, |readers| {
+                if readers >= 0 {
+                    Some(readers + 1)
+                } else {
+                    None
+                }
+            })
+            .is_ok()
+    }
+
+    fn acquire_write(&self) -> bool {
+        self.state
+            .compare_exchange(0, -1, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+    }
+}
+
+impl<'a, T> Deref for RwLockReadGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<'a, T> Drop for RwLockReadGuard<'a, T> {
+    fn drop(&mut self) {
+        if self.lock.state.fetch_sub(1, Ordering::Release) == 1 {
+            self.lock.waiters.wake_all();
+        }
+    }
+}
+
+impl<'a, T> Deref for RwLockWriteGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<'a, T> DerefMut for RwLockWriteGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<'a, T> Drop for RwLockWriteGuard<'a, T> {
+    fn drop(&mut self) {
+        self.lock.state.store(0, Ordering::Release);
+        self.lock.waiters.wake_all();
+    }
+}
+
+#[test_case]
+fn test_mutex_between_tasks() {
+    static COUNTER: Mutex<u64> = Mutex::new(0);
+
+    fn worker(_: usize) {
+        for _ in 0..100 {
+            let mut counter = COUNTER.lock();
+            let value = *counter;
+            // give the other worker a chance to run while the lock is held
+            task::yield_now();
+            *counter = value + 1;
+        }
+    }
+
+    let a = task::spawn(worker, 0).expect("spawn failed");
+    let b = task::spawn(worker, 0).expect("spawn failed");
+    task::join(a);
+    task::join(b);
+    assert_eq!(*COUNTER.lock(), 200);
+}
+
+#[test_case]
+fn test_semaphore_and_condvar() {
+    static ITEMS: Semaphore = Semaphore::new(0);
+    static DONE: Mutex<bool> = Mutex::new(false);
+    static DONE_CHANGED: Condvar = Condvar::new();
+
+    fn producer(count: usize) {
+        for _ in 0..count {
+            ITEMS.release();
+            task::yield_now();
+        }
+        *DONE.lock() = true;
+        DONE_CHANGED.notify_all();
+    }
+
+    let id = task::spawn(producer, 5).expect("spawn failed");
+    for _ in 0..5 {
+        ITEMS.acquire();
+    }
+    let done = DONE_CHANGED.wait_while(DONE.lock(), |done| !*done);
+    assert!(*done);
+    drop(done);
+    assert_eq!(ITEMS.count(), 0);
+    task::join(id);
+}
+
+#[test_case]
+fn test_rwlock_readers_and_writers() {
+    static LOCK: RwLock<u64> = RwLock::new(0);
+    // number of reads done by `reader` tasks and the sum of the values seen
+    static READS: AtomicUsize = AtomicUsize::new(0);
+    static SEEN: AtomicUsize = AtomicUsize::new(0);
+    static WRITTEN: AtomicBool = AtomicBool::new(false);
+
+    fn reader(_: usize) {
+        let value = *LOCK.read();
+        SEEN.fetch_add(value as usize, Ordering::SeqCst);
+        READS.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn writer(_: usize) {
+        *LOCK.write() += 1;
+        WRITTEN.store(true, Ordering::SeqCst);
+    }
+
+    fn yield_until(condition: impl Fn() -> bool) {
+        for _ in 0..100 {
+            if condition() {
+                return;
+            }
+            task::yield_now();
+        }
+    }
+
+    // a reader gets in while another reader holds the lock
+    let first = LOCK.read();
+    let id = task::spawn(reader, 0).expect("spawn failed");
+    yield_until(|| READS.load(Ordering::SeqCst) == 1);
+    assert_eq!(READS.load(Ordering::SeqCst), 1);
+    task::join(id);
+
+    // a writer waits until the last reader is gone
+    let id = task::spawn(writer, 0).expect("spawn failed");
+    yield_until(|| WRITTEN.load(Ordering::SeqCst));
+    assert!(!WRITTEN.load(Ordering::SeqCst));
+    drop(first);
+    task::join(id);
+    assert!(WRITTEN.load(Ordering::SeqCst));
+
+    // readers waiting for a writer all get in once it is done
+    let mut guard = LOCK.write();
+    let a = task::spawn(reader, 0).expect("spawn failed");
+    let b = task::spawn(reader, 0).expect("spawn failed");
+    yield_until(|| READS.load(Ordering::SeqCst) > 1);
+    assert_eq!(READS.load(Ordering::SeqCst), 1);
+    *guard += 1;
+    drop(guard);
+    task::join(a);
+    task::join(b);
+    assert_eq!(READS.load(Ordering::SeqCst), 3);
+    assert_eq!(SEEN.load(Ordering::SeqCst), 4);
+}
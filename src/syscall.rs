@@ -0,0 +1,618 @@
+//! System calls from user mode
+//!
+//! User code puts the system call number in `rax` and the arguments in
+//! `rdi`, `rsi`, `rdx`, `r10`, `r8` and `r9`, then executes `syscall` (or
+//! `int 0x80`). The result comes back in `rax`: a negative value is a
+//! `SyscallError`. `syscall` clobbers `rcx` and `r11`.
+
+use core::arch::global_asm;
+
+use log::error;
+use x86_64::instructions::interrupts;
+use x86_64::registers::model_specific::{Efer, EferFlags, LStar, SFMask, Star};
+use x86_64::registers::rflags::RFlags;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+
+use crate::channel::{self, ChannelError};
+use crate::process::{Pid, ProcessError, WaitError};
+use crate::shm::{self, ShmError};
+use crate::signal::{self, Action, Signal, SignalError};
+use crate::{gdt, memory, print, process, task, usermode};
+
+/// Interrupt vector of the system call gate
+pub const SYSCALL_INTERRUPT: u8 = 0x80;
+
+/// Writes a UTF-8 string to the console: `write(ptr, len) -> len`
+pub const SYS_WRITE: u64 = 0;
+/// Ends the calling task: `exit(code)`
+pub const SYS_EXIT: u64 = 1;
+/// Blocks the calling task: `sleep(milliseconds) -> 0`. Like `wait`, `send` and
+/// `receive`, it ends early with `Interrupted` when a signal arrives.
+pub const SYS_SLEEP: u64 = 2;
+/// Returns the milliseconds since boot: `time() -> ms`
+pub const SYS_TIME: u64 = 3;
+/// Lets other tasks run: `yield() -> 0`
+pub const SYS_YIELD: u64 = 4;
+/// Returns the PID of the calling process: `getpid() -> pid`
+pub const SYS_GETPID: u64 = 5;
+/// Returns the PID of the parent process, 0 if it was started by the kernel:
+/// `getppid() -> pid`
+pub const SYS_GETPPID: u64 = 6;
+/// Starts a copy of the calling process: `fork() -> pid`, which returns the
+/// PID of the child in the parent and 0 in the child
+pub const SYS_FORK: u64 = 7;
+/// Waits for a child process to exit: `wait(pid, status_ptr) -> pid`. A `pid`
+/// of 0 waits for any child. The exit code is stored at `status_ptr` as an
+/// `i64` unless it is 0.
+pub const SYS_WAIT: u64 = 8;
+/// Creates a channel: `channel(handles_ptr) -> 0`, stores the sending and
+/// the receiving handle at `handles_ptr` as two `u64`s
+pub const SYS_CHANNEL: u64 = 9;
+/// Sends a message through a channel: `send(handle, ptr, len, flags) -> 0`
+pub const SYS_SEND: u64 = 10;
+/// Receives a message from a channel: `receive(handle, ptr, len, flags) -> len`
+pub const SYS_RECEIVE: u64 = 11;
+/// Closes a channel handle: `close(handle) -> 0`
+pub const SYS_CLOSE: u64 = 12;
+/// Creates a named shared memory object filled with zeros:
+/// `shm_create(name_ptr, name_len, size) -> 0`
+pub const SYS_SHM_CREATE: u64 = 13;
+/// Maps a shared memory object at a page aligned address:
+/// `shm_map(name_ptr, name_len, addr, prot) -> size`
+pub const SYS_SHM_MAP: u64 = 14;
+/// Removes the name of a shared memory object: `shm_unlink(name_ptr, name_len) -> 0`
+pub const SYS_SHM_UNLINK: u64 = 15;
+/// Unmaps the pages in `[addr, addr + size)`: `unmap(addr, size) -> 0`
+pub const SYS_UNMAP: u64 = 16;
+/// Sends a signal to a process: `kill(pid, signal) -> 0`
+pub const SYS_KILL: u64 = 17;
+/// Sets the action for a signal: `sigaction(signal, handler, restorer) -> 0`.
+/// `handler` is `SIG_DFL`, `SIG_IGN` or the address of a function that
+/// returns to `restorer`, see `signal`.
+pub const SYS_SIGACTION: u64 = 18;
+/// Returns from a signal handler, called by the restorer: `sigreturn()`
+pub const SYS_SIGRETURN: u64 = 19;
+
+/// `handler` values of `sigaction` for the default action and for ignoring
+/// the signal
+pub const SIG_DFL: u64 = 0;
+pub const SIG_IGN: u64 = 1;
+
+/// Flag for `send` and `receive`: fail with `TryAgain` instead of blocking
+pub const MSG_NONBLOCK: u64 = 1;
+/// Flags for `shm_map`: the pages are writable and executable. Without
+/// them they are read-only and, if the CPU supports it, not executable.
+pub const PROT_WRITE: u64 = 1;
+pub const PROT_EXEC: u64 = 2;
+
+/// Maximum number of bytes accepted by `write` in one call
+pub const MAX_WRITE_LEN: u64 = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(i64)]
+pub enum SyscallError {
+    /// no shared memory object with that name
+    NotFound = -2,
+    /// no such process
+    NoSuchProcess = -3,
+    /// a signal arrived while the call was blocked
+    Interrupted = -4,
+    /// the handle is not open
+    BadHandle = -9,
+    /// `wait` found no such child
+    NoChildren = -10,
+    /// the process table is full
+    TryAgain = -11,
+    OutOfMemory = -12,
+    /// a pointer argument is not accessible from user mode
+    BadAddress = -14,
+    /// the name or the address is in use already
+    AlreadyExists = -17,
+    InvalidArgument = -22,
+    /// the task has too many handles open
+    TooManyHandles = -24,
+    /// the other end of the channel is closed
+    Disconnected = -32,
+    /// unknown system call number
+    NoSuchCall = -38,
+    /// the message does not fit in `channel::MAX_MESSAGE_SIZE` or the buffer
+    MessageTooLarge = -90,
+}
+
+type SyscallResult = Result<u64, SyscallError>;
+
+impl From<ShmError> for SyscallError {
+    fn from(error: ShmError) -> Self {
+        match error {
+            ShmError::InvalidName | ShmError::InvalidSize | ShmError::InvalidAddress => {
+                SyscallError::InvalidArgument
+            }
+            ShmError::AlreadyExists | ShmError::AddressInUse => SyscallError::AlreadyExists,
+            ShmError::TooManyObjects | ShmError::OutOfMemory => SyscallError::OutOfMemory,
+            ShmError::NotFound => SyscallError::NotFound,
+        }
+    }
+}
+
+impl From<SignalError> for SyscallError {
+    fn from(error: SignalError) -> Self {
+        match error {
+            SignalError::NoSuchProcess => SyscallError::NoSuchProcess,
+            SignalError::CannotCatch | SignalError::InvalidHandler | SignalError::NoProcess => {
+                SyscallError::InvalidArgument
+            }
+        }
+    }
+}
+
+impl From<ChannelError> for SyscallError {
+    fn from(error: ChannelError) -> Self {
+        match error {
+            ChannelError::TooManyChannels | ChannelError::WouldBlock => SyscallError::TryAgain,
+            ChannelError::MessageTooLarge => SyscallError::MessageTooLarge,
+            ChannelError::Disconnected => SyscallError::Disconnected,
+            ChannelError::BadHandle => SyscallError::BadHandle,
+            ChannelError::TooManyHandles => SyscallError::TooManyHandles,
+            ChannelError::Interrupted => SyscallError::Interrupted,
+        }
+    }
+}
+
+/// User registers saved by the entry code, in the reverse order they are pushed
+///
+/// The last five fields have the layout of an interrupt stack frame, so
+/// both entry paths leave the same frame and `usermode::resume_user_mode`
+/// can return to user mode with `iretq` from a copy of it.
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+pub struct SyscallFrame {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+/// Top of the kernel stack of the running task, loaded by `syscall_entry`
+#[no_mangle]
+static mut SYSCALL_KERNEL_RSP: u64 = 0;
+/// Scratch space for the user stack pointer while switching stacks
+#[no_mangle]
+static mut SYSCALL_USER_RSP: u64 = 0;
+/// User code and stack selectors, pushed by `syscall_entry` like the CPU
+/// pushes them on an interrupt
+#[no_mangle]
+static mut SYSCALL_USER_CS: u64 = 0;
+#[no_mangle]
+static mut SYSCALL_USER_SS: u64 = 0;
+
+// Entry point of the `int 0x80` gate. The CPU has already switched to the
+// task's kernel stack (`TSS.privilege_stack_table[0]`) and pushed five words,
+// so pushing the fifteen general purpose registers keeps the stack 16-byte
+// aligned for the call.
+global_asm!(
+    ".global int80_entry",
+    "int80_entry:",
+    "push rax",
+    "push rbx",
+    "push rcx",
+    "push rdx",
+    "push rsi",
+    "push rdi",
+    "push rbp",
+    "push r8",
+    "push r9",
+    "push r10",
+    "push r11",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "mov rdi, rsp",
+    "call syscall_handler",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop r11",
+    "pop r10",
+    "pop r9",
+    "pop r8",
+    "pop rbp",
+    "pop rdi",
+    "pop rsi",
+    "pop rdx",
+    "pop rcx",
+    "pop rbx",
+    "pop rax",
+    "iretq",
+);
+
+// Entry point of the `syscall` instruction, set in the LSTAR MSR. `syscall`
+// does not switch stacks, so this does it by hand; interrupts stay disabled
+// (see FMASK) until the user stack pointer is saved on the kernel stack.
+// `rcx` holds the user `rip` and `r11` the user `rflags`; they are saved as
+// an interrupt stack frame and loaded from there again for `sysretq`, which
+// `syscall_handler` only lets through for a `rip` in user space.
+global_asm!(
+    ".global syscall_entry",
+    "syscall_entry:",
+    "mov [rip + SYSCALL_USER_RSP], rsp",
+    "mov rsp, [rip + SYSCALL_KERNEL_RSP]",
+    "push qword ptr [rip + SYSCALL_USER_SS]",
+    "push qword ptr [rip + SYSCALL_USER_RSP]",
+    "push r11",
+    "push qword ptr [rip + SYSCALL_USER_CS]",
+    "push rcx",
+    "push rax",
+    "push rbx",
+    "push rcx",
+    "push rdx",
+    "push rsi",
+    "push rdi",
+    "push rbp",
+    "push r8",
+    "push r9",
+    "push r10",
+    "push r11",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "mov rdi, rsp",
+    "call syscall_handler",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop r11",
+    "pop r10",
+    "pop r9",
+    "pop r8",
+    "pop rbp",
+    "pop rdi",
+    "pop rsi",
+    "pop rdx",
+    "pop rcx",
+    "pop rbx",
+    "pop rax",
+    "pop rcx",
+    "add rsp, 8",
+    "pop r11",
+    "pop rsp",
+    "sysretq",
+);
+
+extern "C" {
+    fn int80_entry();
+    fn syscall_entry();
+}
+
+/// Returns the address of the `int 0x80` entry code for the IDT
+pub fn int80_entry_addr() -> u64 {
+    int80_entry as *const () as u64
+}
+
+/// Enables the `syscall` instruction. Must be called after `gdt::init`.
+pub fn init() {
+    let (kernel_code, kernel_data) = gdt::kernel_selectors();
+    let (user_code, user_data) = gdt::user_selectors();
+    unsafe {
+        SYSCALL_USER_CS = user_code.0.into();
+        SYSCALL_USER_SS = user_data.0.into();
+    }
+    Star::write(user_code, user_data, kernel_code, kernel_data)
+        .expect("GDT layout does not fit syscall/sysret");
+    LStar::write(VirtAddr::new(syscall_entry as *const () as u64));
+    // the entry code must start with interrupts disabled
+    SFMask::write(RFlags::INTERRUPT_FLAG | RFlags::DIRECTION_FLAG | RFlags::TRAP_FLAG);
+    unsafe { Efer::update(|flags| *flags |= EferFlags::SYSTEM_CALL_EXTENSIONS) };
+}
+
+/// Sets the stack `syscall_entry` switches to, see `gdt::set_kernel_stack`
+pub fn set_kernel_stack(stack_top: VirtAddr) {
+    unsafe { SYSCALL_KERNEL_RSP = stack_top.as_u64() };
+}
+
+/// Returns the top of the kernel stack of the running task
+pub fn kernel_stack() -> VirtAddr {
+    VirtAddr::new(unsafe { SYSCALL_KERNEL_RSP })
+}
+
+#[no_mangle]
+extern "C" fn syscall_handler(frame: &mut SyscallFrame) {
+    // the gate disabled interrupts, but a system call may take a while
+    interrupts::enable();
+    let args = [frame.rdi, frame.rsi, frame.rdx, frame.r10, frame.r8, frame.r9];
+    frame.rax = match frame.rax {
+        // these need the registers of the caller
+        SYS_FORK => into_return_value(sys_fork(frame)),
+        SYS_SIGRETURN => SyscallError::from(signal::sigreturn(frame)) as i64,
+        number => dispatch(number, args),
+    } as u64;
+    signal::deliver(frame);
+    if frame.rip >= memory::USER_SPACE_END {
+        return_outside_user_space(frame);
+    }
+    interrupts::disable();
+}
+
+/// Returns to a `rip` outside of user space, which a `syscall` at the very
+/// end of user space leaves behind
+///
+/// `sysretq` would fault in ring 0 with the user stack pointer already
+/// loaded, so this returns with `iretq`, which faults in ring 3 for a kernel
+/// address. For a non-canonical address it would fault in ring 0 as well,
+/// so the task ends like for any other fault.
+fn return_outside_user_space(frame: &SyscallFrame) -> ! {
+    if VirtAddr::try_new(frame.rip).is_err() {
+        error!("USER FAULT: non-canonical return address {:#x}", frame.rip);
+        task::exit(usermode::USER_FAULT_EXIT_CODE);
+    }
+    interrupts::disable();
+    unsafe { usermode::resume_user_mode(frame) }
+}
+
+/// Runs system call `number` and returns its result
+///
+/// `fork` and `sigreturn` are handled by the entry code, as they need all
+/// registers of the caller.
+pub fn dispatch(number: u64, args: [u64; 6]) -> i64 {
+    let result = match number {
+        SYS_WRITE => sys_write(args[0], args[1]),
+        SYS_EXIT => task::exit(args[0] as i64),
+        SYS_SLEEP => sys_sleep(args[0]),
+        SYS_TIME => Ok(crate::interrupts::uptime_ms()),
+        SYS_YIELD => {
+            task::yield_now();
+            Ok(0)
+        }
+        SYS_GETPID => Ok(process::current_pid().map_or(0, |pid| pid.as_u64())),
+        SYS_GETPPID => Ok(process::current_pid()
+            .and_then(process::parent_of)
+            .map_or(0, |pid| pid.as_u64())),
+        SYS_WAIT => sys_wait(args[0], args[1]),
+        SYS_CHANNEL => sys_channel(args[0]),
+        SYS_SEND => sys_send(args[0], args[1], args[2], args[3]),
+        SYS_RECEIVE => sys_receive(args[0], args[1], args[2], args[3]),
+        SYS_CLOSE => channel::close_handle(args[0]).map(|_| 0).map_err(SyscallError::from),
+        SYS_SHM_CREATE => sys_shm_create(args[0], args[1], args[2]),
+        SYS_SHM_MAP => sys_shm_map(args[0], args[1], args[2], args[3]),
+        SYS_SHM_UNLINK => sys_shm_unlink(args[0], args[1]),
+        SYS_UNMAP => sys_unmap(args[0], args[1]),
+        SYS_KILL => sys_kill(args[0], args[1]),
+        SYS_SIGACTION => sys_sigaction(args[0], args[1], args[2]),
+        _ => Err(SyscallError::NoSuchCall),
+    };
+    into_return_value(result)
+}
+
+fn into_return_value(result: SyscallResult) -> i64 {
+    match result {
+        Ok(value) => value as i64,
+        Err(error) => error as i64,
+    }
+}
+
+/// Returns the user memory at `[ptr, ptr + len)` after checking that user
+/// mode may access it
+fn user_slice(ptr: u64, len: u64, write: bool) -> Result<&'static [u8], SyscallError> {
+    if !memory::is_user_accessible(VirtAddr::try_new(ptr).map_err(|_| SyscallError::BadAddress)?, len, write) {
+        return Err(SyscallError::BadAddress);
+    }
+    Ok(unsafe { core::slice::from_raw_parts(ptr as *const u8, len as usize) })
+}
+
+/// Returns the UTF-8 string at `[ptr, ptr + len)` in user memory
+fn user_str(ptr: u64, len: u64) -> Result<&'static str, SyscallError> {
+    core::str::from_utf8(user_slice(ptr, len, false)?).map_err(|_| SyscallError::InvalidArgument)
+}
+
+fn sys_write(ptr: u64, len: u64) -> SyscallResult {
+    if len > MAX_WRITE_LEN {
+        return Err(SyscallError::InvalidArgument);
+    }
+    print!("{}", user_str(ptr, len)?);
+    Ok(len)
+}
+
+fn sys_sleep(ms: u64) -> SyscallResult {
+    task::sleep(crate::interrupts::ms_to_ticks(ms));
+    // `signal::send` ends the sleep early
+    if signal::interrupted() {
+        return Err(SyscallError::Interrupted);
+    }
+    Ok(0)
+}
+
+fn sys_fork(frame: &SyscallFrame) -> SyscallResult {
+    let mut child = *frame;
+    child.rax = 0;
+    match process::fork(&child) {
+        Ok(pid) => Ok(pid.as_u64()),
+        Err(ProcessError::OutOfMemory) => Err(SyscallError::OutOfMemory),
+        Err(ProcessError::NoAddressSpace) => Err(SyscallError::InvalidArgument),
+        Err(_) => Err(SyscallError::TryAgain),
+    }
+}
+
+fn sys_wait(pid: u64, status_ptr: u64) -> SyscallResult {
+    // checked before waiting, so the exit code is not lost after reaping
+    if status_ptr != 0 {
+        user_slice(status_ptr, 8, true)?;
+    }
+    let (pid, code) = if pid == 0 {
+        process::wait_any()
+    } else {
+        process::wait(Pid::from_u64(pid)).map(|code| (Pid::from_u64(pid), code))
+    }
+    .map_err(|error| match error {
+        WaitError::Interrupted => SyscallError::Interrupted,
+        _ => SyscallError::NoChildren,
+    })?;
+    if status_ptr != 0 {
+        // a copy-on-write page is copied by the page fault handler
+        unsafe { (status_ptr as *mut i64).write_unaligned(code) };
+    }
+    Ok(pid.as_u64())
+}
+
+fn sys_shm_create(name_ptr: u64, name_len: u64, size: u64) -> SyscallResult {
+    shm::create(user_str(name_ptr, name_len)?, size)?;
+    Ok(0)
+}
+
+fn sys_shm_map(name_ptr: u64, name_len: u64, addr: u64, prot: u64) -> SyscallResult {
+    if prot & !(PROT_WRITE | PROT_EXEC) != 0 {
+        return Err(SyscallError::InvalidArgument);
+    }
+    let name = user_str(name_ptr, name_len)?;
+    let start = VirtAddr::try_new(addr).map_err(|_| SyscallError::InvalidArgument)?;
+    let mut flags = PageTableFlags::empty();
+    if prot & PROT_WRITE != 0 {
+        flags |= PageTableFlags::WRITABLE;
+    }
+    if prot & PROT_EXEC == 0 {
+        flags |= memory::no_execute();
+    }
+    task::with_current_address_space(|space| match space {
+        Some(space) => Ok(shm::map(space, name, start, flags)?),
+        None => Err(SyscallError::InvalidArgument),
+    })
+}
+
+fn sys_shm_unlink(name_ptr: u64, name_len: u64) -> SyscallResult {
+    shm::unlink(user_str(name_ptr, name_len)?)?;
+    Ok(0)
+}
+
+fn sys_unmap(addr: u64, size: u64) -> SyscallResult {
+    let start = VirtAddr::try_new(addr).map_err(|_| SyscallError::InvalidArgument)?;
+    if !start.is_aligned(4096u64)
+        || addr < memory::USER_SPACE_START
+        || memory::USER_SPACE_END - addr < size
+    {
+        return Err(SyscallError::InvalidArgument);
+    }
+    task::with_current_address_space(|space| match space {
+        Some(space) => {
+            space.unmap_region(start, size);
+            Ok(0)
+        }
+        None => Err(SyscallError::InvalidArgument),
+    })
+}
+
+fn sys_kill(pid: u64, signal: u64) -> SyscallResult {
+    let signal = Signal::from_number(signal).ok_or(SyscallError::InvalidArgument)?;
+    signal::send(Pid::from_u64(pid), signal)?;
+    Ok(0)
+}
+
+fn sys_sigaction(signal: u64, handler: u64, restorer: u64) -> SyscallResult {
+    let signal = Signal::from_number(signal).ok_or(SyscallError::InvalidArgument)?;
+    let action = match handler {
+        SIG_DFL => Action::Default,
+        SIG_IGN => Action::Ignore,
+        _ => Action::Handler {
+            handler: VirtAddr::try_new(handler).map_err(|_| SyscallError::InvalidArgument)?,
+            restorer: VirtAddr::try_new(restorer).map_err(|_| SyscallError::InvalidArgument)?,
+        },
+    };
+    signal::set_action(signal, action)?;
+    Ok(0)
+}
+
+fn sys_channel(handles_ptr: u64) -> SyscallResult {
+    user_slice(handles_ptr, 16, true)?;
+    let (sender, receiver) = channel::create_handles()?;
+    let handles = handles_ptr as *mut u64;
+    unsafe {
+        handles.write_unaligned(sender);
+        handles.add(1).write_unaligned(receiver);
+    }
+    Ok(0)
+}
+
+/// Returns whether a blocking `send` or `receive` was asked for
+fn blocking(flags: u64) -> Result<bool, SyscallError> {
+    if flags & !MSG_NONBLOCK != 0 {
+        return Err(SyscallError::InvalidArgument);
+    }
+    Ok(flags & MSG_NONBLOCK == 0)
+}
+
+fn sys_send(handle: u64, ptr: u64, len: u64, flags: u64) -> SyscallResult {
+    let block = blocking(flags)?;
+    if len > channel::MAX_MESSAGE_SIZE as u64 {
+        return Err(SyscallError::MessageTooLarge);
+    }
+    channel::send_by_handle(handle, user_slice(ptr, len, false)?, block)?;
+    Ok(0)
+}
+
+fn sys_receive(handle: u64, ptr: u64, len: u64, flags: u64) -> SyscallResult {
+    let block = blocking(flags)?;
+    let len = len.min(channel::MAX_MESSAGE_SIZE as u64);
+    user_slice(ptr, len, true)?;
+    // copied out after the channel lock is released
+    let mut buf = [0; channel::MAX_MESSAGE_SIZE];
+    let received = channel::receive_by_handle(handle, &mut buf[..len as usize], block)?;
+    unsafe { core::ptr::copy_nonoverlapping(buf.as_ptr(), ptr as *mut u8, received) };
+    Ok(received as u64)
+}
+
+#[test_case]
+fn test_unknown_system_call() {
+    assert_eq!(dispatch(0xdead, [0; 6]), SyscallError::NoSuchCall as i64);
+}
+
+#[test_case]
+fn test_write_rejects_kernel_memory() {
+    let message = "kernel string";
+    let result = dispatch(SYS_WRITE, [message.as_ptr() as u64, message.len() as u64, 0, 0, 0, 0]);
+    assert_eq!(result, SyscallError::BadAddress as i64);
+}
+
+#[test_case]
+fn test_time_and_yield() {
+    let before = dispatch(SYS_TIME, [0; 6]);
+    assert_eq!(dispatch(SYS_YIELD, [0; 6]), 0);
+    assert!(dispatch(SYS_TIME, [0; 6]) >= before);
+}
+
+#[test_case]
+fn test_wait_without_children() {
+    assert_eq!(dispatch(SYS_WAIT, [0; 6]), SyscallError::NoChildren as i64);
+}
+
+#[test_case]
+fn test_channel_handles() {
+    let mut handles = [0u64; 2];
+    // kernel memory is rejected like in `write`
+    let result = dispatch(SYS_CHANNEL, [handles.as_mut_ptr() as u64, 0, 0, 0, 0, 0]);
+    assert_eq!(result, SyscallError::BadAddress as i64);
+    let (sender, receiver) = channel::create_handles().expect("no free channel");
+    assert_eq!(channel::send_by_handle(receiver, b"x", false), Err(ChannelError::BadHandle));
+    assert_eq!(dispatch(SYS_CLOSE, [sender, 0, 0, 0, 0, 0]), 0);
+    assert_eq!(dispatch(SYS_CLOSE, [sender, 0, 0, 0, 0, 0]), SyscallError::BadHandle as i64);
+    let mut buf = [0; 1];
+    assert_eq!(channel::receive_by_handle(receiver, &mut buf, true), Err(ChannelError::Disconnected));
+    assert_eq!(dispatch(SYS_CLOSE, [receiver, 0, 0, 0, 0, 0]), 0);
+}
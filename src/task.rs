@@ -0,0 +1,498 @@
+use core::arch::global_asm;
+use core::ptr::addr_of_mut;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use x86_64::instructions::interrupts;
+use x86_64::registers::control::Cr3;
+use x86_64::VirtAddr;
+
+use crate::address_space::AddressSpace;
+use crate::{gdt, memory, syscall};
+use crate::sync::SpinLock;
+
+/// Maximum number of tasks, including the boot task
+pub const MAX_TASKS: usize = 16;
+/// Size of the kernel stack given to every spawned task
+pub const STACK_SIZE: usize = 4096 * 4;
+
+// The following pic shows the stack of a task that has been switched out
+//
+//   higher addresses
+//  +------------------+
+//  | return address   |  <- where `switch_context` returns to when resumed
+//  | rbp              |
+//  | rbx              |
+//  | r12              |
+//  | r13              |
+//  | r14              |
+//  | r15              |  <- saved `rsp`
+//  +------------------+
+//
+// Only callee-saved registers are kept, everything else has already been
+// saved by the caller of `switch_context` (or by the interrupt handler).
+global_asm!(
+    ".global switch_context",
+    "switch_context:",
+    "push rbp",
+    "push rbx",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "mov [rdi], rsp",
+    "mov rsp, rsi",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop rbx",
+    "pop rbp",
+    "ret",
+    "",
+    // first code run by a new task: r12/r13 hold the entry point and its
+    // argument, r14 holds the address of `task_entry`
+    ".global task_trampoline",
+    "task_trampoline:",
+    "mov rdi, r12",
+    "mov rsi, r13",
+    "call r14",
+    "ud2",
+);
+
+extern "C" {
+    fn switch_context(old_rsp: *mut u64, new_rsp: u64);
+    fn task_trampoline();
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct TaskId(u64);
+
+impl TaskId {
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TaskState {
+    Ready,
+    Running,
+    /// waiting for `unblock`, usually from a `sync::WaitQueue`
+    Blocked,
+    /// waiting for the timer tick with the given number
+    Sleeping(u64),
+    /// finished with the given exit code, waiting to be joined
+    Exited(i64),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpawnError {
+    TooManyTasks,
+}
+
+struct Task {
+    id: TaskId,
+    state: TaskState,
+    rsp: u64,
+    joined_by: Option<TaskId>,
+    /// nobody will join the task, its slot is free again once it has exited
+    detached: bool,
+}
+
+impl Task {
+    fn is_reapable(&self) -> bool {
+        self.detached && matches!(self.state, TaskState::Exited(_))
+    }
+}
+
+struct Scheduler {
+    tasks: [Option<Task>; MAX_TASKS],
+    /// user address space of the task in the same slot, `None` for kernel
+    /// tasks, which run on the kernel page table. Not part of `Task` because
+    /// `new` could not set up the boot task in a const fn otherwise.
+    address_spaces: [Option<AddressSpace>; MAX_TASKS],
+    current: usize,
+    next_id: u64,
+}
+
+#[repr(C, align(16))]
+struct Stack([u8; STACK_SIZE]);
+
+const EMPTY_STACK: Stack = Stack([0; STACK_SIZE]);
+const NO_TASK: Option<Task> = None;
+const NO_ADDRESS_SPACE: Option<AddressSpace> = None;
+
+/// Kernel stacks, indexed by slot. Slot 0 is the boot task, which keeps
+/// running on the stack set up by the bootloader.
+static mut STACKS: [Stack; MAX_TASKS] = [EMPTY_STACK; MAX_TASKS];
+
+static SCHEDULER: SpinLock<Scheduler> = SpinLock::new(Scheduler::new());
+
+/// Id of the running task, readable without taking the scheduler lock
+static CURRENT_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Returns the top of the kernel stack of the task in the given slot
+fn stack_top(slot: usize) -> VirtAddr {
+    VirtAddr::new(unsafe { addr_of_mut!(STACKS[slot]) as u64 } + STACK_SIZE as u64)
+}
+
+impl Scheduler {
+    const fn new() -> Self {
+        let mut tasks = [NO_TASK; MAX_TASKS];
+        // the code running at boot is task 0
+        tasks[0] = Some(Task {
+            id: TaskId(0),
+            state: TaskState::Running,
+            rsp: 0,
+            joined_by: None,
+            detached: false,
+        });
+        Scheduler {
+            tasks,
+            address_spaces: [NO_ADDRESS_SPACE; MAX_TASKS],
+            current: 0,
+            next_id: 1,
+        }
+    }
+
+    fn slot_of(&self, id: TaskId) -> Option<usize> {
+        self.tasks
+            .iter()
+            .position(|t| matches!(t, Some(task) if task.id == id && !task.is_reapable()))
+    }
+
+    fn current_mut(&mut self) -> &mut Task {
+        self.tasks[self.current].as_mut().expect("current task has no slot")
+    }
+
+    fn set_ready(&mut self, id: TaskId) {
+        if let Some(slot) = self.slot_of(id) {
+            let task = self.tasks[slot].as_mut().unwrap();
+            if let TaskState::Blocked | TaskState::Sleeping(_) = task.state {
+                task.state = TaskState::Ready;
+            }
+        }
+    }
+
+    /// Picks the next ready task in round-robin order and makes it current.
+    ///
+    /// Returns where to save the old stack pointer and the new one to load,
+    /// or `None` if no other task is ready.
+    fn switch_to_next(&mut self) -> Option<(*mut u64, u64)> {
+        let old = self.current;
+        let next = (1..MAX_TASKS)
+            .map(|offset| (old + offset) % MAX_TASKS)
+            .find(|&i| matches!(&self.tasks[i], Some(t) if t.state == TaskState::Ready))?;
+
+        let old_task = self.current_mut();
+        if old_task.state == TaskState::Running {
+            old_task.state = TaskState::Ready;
+        }
+        let old_rsp: *mut u64 = &mut old_task.rsp;
+
+        let new_task = self.tasks[next].as_mut().unwrap();
+        new_task.state = TaskState::Running;
+        let page_table = match &self.address_spaces[next] {
+            Some(space) => Some(space.level_4_frame()),
+            None => memory::kernel_page_table(),
+        };
+        let (old_page_table, cr3_flags) = Cr3::read();
+        if let Some(page_table) = page_table.filter(|&table| table != old_page_table) {
+            // every page table maps the kernel the same way, so the kernel
+            // stacks stay valid across the switch
+            unsafe { Cr3::write(page_table, cr3_flags) };
+        }
+        CURRENT_ID.store(new_task.id.0, Ordering::Relaxed);
+        let new_rsp = new_task.rsp;
+        self.current = next;
+        // interrupts from user mode must land on the new task's stack; the
+        // boot task never runs user code and has no stack of its own here
+        if next != 0 {
+            gdt::set_kernel_stack(stack_top(next));
+            syscall::set_kernel_stack(stack_top(next));
+        }
+        Some((old_rsp, new_rsp))
+    }
+}
+
+/// Switches to the next ready task.
+///
+/// If the current task is no longer runnable and nothing else is ready, the
+/// CPU halts until an interrupt makes some task ready. Must be called with
+/// interrupts disabled.
+fn schedule() {
+    loop {
+        let switch = SCHEDULER.lock().switch_to_next();
+        if let Some((old_rsp, new_rsp)) = switch {
+            // the lock is released here, `task_entry` and the code after
+            // `switch_context` must not expect it to be held
+            unsafe { switch_context(old_rsp, new_rsp) };
+            return;
+        }
+        {
+            let mut scheduler = SCHEDULER.lock();
+            let current = scheduler.current_mut();
+            match current.state {
+                TaskState::Running => return,
+                // woken up while no other task was ready, so it keeps the CPU
+                TaskState::Ready => {
+                    current.state = TaskState::Running;
+                    return;
+                }
+                _ => {}
+            }
+        }
+        interrupts::enable_and_hlt();
+        interrupts::disable();
+    }
+}
+
+extern "C" fn task_entry(entry: usize, arg: usize) -> ! {
+    // we got here from `schedule`, which runs with interrupts disabled
+    interrupts::enable();
+    let entry: fn(usize) = unsafe { core::mem::transmute(entry) };
+    entry(arg);
+    exit(0)
+}
+
+/// Creates a new kernel task running `entry(arg)`.
+///
+/// The task is ready immediately and exits with code 0 when `entry` returns.
+/// Its slot is only freed once another task `join`s it.
+pub fn spawn(entry: fn(usize), arg: usize) -> Result<TaskId, SpawnError> {
+    spawn_task(entry, arg, None)
+}
+
+/// Like `spawn`, but the task runs in the given address space, which is
+/// dropped when the task exits
+pub fn spawn_in(
+    address_space: AddressSpace,
+    entry: fn(usize),
+    arg: usize,
+) -> Result<TaskId, SpawnError> {
+    spawn_task(entry, arg, Some(address_space))
+}
+
+fn spawn_task(
+    entry: fn(usize),
+    arg: usize,
+    address_space: Option<AddressSpace>,
+) -> Result<TaskId, SpawnError> {
+    let mut scheduler = SCHEDULER.lock();
+    let slot = scheduler
+        .tasks
+        .iter()
+        .position(|t| match t {
+            Some(task) => task.is_reapable(),
+            None => true,
+        })
+        .ok_or(SpawnError::TooManyTasks)?;
+
+    let top = stack_top(slot).as_u64();
+    // initial frame popped by `switch_context`, see the picture above; the
+    // two zero words keep the stack 16-byte aligned when the trampoline calls
+    let frame: [u64; 9] = [
+        0,                                    // r15
+        task_entry as *const () as u64,       // r14
+        arg as u64,                           // r13
+        entry as *const () as u64,            // r12
+        0,                                    // rbx
+        0,                                    // rbp
+        task_trampoline as *const () as u64,  // return address
+        0,
+        0,
+    ];
+    let rsp = top - (frame.len() * 8) as u64;
+    unsafe {
+        core::ptr::copy_nonoverlapping(frame.as_ptr(), rsp as *mut u64, frame.len());
+    }
+
+    let id = TaskId(scheduler.next_id);
+    scheduler.next_id += 1;
+    scheduler.tasks[slot] = Some(Task {
+        id,
+        state: TaskState::Ready,
+        rsp,
+        joined_by: None,
+        detached: false,
+    });
+    scheduler.address_spaces[slot] = address_space;
+    Ok(id)
+}
+
+/// Returns the id of the running task
+pub fn current_id() -> TaskId {
+    TaskId(CURRENT_ID.load(Ordering::Relaxed))
+}
+
+/// Calls `f` with the address space of the running task, `None` for
+/// kernel tasks
+///
+/// The scheduler is locked meanwhile, so `f` must not block.
+pub fn with_current_address_space<R>(f: impl FnOnce(Option<&mut AddressSpace>) -> R) -> R {
+    let mut scheduler = SCHEDULER.lock();
+    let current = scheduler.current;
+    f(scheduler.address_spaces[current].as_mut())
+}
+
+/// Returns the number of tasks that have not been joined yet, including the boot task
+pub fn task_count() -> usize {
+    SCHEDULER
+        .lock()
+        .tasks
+        .iter()
+        .filter(|t| matches!(t, Some(task) if !task.is_reapable()))
+        .count()
+}
+
+/// Gives the CPU to the next ready task, if there is one
+pub fn yield_now() {
+    interrupts::without_interrupts(schedule);
+}
+
+/// Blocks the current task until another task or an interrupt handler calls
+/// `unblock` on it.
+///
+/// Callers must disable interrupts between checking their wake-up condition
+/// and calling this, otherwise the wake-up can be lost.
+pub fn block() {
+    interrupts::without_interrupts(|| {
+        SCHEDULER.lock().current_mut().state = TaskState::Blocked;
+        schedule();
+    });
+}
+
+/// Makes a blocked or sleeping task ready again. Does nothing for other tasks.
+///
+/// This never switches tasks, so it is safe to call from interrupt handlers.
+pub fn unblock(id: TaskId) {
+    SCHEDULER.lock().set_ready(id);
+}
+
+/// Puts the current task to sleep for at least `ticks` timer interrupts
+pub fn sleep(ticks: u64) {
+    interrupts::without_interrupts(|| {
+        let until = crate::interrupts::ticks() + ticks;
+        SCHEDULER.lock().current_mut().state = TaskState::Sleeping(until);
+        schedule();
+    });
+}
+
+/// Ends the current task with the given exit code
+pub fn exit(code: i64) -> ! {
+    interrupts::disable();
+    crate::process::task_exited(current_id(), code);
+    crate::channel::task_exited(current_id());
+    let address_space = {
+        let mut scheduler = SCHEDULER.lock();
+        let task = scheduler.current_mut();
+        task.state = TaskState::Exited(code);
+        if let Some(joiner) = task.joined_by {
+            scheduler.set_ready(joiner);
+        }
+        let current = scheduler.current;
+        scheduler.address_spaces[current].take()
+    };
+    if let Some(address_space) = address_space {
+        // leave the address space before freeing it; this code and its
+        // stack are in the kernel half
+        let (_, flags) = Cr3::read();
+        let kernel = memory::kernel_page_table().expect("address space without memory::init");
+        unsafe { Cr3::write(kernel, flags) };
+        drop(address_space);
+    }
+    schedule();
+    unreachable!("exited task was scheduled again");
+}
+
+/// Waits for the given task to exit, frees its slot and returns its exit code.
+///
+/// Returns `None` if there is no such task (or it was already joined) or the
+/// task has been detached.
+pub fn join(id: TaskId) -> Option<i64> {
+    interrupts::without_interrupts(|| loop {
+        {
+            let mut scheduler = SCHEDULER.lock();
+            let slot = scheduler.slot_of(id)?;
+            let task = scheduler.tasks[slot].as_ref().unwrap();
+            if task.detached {
+                return None;
+            }
+            if let TaskState::Exited(code) = task.state {
+                scheduler.tasks[slot] = None;
+                return Some(code);
+            }
+            let me = current_id();
+            scheduler.tasks[slot].as_mut().unwrap().joined_by = Some(me);
+            scheduler.current_mut().state = TaskState::Blocked;
+        }
+        schedule();
+    })
+}
+
+/// Lets the given task free its slot by itself once it exits, instead of
+/// waiting for `join`
+///
+/// Does nothing if there is no such task. After this, `join` returns `None`
+/// for the task.
+pub fn detach(id: TaskId) {
+    let mut scheduler = SCHEDULER.lock();
+    if let Some(slot) = scheduler.slot_of(id) {
+        let task = scheduler.tasks[slot].as_mut().unwrap();
+        if let TaskState::Exited(_) = task.state {
+            scheduler.tasks[slot] = None;
+        } else {
+            task.detached = true;
+        }
+    }
+}
+
+/// Called on every timer interrupt, after the end of interrupt was signalled.
+///
+/// Wakes sleeping tasks whose time has come and preempts the current task.
+/// Unlike `schedule` this never waits, so an interrupt that arrives while
+/// the current task idles does not nest another idle loop.
+pub(crate) fn timer_tick(now: u64) {
+    let switch = {
+        let mut scheduler = SCHEDULER.lock();
+        for task in scheduler.tasks.iter_mut().flatten() {
+            if let TaskState::Sleeping(until) = task.state {
+                if until <= now {
+                    task.state = TaskState::Ready;
+                }
+            }
+        }
+        scheduler.switch_to_next()
+    };
+    if let Some((old_rsp, new_rsp)) = switch {
+        unsafe { switch_context(old_rsp, new_rsp) };
+    }
+}
+
+#[test_case]
+fn test_spawn_and_join() {
+    fn worker(arg: usize) {
+        exit(arg as i64 * 2);
+    }
+    let id = spawn(worker, 21).expect("spawn failed");
+    assert_eq!(join(id), Some(42));
+    assert_eq!(join(id), None);
+}
+
+#[test_case]
+fn test_sleep() {
+    let start = crate::interrupts::ticks();
+    sleep(2);
+    assert!(crate::interrupts::ticks() >= start + 2);
+}
+
+#[test_case]
+fn test_join_detached() {
+    fn worker(_: usize) {
+        sleep(1);
+    }
+    let id = spawn(worker, 0).expect("spawn failed");
+    detach(id);
+    // returns at once, the task is still sleeping
+    assert_eq!(join(id), None);
+}
@@ -0,0 +1,205 @@
+//! Running code in ring 3
+
+use core::arch::asm;
+
+use log::error;
+
+use x86_64::registers::rflags::RFlags;
+use x86_64::structures::idt::InterruptStackFrame;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+
+use crate::address_space::AddressSpace;
+use crate::memory::USER_SPACE_START;
+use crate::sync::SpinLock;
+use crate::syscall::SyscallFrame;
+use crate::task::{self, SpawnError, TaskId, MAX_TASKS};
+use crate::signal::{self, Signal};
+use crate::gdt;
+
+/// Exit code of a task that was killed by a fault in user mode
+pub const USER_FAULT_EXIT_CODE: i64 = -1;
+
+/// Address `spawn_user_code` copies the code to
+const CODE_START: u64 = USER_SPACE_START;
+/// End (exclusive) of the user stack of programs started by `spawn_user_code`
+const USER_STACK_TOP: u64 = USER_SPACE_START + 0x10_0000;
+/// Size of the user stack of programs started by `spawn_user_code`
+const USER_STACK_SIZE: u64 = 4096 * 4;
+/// Maximum size of the code passed to `spawn_user_code`
+pub const MAX_CODE_SIZE: usize = 0x8000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UserSpawnError {
+    CodeTooLarge,
+    OutOfMemory,
+    Spawn(SpawnError),
+}
+
+/// Spawns a task that runs `code` in ring 3
+///
+/// The code is copied into a new address space, so it must be position
+/// independent. It starts with an empty stack and ends through the exit
+/// system call. A fault only kills the task, which then exits with
+/// `USER_FAULT_EXIT_CODE`.
+pub fn spawn_user_code(code: &[u8]) -> Result<TaskId, UserSpawnError> {
+    if code.len() > MAX_CODE_SIZE {
+        return Err(UserSpawnError::CodeTooLarge);
+    }
+    let mut address_space = AddressSpace::new().ok_or(UserSpawnError::OutOfMemory)?;
+    address_space
+        .map_user_region(VirtAddr::new(CODE_START), code.len() as u64, code, PageTableFlags::empty())
+        .and_then(|_| {
+            address_space.map_user_region(
+                VirtAddr::new(USER_STACK_TOP - USER_STACK_SIZE),
+                USER_STACK_SIZE,
+                &[],
+                PageTableFlags::WRITABLE,
+            )
+        })
+        .map_err(|_| UserSpawnError::OutOfMemory)?;
+
+    spawn_user_task(address_space, VirtAddr::new(CODE_START), VirtAddr::new(USER_STACK_TOP))
+        .map_err(UserSpawnError::Spawn)
+}
+
+/// Start parameters handed from `spawn_user_task` to the new task, which
+/// finds its registers through the index passed as the task argument
+static USER_STARTS: SpinLock<[Option<SyscallFrame>; MAX_TASKS]> = SpinLock::new([None; MAX_TASKS]);
+
+/// Spawns a task in `address_space` that continues at `entry` in ring 3,
+/// with the stack pointer at `stack_top`
+///
+/// Both addresses must be mapped user accessible in the address space. It
+/// is freed when the task exits.
+pub fn spawn_user_task(
+    address_space: AddressSpace,
+    entry: VirtAddr,
+    stack_top: VirtAddr,
+) -> Result<TaskId, SpawnError> {
+    let registers = SyscallFrame {
+        rip: entry.as_u64(),
+        rsp: stack_top.as_u64(),
+        ..SyscallFrame::default()
+    };
+    spawn_user_task_with(address_space, &registers)
+}
+
+/// Spawns a task in `address_space` that continues in ring 3 with the given
+/// registers, see `resume_user_mode`
+pub fn spawn_user_task_with(
+    address_space: AddressSpace,
+    registers: &SyscallFrame,
+) -> Result<TaskId, SpawnError> {
+    let index = {
+        let mut starts = USER_STARTS.lock();
+        let index = starts
+            .iter()
+            .position(|s| s.is_none())
+            .ok_or(SpawnError::TooManyTasks)?;
+        starts[index] = Some(*registers);
+        index
+    };
+    let result = task::spawn_in(address_space, run_user_start, index);
+    if result.is_err() {
+        USER_STARTS.lock()[index] = None;
+    }
+    result
+}
+
+fn run_user_start(index: usize) {
+    let registers = USER_STARTS.lock()[index].take().expect("user task started twice");
+    // the scheduler has already loaded the address space of this task
+    unsafe { resume_user_mode(&registers) }
+}
+
+/// Switches to ring 3 and continues at `entry` with the stack pointer at `stack_top`
+///
+/// The general purpose registers are cleared so no kernel data leaks to user
+/// mode, and interrupts are enabled.
+///
+/// # Safety
+///
+/// Both addresses must be mapped user accessible in the active page table.
+pub unsafe fn enter_user_mode(entry: VirtAddr, stack_top: VirtAddr) -> ! {
+    resume_user_mode(&SyscallFrame {
+        rip: entry.as_u64(),
+        rsp: stack_top.as_u64(),
+        ..SyscallFrame::default()
+    })
+}
+
+/// Switches to ring 3 with the registers in `registers`
+///
+/// The segment selectors are replaced with the user selectors and only the
+/// arithmetic flags and the direction flag are taken from `rflags`;
+/// interrupts are always enabled.
+///
+/// # Safety
+///
+/// `rip` and `rsp` must be valid for the program in the active page table.
+pub unsafe fn resume_user_mode(registers: &SyscallFrame) -> ! {
+    let user_flags = RFlags::CARRY_FLAG
+        | RFlags::PARITY_FLAG
+        | RFlags::AUXILIARY_CARRY_FLAG
+        | RFlags::ZERO_FLAG
+        | RFlags::SIGN_FLAG
+        | RFlags::DIRECTION_FLAG
+        | RFlags::OVERFLOW_FLAG;
+    let (code_selector, data_selector) = gdt::user_selectors();
+    let mut frame = *registers;
+    frame.cs = code_selector.0.into();
+    frame.ss = data_selector.0.into();
+    frame.rflags = (RFlags::from_bits_truncate(frame.rflags) & user_flags | RFlags::INTERRUPT_FLAG).bits();
+
+    asm!(
+        // the frame has the layout the syscall entry code pops, ending with
+        // the frame `iretq` expects, as if an interrupt came from user mode
+        "mov rsp, {}",
+        "pop r15",
+        "pop r14",
+        "pop r13",
+        "pop r12",
+        "pop r11",
+        "pop r10",
+        "pop r9",
+        "pop r8",
+        "pop rbp",
+        "pop rdi",
+        "pop rsi",
+        "pop rdx",
+        "pop rcx",
+        "pop rbx",
+        "pop rax",
+        "iretq",
+        in(reg) &frame,
+        options(noreturn),
+    );
+}
+
+/// Returns whether the interrupted code was running in ring 3
+pub fn from_user_mode(stack_frame: &InterruptStackFrame) -> bool {
+    stack_frame.code_segment & 0b11 == 3
+}
+
+/// Raises `signal` for the current process if the exception interrupted
+/// user code, and kills the task if the process has no handler for it
+///
+/// Exception handlers call this first, so that only faults in the kernel
+/// itself are fatal. Returns true if the handler should return to let the
+/// signal handler run, false if the exception happened in the kernel.
+pub fn handle_user_fault(exception: &str, signal: Signal, stack_frame: &mut InterruptStackFrame) -> bool {
+    if from_user_mode(stack_frame) {
+        if signal::raise_fault(signal, stack_frame) {
+            return true;
+        }
+        error!(
+            "USER FAULT: {} in task {} at {:?}",
+            exception,
+            task::current_id().as_u64(),
+            stack_frame.instruction_pointer
+        );
+        task::exit(USER_FAULT_EXIT_CODE);
+    }
+    false
+}
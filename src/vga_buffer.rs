@@ -1,8 +1,13 @@
 use core::fmt;
+use core::ptr;
 
 use lazy_static::lazy_static;
-use spin::Mutex;
 use volatile::Volatile;
+use x86_64::instructions::port::Port;
+
+use crate::ansi::{Action, Csi, Parser};
+use crate::cp437::{self, Utf8Decoder};
+use crate::sync::SpinLock;
 
 // src/vga_buffer.rs
 #[allow(dead_code)]
@@ -39,26 +44,110 @@ macro_rules! println {
     ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
 }
 
+/// Prints to the VGA screen like `print!`, in the foreground colour given as
+/// first argument
+#[macro_export]
+macro_rules! print_color {
+    ($color:expr, $($arg:tt)*) => (
+        $crate::vga_buffer::_print_color($color, format_args!($($arg)*))
+    );
+}
+
+/// Prints a line to the VGA screen like `println!`, in the foreground colour
+/// given as first argument
+#[macro_export]
+macro_rules! println_color {
+    ($color:expr) => ($crate::print_color!($color, "\n"));
+    ($color:expr, $($arg:tt)*) => ($crate::print_color!($color, "{}\n", format_args!($($arg)*)));
+}
+
 #[doc(hidden)]
 pub fn _print(args: fmt::Arguments) {
     use core::fmt::Write;
-    use x86_64::instructions::interrupts; // these lines of codes are used to prevent deadlock
 
-    interrupts::without_interrupts(|| {  // prevent deadlock (interrupt is disabled when Mutex is locked)
-        WRITER.lock().write_fmt(args).unwrap();
-    });
+    // `SpinLock` disables interrupts while held, so the timer interrupt
+    // handler can't deadlock on `WRITER` in the middle of a print
+    WRITER.lock().write_fmt(args).unwrap();
+}
+
+#[doc(hidden)]
+pub fn _print_color(foreground: Color, args: fmt::Arguments) {
+    use core::fmt::Write;
+
+    // the colour is changed under the same lock, so other output keeps its own
+    let mut writer = WRITER.lock();
+    let previous = writer.color_code;
+    writer.set_foreground(foreground);
+    writer.write_fmt(args).unwrap();
+    writer.color_code = previous;
 }
 
+/// Colours by their number in the VGA attribute byte
+const COLORS: [Color; 16] = [
+    Color::Black,
+    Color::Blue,
+    Color::Green,
+    Color::Cyan,
+    Color::Red,
+    Color::Magenta,
+    Color::Brown,
+    Color::LightGray,
+    Color::DarkGray,
+    Color::LighBlue,
+    Color::LightGreen,
+    Color::LightCyan,
+    Color::LightRed,
+    Color::Pink,
+    Color::Yellow,
+    Color::White,
+];
+
+/// Colour used until it is changed, yellow on black
+pub const DEFAULT_COLOR: ColorCode = ColorCode::new(Color::Yellow, Color::Black);
+
+/// Foreground and background colour of a character, as stored in the VGA
+/// attribute byte
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(transparent)]
-struct ColorCode(u8);
+pub struct ColorCode(u8);
 
 impl ColorCode {
-    fn new(foreground: Color, background: Color) -> ColorCode {
+    pub const fn new(foreground: Color, background: Color) -> ColorCode {
         ColorCode((background as u8) << 4 | (foreground as u8))
     }
+
+    pub fn foreground(self) -> Color {
+        COLORS[usize::from(self.0 & 0x0f)]
+    }
+
+    pub fn background(self) -> Color {
+        COLORS[usize::from(self.0 >> 4)]
+    }
+
+    fn with_foreground(self, foreground: u8) -> ColorCode {
+        ColorCode(self.0 & 0xf0 | foreground & 0x0f)
+    }
+
+    fn with_background(self, background: u8) -> ColorCode {
+        ColorCode(self.0 & 0x0f | background << 4)
+    }
 }
 
+/// `Color`s of the ANSI colour numbers 0 to 7; setting bit 3 gives the bright
+/// variant of each
+const ANSI_COLORS: [Color; 8] = [
+    Color::Black,
+    Color::Red,
+    Color::Green,
+    Color::Brown,
+    Color::Blue,
+    Color::Magenta,
+    Color::Cyan,
+    Color::LightGray,
+];
+/// Bit of a colour that makes it bright
+const BRIGHT: u8 = 0x08;
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(C)]
 struct ScreenChar {
@@ -66,63 +155,626 @@ struct ScreenChar {
     color_code: ColorCode,
 }
 
-const BUFFER_HEIGHT: usize = 25;
-const BUFFER_WIDTH: usize = 80;
+pub const BUFFER_HEIGHT: usize = 25;
+pub const BUFFER_WIDTH: usize = 80;
 
 #[repr(transparent)]
 struct Buffer {
     chars: [[Volatile<ScreenChar>; BUFFER_WIDTH]; BUFFER_HEIGHT],
 }
 
+/// Maximum number of lines the scrollback history can keep
+pub const SCROLLBACK_CAPACITY: usize = 256;
+
+const BLANK: ScreenChar = ScreenChar {
+    ascii_character: b' ',
+    color_code: DEFAULT_COLOR,
+};
+
+type Line = [ScreenChar; BUFFER_WIDTH];
+
+/// Lines scrolled off the top of the screen, oldest first
+struct Scrollback {
+    lines: [Line; SCROLLBACK_CAPACITY],
+    /// index of the oldest line in `lines`
+    start: usize,
+    len: usize,
+    /// number of lines kept, older ones are dropped
+    limit: usize,
+    /// number of lines the view is scrolled back by, 0 for the live screen
+    offset: usize,
+    /// the live screen, saved while the view is scrolled back
+    live: [Line; BUFFER_HEIGHT],
+}
+
+impl Scrollback {
+    const fn new() -> Self {
+        Scrollback {
+            lines: [[BLANK; BUFFER_WIDTH]; SCROLLBACK_CAPACITY],
+            start: 0,
+            len: 0,
+            limit: SCROLLBACK_CAPACITY,
+            offset: 0,
+            live: [[BLANK; BUFFER_WIDTH]; BUFFER_HEIGHT],
+        }
+    }
+
+    fn push(&mut self, line: Line) {
+        if self.limit == 0 {
+            return;
+        }
+        if self.len == self.limit {
+            self.start = (self.start + 1) % SCROLLBACK_CAPACITY;
+            self.len -= 1;
+        }
+        self.lines[(self.start + self.len) % SCROLLBACK_CAPACITY] = line;
+        self.len += 1;
+    }
+
+    /// Returns line `index`, counted from the oldest one
+    fn line(&self, index: usize) -> &Line {
+        &self.lines[(self.start + index) % SCROLLBACK_CAPACITY]
+    }
+
+    /// Keeps at most `limit` lines, dropping the oldest ones
+    fn set_limit(&mut self, limit: usize) {
+        let limit = limit.min(SCROLLBACK_CAPACITY);
+        if self.len > limit {
+            self.start = (self.start + self.len - limit) % SCROLLBACK_CAPACITY;
+            self.len = limit;
+        }
+        self.limit = limit;
+    }
+}
+
+/// Number of virtual consoles, switched with Alt+F1 and so on
+pub const CONSOLES: usize = 6;
+
+/// Scrollback history of each console
+static mut SCROLLBACKS: [Scrollback; CONSOLES] = {
+    const EMPTY: Scrollback = Scrollback::new();
+    [EMPTY; CONSOLES]
+};
+
+/// Screens of the consoles that are not shown, in the layout of `Buffer`
+static mut SCREENS: [[Line; BUFFER_HEIGHT]; CONSOLES] =
+    [[[BLANK; BUFFER_WIDTH]; BUFFER_HEIGHT]; CONSOLES];
+
+/// Index port of the CRT controller, selects the register the data port accesses
+const CRTC_INDEX: u16 = 0x3D4;
+const CRTC_DATA: u16 = 0x3D5;
+// CRT controller registers of the text cursor
+const CURSOR_START: u8 = 0x0A;
+const CURSOR_END: u8 = 0x0B;
+const CURSOR_LOCATION_HIGH: u8 = 0x0E;
+const CURSOR_LOCATION_LOW: u8 = 0x0F;
+/// Bit of the cursor start register that hides the cursor
+const CURSOR_DISABLE: u8 = 1 << 5;
+/// Bits of the cursor start and end registers that hold the scanline
+const SCANLINE_MASK: u8 = 0x1f;
+/// Number of scanlines of a character cell
+pub const SCANLINES: u8 = 16;
+
+fn read_crtc(register: u8) -> u8 {
+    let mut index: Port<u8> = Port::new(CRTC_INDEX);
+    let mut data: Port<u8> = Port::new(CRTC_DATA);
+    unsafe {
+        index.write(register);
+        data.read()
+    }
+}
+
+fn write_crtc(register: u8, value: u8) {
+    let mut index: Port<u8> = Port::new(CRTC_INDEX);
+    let mut data: Port<u8> = Port::new(CRTC_DATA);
+    unsafe {
+        index.write(register);
+        data.write(value);
+    }
+}
+
 pub struct Writer {
+    /// row the next character goes to; output starts on the bottom row and
+    /// only scrolls once it reaches it
+    row_position: usize,
     column_position: usize,
+    /// position kept by `save_position`
+    saved_position: (usize, usize),
     color_code: ColorCode,
+    /// colour restored by `reset_color` and `ESC [ 0 m`
+    default_color: ColorCode,
+    /// escape sequences in `write_string`
+    parser: Parser,
+    /// a character whose bytes are split between writes
+    utf8: Utf8Decoder,
+    /// the VGA buffer while the console is shown, its own screen otherwise
     buffer: &'static mut Buffer,
+    /// the console's own screen while it is shown, which it is iff this is
+    /// `Some`
+    spare: Option<&'static mut Buffer>,
+    /// the hardware cursor is shown while the console is
+    cursor_visible: bool,
+    /// rows at the top kept out of the text area, see `set_reserved_rows`
+    reserved_rows: usize,
+    scrollback: &'static mut Scrollback,
 }
 
 impl Writer {
+    /// Creates the writer of console `index`; console 0 is shown at first
+    ///
+    /// Must only be called once for each console.
+    fn new(index: usize) -> Writer {
+        // `Volatile` is `repr(transparent)`, so a screen has the layout of `Buffer`
+        let screen = unsafe { &mut *(ptr::addr_of_mut!(SCREENS[index]) as *mut Buffer) };
+        let (buffer, spare) = match index {
+            0 => (unsafe { &mut *(0xb8000 as *mut Buffer) }, Some(screen)),
+            _ => (screen, None),
+        };
+        Writer {
+            row_position: BUFFER_HEIGHT - 1,
+            column_position: 0,
+            saved_position: (BUFFER_HEIGHT - 1, 0),
+            color_code: DEFAULT_COLOR,
+            default_color: DEFAULT_COLOR,
+            parser: Parser::new(),
+            utf8: Utf8Decoder::new(),
+            buffer,
+            spare,
+            cursor_visible: true,
+            reserved_rows: 0,
+            scrollback: unsafe { &mut *ptr::addr_of_mut!(SCROLLBACKS[index]) },
+        }
+    }
+
+    /// Returns whether the console is the one shown
+    pub fn is_shown(&self) -> bool {
+        self.spare.is_some()
+    }
+
+    /// Moves the writer of the console shown to its own screen, returns the
+    /// VGA buffer
+    fn detach(&mut self) -> &'static mut Buffer {
+        self.show_live();
+        let screen = self.spare.take().expect("shown console without a screen");
+        copy_buffer(self.buffer, screen);
+        core::mem::replace(&mut self.buffer, screen)
+    }
+
+    /// Shows the console in `vga`, the VGA buffer
+    fn attach(&mut self, vga: &'static mut Buffer) {
+        copy_buffer(self.buffer, vga);
+        let screen = core::mem::replace(&mut self.buffer, vga);
+        self.spare = Some(screen);
+        let visible = self.cursor_visible;
+        self.set_cursor_visible(visible);
+        self.update_cursor();
+    }
+
     pub fn write_byte(&mut self, byte: u8) {
+        self.show_live();
+        self.put_byte(byte);
+        self.update_cursor();
+    }
+
+    /// Writes a byte without moving the hardware cursor
+    fn put_byte(&mut self, byte: u8) {
         match byte {
             b'\n' => self.new_line(),
-            byte => {
-                if self.column_position >= BUFFER_WIDTH {
-                    self.new_line();
+            // backspace only moves left, like on a terminal
+            0x08 => self.column_position = self.column_position.saturating_sub(1),
+            byte => self.put_glyph(byte),
+        }
+    }
+
+    /// Writes the code page 437 glyph `byte`, even the ones of control bytes
+    fn put_glyph(&mut self, byte: u8) {
+        if self.column_position >= BUFFER_WIDTH {
+            self.new_line();
+        }
+
+        let row = self.row_position;
+        let col = self.column_position;
+
+        let color_code = self.color_code;
+        self.buffer.chars[row][col].write(ScreenChar {
+            ascii_character: byte,
+            color_code: color_code,
+        });
+        self.column_position += 1;
+    }
+
+    /// Writes `s`, interpreting control characters and ANSI escape sequences
+    ///
+    /// Characters are shown with their code page 437 glyph, or `■` if there
+    /// is none. Supported are `\n`, `\r`, `\t` and backspace, ESC 7/8 and these CSI
+    /// sequences: cursor movement (`A`-`D`, `G`, `H`, `f`), erase in display
+    /// and line (`J`, `K`), colours (`m`), save/restore (`s`, `u`) and
+    /// showing/hiding the cursor (`?25h`, `?25l`). Other sequences and
+    /// control characters are ignored.
+    pub fn write_string(&mut self, s: &str) {
+        self.write_bytes(s.as_bytes());
+    }
+
+    /// Writes UTF-8 encoded `bytes` like `write_string`
+    ///
+    /// A character may be split between calls; invalid UTF-8 shows as `■`.
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        self.show_live();
+        for &byte in bytes {
+            let action = self.parser.advance(byte);
+            if let Some(Action::Print(byte)) = action {
+                // the decoder is copied out so `self` can be borrowed by `emit`
+                let mut utf8 = self.utf8;
+                utf8.push(byte, |c| self.put_char(c));
+                self.utf8 = utf8;
+                continue;
+            }
+            // control bytes and escape sequences cut off a character
+            if action.is_some() {
+                if let Some(c) = self.utf8.reset() {
+                    self.put_char(c);
                 }
+            }
+            match action {
+                Some(Action::Execute(byte)) => self.execute(byte),
+                Some(Action::Escape(b'7')) => self.save_position(),
+                Some(Action::Escape(b'8')) => self.restore_position(),
+                Some(Action::Csi(csi)) => self.csi(&csi),
+                Some(Action::Print(_)) | Some(Action::Escape(_)) | None => {}
+            }
+        }
+        self.update_cursor();
+    }
 
-                let row = BUFFER_HEIGHT - 1;
-                let col = self.column_position;
+    fn put_char(&mut self, c: char) {
+        self.put_glyph(cp437::encode(c).unwrap_or(cp437::REPLACEMENT));
+    }
 
-                let color_code = self.color_code;
-                self.buffer.chars[row][col].write(ScreenChar {
-                    ascii_character: byte,
-                    color_code: color_code,
-                });
-                self.column_position += 1;
+    fn execute(&mut self, control: u8) {
+        match control {
+            b'\n' | 0x08 => self.put_byte(control),
+            b'\r' => self.column_position = 0,
+            b'\t' => self.column_position = ((self.column_position / 8 + 1) * 8).min(BUFFER_WIDTH),
+            _ => {}
+        }
+    }
+
+    fn csi(&mut self, csi: &Csi) {
+        let n = usize::from(csi.param(0, 1));
+        let (row, col) = self.cursor_position();
+        match (csi.private, csi.final_byte) {
+            (false, b'A') => self.set_position(row.saturating_sub(n), col),
+            (false, b'B') => self.set_position(row + n, col),
+            (false, b'C') => self.set_position(row, col + n),
+            (false, b'D') => self.set_position(row, col.saturating_sub(n)),
+            (false, b'G') => self.set_position(row, n - 1),
+            (false, b'H') | (false, b'f') => {
+                self.set_position(n - 1, usize::from(csi.param(1, 1)) - 1)
             }
+            (false, b'J') => match csi.param(0, 0) {
+                0 => {
+                    self.clear_region(row, col, 1, BUFFER_WIDTH);
+                    self.clear_region(row + 1, 0, BUFFER_HEIGHT, BUFFER_WIDTH);
+                }
+                1 => {
+                    self.clear_region(0, 0, row, BUFFER_WIDTH);
+                    self.clear_region(row, 0, 1, col + 1);
+                }
+                _ => self.clear_region(0, 0, BUFFER_HEIGHT, BUFFER_WIDTH),
+            },
+            (false, b'K') => match csi.param(0, 0) {
+                0 => self.clear_region(row, col, 1, BUFFER_WIDTH),
+                1 => self.clear_region(row, 0, 1, col + 1),
+                _ => self.clear_region(row, 0, 1, BUFFER_WIDTH),
+            },
+            (false, b'm') => self.select_graphic_rendition(csi.params()),
+            (false, b's') => self.save_position(),
+            (false, b'u') => self.restore_position(),
+            (true, b'h') if csi.params() == [25] => self.set_cursor_visible(true),
+            (true, b'l') if csi.params() == [25] => self.set_cursor_visible(false),
+            _ => {}
         }
     }
 
-    pub fn write_string(&mut self, s: &str) {
-        for byte in s.bytes() {
-            match byte {
-                // printable ASCII or '\n'
-                0x20..=0x7e | b'\n' => self.write_byte(byte),
-                // not included bytes
-                _ => self.write_byte(0xfe),
+    /// Applies the parameters of an SGR sequence (`ESC [ ... m`)
+    ///
+    /// Bold is shown as the bright variant of the foreground colour.
+    fn select_graphic_rendition(&mut self, params: &[u16]) {
+        // `ESC [ m` is the same as `ESC [ 0 m`
+        let params = if params.is_empty() { &[0][..] } else { params };
+        for &param in params {
+            let color = self.color_code;
+            self.color_code = match param {
+                0 => self.default_color,
+                1 => ColorCode(color.0 | BRIGHT),
+                22 => ColorCode(color.0 & !BRIGHT),
+                30..=37 => color.with_foreground(ANSI_COLORS[usize::from(param - 30)] as u8),
+                39 => color.with_foreground(self.default_color.0),
+                40..=47 => color.with_background(ANSI_COLORS[usize::from(param - 40)] as u8),
+                49 => color.with_background(self.default_color.0 >> 4),
+                90..=97 => {
+                    color.with_foreground(ANSI_COLORS[usize::from(param - 90)] as u8 | BRIGHT)
+                }
+                100..=107 => {
+                    color.with_background(ANSI_COLORS[usize::from(param - 100)] as u8 | BRIGHT)
+                }
+                _ => color,
+            };
+        }
+    }
+
+    /// Returns the colour characters are written in
+    pub fn color_code(&self) -> ColorCode {
+        self.color_code
+    }
+
+    /// Sets the colour of the following characters
+    pub fn set_color_code(&mut self, color_code: ColorCode) {
+        self.color_code = color_code;
+    }
+
+    pub fn set_foreground(&mut self, foreground: Color) {
+        self.color_code = self.color_code.with_foreground(foreground as u8);
+    }
+
+    pub fn set_background(&mut self, background: Color) {
+        self.color_code = self.color_code.with_background(background as u8);
+    }
+
+    /// Returns the colour restored by `reset_color`
+    pub fn default_color(&self) -> ColorCode {
+        self.default_color
+    }
+
+    /// Sets the colour restored by `reset_color`, the current one is kept
+    pub fn set_default_color(&mut self, color_code: ColorCode) {
+        self.default_color = color_code;
+    }
+
+    /// Goes back to the default colour
+    pub fn reset_color(&mut self) {
+        self.color_code = self.default_color;
+    }
+
+    /// Returns the row and column the next character is written to
+    pub fn cursor_position(&self) -> (usize, usize) {
+        // a full row only wraps with the next character
+        (self.row_position, self.column_position.min(BUFFER_WIDTH - 1))
+    }
+
+    /// Moves to `row` and `col`, clamped to the text area
+    pub fn set_position(&mut self, row: usize, col: usize) {
+        self.row_position = row.max(self.reserved_rows).min(BUFFER_HEIGHT - 1);
+        self.column_position = col.min(BUFFER_WIDTH - 1);
+        self.update_cursor();
+    }
+
+    /// Remembers the current position for `restore_position`
+    pub fn save_position(&mut self) {
+        self.saved_position = (self.row_position, self.column_position);
+    }
+
+    /// Moves back to the position remembered by `save_position`
+    pub fn restore_position(&mut self) {
+        let (row, col) = self.saved_position;
+        self.set_position(row, col);
+    }
+
+    /// Writes `s` starting at `row` and `col` without moving the position
+    ///
+    /// The text does not wrap; whatever does not fit into the row is cut off.
+    /// Nothing is written to reserved rows.
+    pub fn write_at(&mut self, row: usize, col: usize, s: &str) {
+        if row < self.reserved_rows || row >= BUFFER_HEIGHT {
+            return;
+        }
+        self.show_live();
+        for (col, c) in (col..BUFFER_WIDTH).zip(s.chars()) {
+            self.buffer.chars[row][col].write(ScreenChar {
+                ascii_character: cp437::encode(c).unwrap_or(cp437::REPLACEMENT),
+                color_code: self.color_code,
+            });
+        }
+    }
+
+    /// Blanks `height` rows of `width` characters from `top` and `left` on,
+    /// as far as they are in the text area
+    pub fn clear_region(&mut self, top: usize, left: usize, height: usize, width: usize) {
+        let blank = ScreenChar {
+            ascii_character: b' ',
+            color_code: self.color_code,
+        };
+        self.show_live();
+        for row in top.max(self.reserved_rows)..(top + height).min(BUFFER_HEIGHT) {
+            for col in left..(left + width).min(BUFFER_WIDTH) {
+                self.buffer.chars[row][col].write(blank);
             }
         }
     }
 
+    /// Moves the hardware cursor to `cursor_position`, or off the screen
+    /// while the view is scrolled back
+    fn update_cursor(&self) {
+        if !self.is_shown() {
+            return;
+        }
+        let (row, col) = self.cursor_position();
+        let location = match self.scrollback.offset {
+            0 => (row * BUFFER_WIDTH + col) as u16,
+            _ => (BUFFER_HEIGHT * BUFFER_WIDTH) as u16,
+        };
+        write_crtc(CURSOR_LOCATION_HIGH, (location >> 8) as u8);
+        write_crtc(CURSOR_LOCATION_LOW, location as u8);
+    }
+
+    /// Shows or hides the hardware cursor
+    ///
+    /// Each console has its own setting, which takes effect while it is shown.
+    pub fn set_cursor_visible(&mut self, visible: bool) {
+        self.cursor_visible = visible;
+        if !self.is_shown() {
+            return;
+        }
+        let start = read_crtc(CURSOR_START);
+        if visible {
+            write_crtc(CURSOR_START, start & !CURSOR_DISABLE);
+        } else {
+            write_crtc(CURSOR_START, start | CURSOR_DISABLE);
+        }
+    }
+
+    /// Returns whether the hardware cursor is shown for this console
+    pub fn cursor_visible(&self) -> bool {
+        self.cursor_visible
+    }
+
+    /// Makes the cursor cover the scanlines `start` to `end` of a character
+    /// cell, e.g. `(14, 15)` for an underline or `(0, 15)` for a block
+    ///
+    /// The shape is shared by all consoles.
+    ///
+    /// Both are clamped to `SCANLINES - 1`.
+    pub fn set_cursor_shape(&mut self, start: u8, end: u8) {
+        let start = start.min(SCANLINES - 1);
+        let end = end.min(SCANLINES - 1);
+        let start_register = read_crtc(CURSOR_START);
+        write_crtc(CURSOR_START, start_register & !SCANLINE_MASK | start);
+        let end_register = read_crtc(CURSOR_END);
+        write_crtc(CURSOR_END, end_register & !SCANLINE_MASK | end);
+    }
+
+    /// Returns the first and last scanline covered by the cursor
+    pub fn cursor_shape(&self) -> (u8, u8) {
+        (read_crtc(CURSOR_START) & SCANLINE_MASK, read_crtc(CURSOR_END) & SCANLINE_MASK)
+    }
+
     fn new_line(&mut self) {
         // todo!()
-        for row in 1..BUFFER_HEIGHT {
+        self.column_position = 0;
+        if self.row_position < BUFFER_HEIGHT - 1 {
+            self.row_position += 1;
+            return;
+        }
+        let top = self.read_line(self.reserved_rows);
+        self.scrollback.push(top);
+        for row in self.reserved_rows + 1..BUFFER_HEIGHT {
             for col in 0..BUFFER_WIDTH {
                 let character = self.buffer.chars[row][col].read();
                 self.buffer.chars[row - 1][col].write(character);
             }
         }
         self.clear_row(BUFFER_HEIGHT - 1);
-        self.column_position = 0;
+    }
+
+    fn read_line(&self, row: usize) -> Line {
+        let mut line = [BLANK; BUFFER_WIDTH];
+        for (col, character) in line.iter_mut().enumerate() {
+            *character = self.buffer.chars[row][col].read();
+        }
+        line
+    }
+
+    fn write_line(&mut self, row: usize, line: &Line) {
+        for (col, &character) in line.iter().enumerate() {
+            self.buffer.chars[row][col].write(character);
+        }
+    }
+
+    /// Shows the screen as it was `lines` lines further back in the
+    /// scrollback history, as far as the history goes
+    ///
+    /// Any output returns to the live screen.
+    pub fn scroll_back(&mut self, lines: usize) {
+        self.scroll_view(self.scrollback.offset.saturating_add(lines));
+    }
+
+    /// Scrolls the view `lines` lines towards the live screen
+    pub fn scroll_forward(&mut self, lines: usize) {
+        self.scroll_view(self.scrollback.offset.saturating_sub(lines));
+    }
+
+    /// Returns the number of rows at the top kept out of the text area
+    pub fn reserved_rows(&self) -> usize {
+        self.reserved_rows
+    }
+
+    /// Keeps the top `rows` rows, up to `BUFFER_HEIGHT - 1`, out of the text
+    /// area: they do not scroll and are only written by `write_reserved`
+    pub fn set_reserved_rows(&mut self, rows: usize) {
+        self.show_live();
+        self.reserved_rows = rows.min(BUFFER_HEIGHT - 1);
+        let (row, col) = (self.row_position, self.column_position);
+        self.set_position(row, col);
+    }
+
+    /// Fills reserved row `row` with `s`, padded with blanks, in `color_code`
+    ///
+    /// Unlike other output this does not leave the scrollback history.
+    pub fn write_reserved(&mut self, row: usize, s: &str, color_code: ColorCode) {
+        if row >= self.reserved_rows {
+            return;
+        }
+        let mut chars = s.chars();
+        for col in 0..BUFFER_WIDTH {
+            let ascii_character = match chars.next() {
+                Some(c) => cp437::encode(c).unwrap_or(cp437::REPLACEMENT),
+                None => b' ',
+            };
+            self.buffer.chars[row][col].write(ScreenChar { ascii_character, color_code });
+        }
+    }
+
+    /// Returns the number of lines the view is scrolled back by, 0 if the
+    /// live screen is shown
+    pub fn scrolled_back(&self) -> usize {
+        self.scrollback.offset
+    }
+
+    /// Returns the number of lines the scrollback history keeps
+    pub fn scrollback_lines(&self) -> usize {
+        self.scrollback.limit
+    }
+
+    /// Keeps at most `lines` lines, up to `SCROLLBACK_CAPACITY`, in the
+    /// scrollback history; 0 turns it off
+    pub fn set_scrollback_lines(&mut self, lines: usize) {
+        self.show_live();
+        self.scrollback.set_limit(lines);
+    }
+
+    fn show_live(&mut self) {
+        self.scroll_view(0);
+    }
+
+    fn scroll_view(&mut self, offset: usize) {
+        let offset = offset.min(self.scrollback.len);
+        if offset == self.scrollback.offset {
+            return;
+        }
+        let top = self.reserved_rows;
+        if self.scrollback.offset == 0 {
+            for row in top..BUFFER_HEIGHT {
+                self.scrollback.live[row] = self.read_line(row);
+            }
+        }
+        self.scrollback.offset = offset;
+        // the top `offset` rows of the text area show history, the live
+        // screen follows below
+        let first = self.scrollback.len - offset;
+        for row in top..BUFFER_HEIGHT {
+            let line = match (row - top).checked_sub(offset) {
+                Some(live_row) => self.scrollback.live[top + live_row],
+                None => *self.scrollback.line(first + row - top),
+            };
+            self.write_line(row, &line);
+        }
+        self.update_cursor();
+    }
+
+    /// Blanks the text area and moves to its top left corner
+    pub fn clear_screen(&mut self) {
+        self.clear_region(0, 0, BUFFER_HEIGHT, BUFFER_WIDTH);
+        self.set_position(0, 0);
     }
 
     fn clear_row(&mut self, row: usize) {
@@ -159,11 +811,132 @@ impl fmt::Write for Writer {
 // }
 
 lazy_static! {
-    pub static ref WRITER: Mutex<Writer> = Mutex::new(Writer {
-        column_position: 0,
-        color_code: ColorCode::new(Color::Yellow, Color::Black),
-        buffer: unsafe { &mut *(0xb8000 as *mut Buffer) },
-    });
+    /// Writer of console 0, which `print!` and the shell write to
+    pub static ref WRITER: SpinLock<Writer> = SpinLock::new(Writer::new(0));
+    static ref OTHER_WRITERS: [SpinLock<Writer>; CONSOLES - 1] = [
+        SpinLock::new(Writer::new(1)),
+        SpinLock::new(Writer::new(2)),
+        SpinLock::new(Writer::new(3)),
+        SpinLock::new(Writer::new(4)),
+        SpinLock::new(Writer::new(5)),
+    ];
+}
+
+/// Which console is shown; locked while switching, before the writers
+struct Display {
+    index: usize,
+    /// the VGA buffer while no console is attached to it, see `detach_screen`
+    detached: Option<&'static mut Buffer>,
+}
+
+static DISPLAY: SpinLock<Display> = SpinLock::new(Display { index: 0, detached: None });
+
+/// Returns the writer of console `index`
+///
+/// # Panics
+///
+/// Panics if `index` is not below `CONSOLES`.
+pub fn console(index: usize) -> &'static SpinLock<Writer> {
+    match index {
+        0 => &WRITER,
+        _ => &OTHER_WRITERS[index - 1],
+    }
+}
+
+/// Prints to console `index`, which need not be shown
+pub fn print_to(index: usize, args: fmt::Arguments) {
+    use core::fmt::Write;
+
+    console(index).lock().write_fmt(args).unwrap();
+}
+
+/// Returns the index of the console shown
+pub fn shown_console() -> usize {
+    DISPLAY.lock().index
+}
+
+/// Runs `f` on the writer of the console shown, which stays shown meanwhile
+pub fn with_shown_console<R>(f: impl FnOnce(&mut Writer) -> R) -> R {
+    let display = DISPLAY.lock();
+    f(&mut console(display.index).lock())
+}
+
+/// Shows console `index` instead of the current one; does nothing if there
+/// is no such console
+pub fn switch_console(index: usize) {
+    let mut display = DISPLAY.lock();
+    if index >= CONSOLES || index == display.index {
+        return;
+    }
+    if display.detached.is_none() {
+        let vga = console(display.index).lock().detach();
+        console(index).lock().attach(vga);
+    }
+    display.index = index;
+}
+
+/// Stops showing the consoles, which keep writing to their own screens, so
+/// the VGA memory can be used for something else such as a graphics mode
+pub(crate) fn detach_screen() {
+    let mut display = DISPLAY.lock();
+    if display.detached.is_none() {
+        let vga = console(display.index).lock().detach();
+        display.detached = Some(vga);
+    }
+}
+
+/// Shows the console again after `detach_screen`; the card must be back in
+/// text mode
+pub(crate) fn attach_screen() {
+    let mut display = DISPLAY.lock();
+    if let Some(vga) = display.detached.take() {
+        console(display.index).lock().attach(vga);
+    }
+}
+
+fn copy_buffer(from: &Buffer, to: &mut Buffer) {
+    for (from_row, to_row) in from.chars.iter().zip(to.chars.iter_mut()) {
+        for (from, to) in from_row.iter().zip(to_row.iter_mut()) {
+            to.write(from.read());
+        }
+    }
+}
+
+/// Forcibly unlocks all consoles, for panic handlers that may have
+/// interrupted a write or a switch
+///
+/// # Safety
+///
+/// The current holders of the locks, if any, must never run again.
+pub unsafe fn force_unlock() {
+    DISPLAY.force_unlock();
+    WRITER.force_unlock();
+    for writer in OTHER_WRITERS.iter() {
+        writer.force_unlock();
+    }
+}
+
+/// Restores the colour of `WRITER` it was created with when dropped
+#[must_use = "the colour is restored as soon as the guard is dropped"]
+pub struct ColorGuard {
+    previous: ColorCode,
+}
+
+impl Drop for ColorGuard {
+    fn drop(&mut self) {
+        WRITER.lock().color_code = self.previous;
+    }
+}
+
+/// Switches `WRITER` to `color_code` until the returned guard is dropped
+///
+/// The colour applies to all output in the meantime, including that of
+/// other tasks and interrupt handlers; `print_color!` only colours its own.
+pub fn scoped_color(color_code: ColorCode) -> ColorGuard {
+    let mut writer = WRITER.lock();
+    let previous = writer.color_code;
+    writer.color_code = color_code;
+    ColorGuard { previous }
 }
 
 #[test_case]
@@ -178,6 +951,229 @@ fn test_println_many() {
     }
 }
 
+#[test_case]
+fn test_backspace_moves_left() {
+    use core::fmt::Write;
+
+    let mut writer = WRITER.lock();
+    write!(writer, "\nabc\x08\x08X").expect("write failed");
+    for (col, &byte) in b"aXc".iter().enumerate() {
+        let screen_char = writer.buffer.chars[BUFFER_HEIGHT - 1][col].read();
+        assert_eq!(screen_char.ascii_character, byte);
+    }
+    assert_eq!(writer.column_position, 2);
+}
+
+#[test_case]
+fn test_hardware_cursor_follows_output() {
+    use core::fmt::Write;
+
+    let mut writer = WRITER.lock();
+    write!(writer, "\nab").expect("write failed");
+    let location = u16::from(read_crtc(CURSOR_LOCATION_HIGH)) << 8
+        | u16::from(read_crtc(CURSOR_LOCATION_LOW));
+    assert_eq!(location as usize, (BUFFER_HEIGHT - 1) * BUFFER_WIDTH + 2);
+
+    let shape = writer.cursor_shape();
+    writer.set_cursor_shape(0, 15);
+    assert_eq!(writer.cursor_shape(), (0, 15));
+    writer.set_cursor_visible(false);
+    assert!(!writer.cursor_visible());
+    assert_eq!(writer.cursor_shape(), (0, 15));
+    writer.set_cursor_visible(true);
+    writer.set_cursor_shape(shape.0, shape.1);
+    assert!(writer.cursor_visible());
+}
+
+#[test_case]
+fn test_positioned_output() {
+    use core::fmt::Write;
+
+    let mut writer = WRITER.lock();
+    let char_at = |writer: &Writer, row: usize, col: usize| {
+        writer.buffer.chars[row][col].read().ascii_character
+    };
+    writer.save_position();
+    writer.set_position(3, 10);
+    write!(writer, "hi").expect("write failed");
+    assert_eq!(writer.cursor_position(), (3, 12));
+    assert_eq!((char_at(&writer, 3, 10), char_at(&writer, 3, 11)), (b'h', b'i'));
+
+    writer.write_at(5, BUFFER_WIDTH - 2, "xyz");
+    assert_eq!(char_at(&writer, 5, BUFFER_WIDTH - 2), b'x');
+    assert_eq!(char_at(&writer, 5, BUFFER_WIDTH - 1), b'y');
+    assert_eq!(char_at(&writer, 6, 0), b' ');
+    assert_eq!(writer.cursor_position(), (3, 12));
+
+    writer.clear_region(3, 10, 1, 1);
+    assert_eq!((char_at(&writer, 3, 10), char_at(&writer, 3, 11)), (b' ', b'i'));
+    writer.restore_position();
+    assert_eq!(writer.cursor_position().0, BUFFER_HEIGHT - 1);
+}
+
+#[test_case]
+fn test_escape_sequences() {
+    use core::fmt::Write;
+
+    let mut writer = WRITER.lock();
+    let default_color = writer.default_color;
+    write!(writer, "\x1b[3;5HA\x1b[31;44mB\x1b[0mC").expect("write failed");
+    let row = &writer.buffer.chars[2];
+    let (a, b, c) = (row[4].read(), row[5].read(), row[6].read());
+    assert_eq!((a.ascii_character, b.ascii_character, c.ascii_character), (b'A', b'B', b'C'));
+    assert_eq!(b.color_code, ColorCode::new(Color::Red, Color::Blue));
+    assert_eq!(c.color_code, default_color);
+
+    write!(writer, "\x1b[s\x1b[2D\x1b[K\x1b[u\x1b[1A").expect("write failed");
+    assert_eq!(writer.buffer.chars[2][5].read().ascii_character, b' ');
+    assert_eq!(writer.buffer.chars[2][4].read().ascii_character, b'A');
+    assert_eq!(writer.cursor_position(), (1, 7));
+    writer.set_position(BUFFER_HEIGHT - 1, 0);
+}
+
+#[test_case]
+fn test_colors() {
+    let red_on_blue = ColorCode::new(Color::LightRed, Color::Blue);
+    assert_eq!(red_on_blue.foreground(), Color::LightRed);
+    assert_eq!(red_on_blue.background(), Color::Blue);
+
+    let initial = WRITER.lock().color_code();
+    {
+        let _guard = scoped_color(ColorCode::new(Color::White, Color::Blue));
+        WRITER.lock().set_foreground(Color::LightRed);
+        assert_eq!(WRITER.lock().color_code(), red_on_blue);
+    }
+    assert_eq!(WRITER.lock().color_code(), initial);
+
+    WRITER.lock().set_position(3, 0);
+    print_color!(Color::Green, "ok");
+    let mut writer = WRITER.lock();
+    assert_eq!(writer.color_code(), initial);
+    let green = ColorCode::new(Color::Green, initial.background());
+    assert_eq!(writer.buffer.chars[3][0].read().color_code, green);
+    assert_eq!(writer.buffer.chars[3][1].read().color_code, green);
+    writer.set_position(BUFFER_HEIGHT - 1, 0);
+}
+
+#[test_case]
+fn test_scrollback() {
+    use core::fmt::Write;
+
+    fn text(writer: &Writer, row: usize) -> [u8; 7] {
+        let mut text = [0; 7];
+        for (col, byte) in text.iter_mut().enumerate() {
+            *byte = writer.buffer.chars[row][col].read().ascii_character;
+        }
+        text
+    }
+
+    let mut writer = WRITER.lock();
+    writer.set_position(BUFFER_HEIGHT - 1, 0);
+    for i in 0..BUFFER_HEIGHT + 5 {
+        write!(writer, "\nline {:02}", i).expect("write failed");
+    }
+    assert_eq!(&text(&writer, 0), b"line 05");
+
+    writer.scroll_back(3);
+    assert_eq!(writer.scrolled_back(), 3);
+    assert_eq!(&text(&writer, 0), b"line 02");
+    assert_eq!(&text(&writer, 3), b"line 05");
+    writer.scroll_forward(1);
+    assert_eq!(&text(&writer, 0), b"line 03");
+
+    // new output goes back to the live screen
+    write!(writer, "!").expect("write failed");
+    assert_eq!(writer.scrolled_back(), 0);
+    assert_eq!(&text(&writer, 0), b"line 05");
+    assert_eq!(&text(&writer, BUFFER_HEIGHT - 1), b"line 29");
+
+    writer.set_scrollback_lines(2);
+    writer.scroll_back(BUFFER_HEIGHT);
+    assert_eq!(writer.scrolled_back(), 2);
+    assert_eq!(&text(&writer, 0), b"line 03");
+    writer.set_scrollback_lines(SCROLLBACK_CAPACITY);
+    assert_eq!(&text(&writer, 0), b"line 05");
+    writer.set_position(BUFFER_HEIGHT - 1, 0);
+}
+
+#[test_case]
+fn test_virtual_consoles() {
+    let text = |writer: &Writer, row: usize| -> [u8; 5] {
+        let mut text = [0; 5];
+        for (col, byte) in text.iter_mut().enumerate() {
+            *byte = writer.buffer.chars[row][col].read().ascii_character;
+        }
+        text
+    };
+
+    WRITER.lock().write_at(0, 0, "zero!");
+    console(2).lock().set_position(0, 0);
+    print_to(2, format_args!("two!!"));
+    assert!(WRITER.lock().is_shown() && !console(2).lock().is_shown());
+    assert_eq!(&text(&console(2).lock(), 0), b"two!!");
+
+    switch_console(2);
+    assert_eq!(shown_console(), 2);
+    assert!(!WRITER.lock().is_shown() && console(2).lock().is_shown());
+    let vga = unsafe { &*(0xb8000 as *const Buffer) };
+    assert_eq!(vga.chars[0][0].read().ascii_character, b't');
+    // a hidden console keeps its screen and can be written to
+    assert_eq!(&text(&WRITER.lock(), 0), b"zero!");
+    WRITER.lock().write_at(0, 0, "Zero!");
+
+    switch_console(CONSOLES);
+    assert_eq!(shown_console(), 2);
+    switch_console(0);
+    assert!(WRITER.lock().is_shown());
+    assert_eq!(vga.chars[0][0].read().ascii_character, b'Z');
+    assert_eq!(&text(&console(2).lock(), 0), b"two!!");
+}
+
+#[test_case]
+fn test_reserved_rows() {
+    use core::fmt::Write;
+
+    // console 5 is not shown, so this leaves the screen alone
+    let mut writer = console(5).lock();
+    let status = ColorCode::new(Color::Black, Color::LightGray);
+    writer.set_reserved_rows(1);
+    writer.write_reserved(0, "status", status);
+    writer.set_position(0, 0);
+    assert_eq!(writer.cursor_position(), (1, 0));
+    for _ in 0..BUFFER_HEIGHT {
+        writeln!(writer, "text").expect("write failed");
+    }
+    writer.clear_region(0, 0, 1, BUFFER_WIDTH);
+    writer.write_at(0, 0, "lost");
+    let top = writer.buffer.chars[0][0].read();
+    assert_eq!((top.ascii_character, top.color_code), (b's', status));
+    assert_eq!(writer.buffer.chars[0][BUFFER_WIDTH - 1].read().ascii_character, b' ');
+    assert_eq!(writer.buffer.chars[1][0].read().ascii_character, b't');
+
+    writer.clear_screen();
+    assert_eq!(writer.buffer.chars[0][0].read().ascii_character, b's');
+    assert_eq!(writer.cursor_position(), (1, 0));
+    writer.set_reserved_rows(0);
+}
+
+#[test_case]
+fn test_utf8_output() {
+    use core::fmt::Write;
+
+    let mut writer = WRITER.lock();
+    writer.set_position(3, 0);
+    write!(writer, "Wörld ─ 20°C €").expect("write failed");
+    // "é" split between two writes
+    writer.write_bytes(b" caf\xc3");
+    writer.write_bytes(b"\xa9");
+    let expected = b"W\x94rld \xc4 20\xf8C \xfe caf\x82";
+    for (i, &glyph) in expected.iter().enumerate() {
+        assert_eq!(writer.buffer.chars[3][i].read().ascii_character, glyph);
+    }
+    assert_eq!(writer.cursor_position(), (3, expected.len()));
+    writer.set_position(BUFFER_HEIGHT - 1, 0);
+}
+
 #[test_case]
 fn test_println_output() {
     use core::fmt::Write;  // new
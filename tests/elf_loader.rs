@@ -0,0 +1,84 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::panic::PanicInfo;
+use blog_os::elf::{self, ElfError, LoadError};
+use blog_os::{memory, task, usermode};
+use bootloader::{entry_point, BootInfo};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    blog_os::init();
+    unsafe {
+        memory::init(VirtAddr::new(boot_info.physical_memory_offset));
+        memory::init_frame_allocator(&boot_info.memory_map);
+    }
+
+    test_main();
+    blog_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+// built from the sources in tests/elf, see the Makefile there
+static ARGS: &[u8] = include_bytes!("elf/args.elf");
+static DATA: &[u8] = include_bytes!("elf/data.elf");
+static WRITE_TEXT: &[u8] = include_bytes!("elf/write_text.elf");
+
+fn run(program: &[u8], argv: &[&str], envp: &[&str]) -> Option<i64> {
+    let id = elf::spawn(program, argv, envp).expect("spawn failed");
+    task::join(id)
+}
+
+#[test_case]
+fn program_sees_its_arguments() {
+    let code = run(ARGS, &["args", "hello from argv\n"], &["HOME=/", "TERM=vga"]);
+    assert_eq!(code, Some((2 << 16) | (2 << 8) | "hello from argv\n".len() as i64));
+}
+
+#[test_case]
+fn program_without_arguments() {
+    assert_eq!(run(ARGS, &[], &[]), Some(0));
+}
+
+#[test_case]
+fn data_is_writable_and_bss_is_zeroed() {
+    assert_eq!(run(DATA, &["data"], &[]), Some(42));
+}
+
+#[test_case]
+fn code_is_read_only() {
+    assert_eq!(run(WRITE_TEXT, &["write_text"], &[]), Some(usermode::USER_FAULT_EXIT_CODE));
+}
+
+#[test_case]
+fn programs_run_in_separate_page_tables() {
+    // both programs are linked to the same addresses
+    let first = elf::spawn(DATA, &[], &[]).expect("spawn failed");
+    let second = elf::spawn(DATA, &[], &[]).expect("spawn failed");
+    assert_eq!(task::join(first), Some(42));
+    assert_eq!(task::join(second), Some(42));
+}
+
+#[test_case]
+fn invalid_images_are_rejected() {
+    assert_eq!(elf::spawn(b"#!/bin/sh\n", &[], &[]).err(), Some(LoadError::Elf(ElfError::Truncated)));
+    // cuts the code segment short
+    let truncated = &ARGS[..0x1010];
+    assert_eq!(elf::load(truncated, &[], &[]).err(), Some(LoadError::Elf(ElfError::BadSegment)));
+}
+
+#[test_case]
+fn exited_program_frees_its_frames() {
+    let before = memory::frames_in_use();
+    assert_eq!(run(DATA, &["data"], &[]), Some(42));
+    assert_eq!(memory::frames_in_use(), before);
+}
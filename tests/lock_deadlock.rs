@@ -0,0 +1,54 @@
+#![no_std]
+#![no_main]
+
+use core::fmt::{self, Write};
+use core::panic::PanicInfo;
+use blog_os::{exit_qemu, serial_print, serial_println, QemuExitCode};
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    serial_print!("lock_deadlock::breakpoint_while_writer_held...\t");
+    blog_os::init();
+
+    let _writer = blog_os::vga_buffer::WRITER.lock();
+    // the breakpoint handler prints to the screen, so it needs the writer too
+    x86_64::instructions::interrupts::int3();
+
+    serial_println!("[test did not panic]");
+    exit_qemu(QemuExitCode::Failed);
+    blog_os::hlt_loop();
+}
+
+/// Keeps the first bytes of the panic message so they can be checked
+struct Message {
+    buf: [u8; 512],
+    len: usize,
+}
+
+impl Write for Message {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let n = s.len().min(self.buf.len() - self.len);
+        self.buf[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+        Ok(())
+    }
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    let mut message = Message { buf: [0; 512], len: 0 };
+    let _ = write!(message, "{}", info);
+    let message = core::str::from_utf8(&message.buf[..message.len]).unwrap_or("");
+
+    // both the interrupted acquisition and the one that would hang are named
+    if message.contains("deadlock")
+        && message.contains("lock_deadlock.rs")
+        && message.contains("vga_buffer.rs")
+    {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        blog_os::test_panic_handler(info);
+    }
+    blog_os::hlt_loop();
+}
@@ -0,0 +1,154 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::panic::PanicInfo;
+use blog_os::process::{self, ProcessState, WaitError};
+use blog_os::signal::{self, Signal};
+use blog_os::{memory, task, usermode};
+use blog_os::interrupts::ms_to_ticks;
+use bootloader::{entry_point, BootInfo};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    blog_os::init();
+    unsafe {
+        memory::init(VirtAddr::new(boot_info.physical_memory_offset));
+        memory::init_frame_allocator(&boot_info.memory_map);
+    }
+
+    test_main();
+    blog_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+// built from the sources in tests/elf, see the Makefile there
+static CHANNEL: &[u8] = include_bytes!("elf/channel.elf");
+static DATA: &[u8] = include_bytes!("elf/data.elf");
+static FORK: &[u8] = include_bytes!("elf/fork.elf");
+static ORPHAN: &[u8] = include_bytes!("elf/orphan.elf");
+static PID: &[u8] = include_bytes!("elf/pid.elf");
+static RECEIVE: &[u8] = include_bytes!("elf/receive.elf");
+static SHM: &[u8] = include_bytes!("elf/shm.elf");
+static SIGNAL: &[u8] = include_bytes!("elf/signal.elf");
+static SPIN: &[u8] = include_bytes!("elf/spin.elf");
+static WRITE_TEXT: &[u8] = include_bytes!("elf/write_text.elf");
+
+#[test_case]
+fn run_collects_the_exit_code() {
+    assert_eq!(process::run_elf(DATA, &["data"], &[]), Ok(42));
+    assert_eq!(process::process_count(), 0);
+}
+
+#[test_case]
+fn faulting_process_exits_with_fault_code() {
+    assert_eq!(process::run_elf(WRITE_TEXT, &[], &[]), Ok(usermode::USER_FAULT_EXIT_CODE));
+}
+
+#[test_case]
+fn process_sees_its_pid() {
+    let pid = process::spawn_elf(PID, &[], &[]).expect("spawn failed");
+    // started by the kernel, so getppid returns 0
+    assert_eq!(process::wait(pid), Ok((pid.as_u64() as i64) << 8));
+}
+
+#[test_case]
+fn zombie_keeps_its_exit_code_until_waited_for() {
+    let pid = process::spawn_elf(DATA, &[], &[]).expect("spawn failed");
+    while process::state_of(pid) == Some(ProcessState::Running) {
+        task::yield_now();
+    }
+    assert_eq!(process::state_of(pid), Some(ProcessState::Zombie(42)));
+    assert_eq!(process::wait(pid), Ok(42));
+    assert_eq!(process::state_of(pid), None);
+    assert_eq!(process::wait(pid), Err(WaitError::NoSuchProcess));
+}
+
+#[test_case]
+fn wait_any_collects_every_child() {
+    let tasks_before = task::task_count();
+    let first = process::spawn_elf(DATA, &[], &[]).expect("spawn failed");
+    let second = process::spawn_elf(PID, &[], &[]).expect("spawn failed");
+    assert_ne!(first, second);
+
+    let mut seen = [None, None];
+    for result in seen.iter_mut() {
+        *result = Some(process::wait_any().expect("child missing"));
+    }
+    assert!(seen.contains(&Some((first, 42))));
+    assert!(seen.contains(&Some((second, (second.as_u64() as i64) << 8))));
+    assert_eq!(process::wait_any(), Err(WaitError::NoChildren));
+    assert_eq!(task::task_count(), tasks_before);
+}
+
+#[test_case]
+fn process_resources_are_freed() {
+    let frames_before = memory::frames_in_use();
+    for _ in 0..(task::MAX_TASKS * 2) {
+        assert_eq!(process::run_elf(DATA, &[], &[]), Ok(42));
+    }
+    assert_eq!(memory::frames_in_use(), frames_before);
+}
+
+#[test_case]
+fn forked_child_gets_its_own_copy_of_memory() {
+    let frames = memory::frames_in_use();
+    assert_eq!(process::run_elf(FORK, &[], &[]), Ok(1501));
+    assert_eq!(process::process_count(), 0);
+    assert_eq!(memory::frames_in_use(), frames);
+}
+
+#[test_case]
+fn forked_orphan_is_removed_when_it_exits() {
+    assert_eq!(process::run_elf(ORPHAN, &[], &[]), Ok(7));
+    // the child sleeps for 50 ms before it exits
+    for _ in 0..20 {
+        if process::process_count() == 0 {
+            break;
+        }
+        task::sleep(ms_to_ticks(10));
+    }
+    assert_eq!(process::process_count(), 0);
+}
+
+#[test_case]
+fn receiver_sees_messages_then_sender_exit() {
+    assert_eq!(process::run_elf(CHANNEL, &[], &[]), Ok(306));
+    assert_eq!(process::process_count(), 0);
+}
+
+#[test_case]
+fn shared_memory_is_shared_after_fork() {
+    let frames = memory::frames_in_use();
+    assert_eq!(process::run_elf(SHM, &[], &[]), Ok(99));
+    assert_eq!(memory::frames_in_use(), frames);
+}
+
+#[test_case]
+fn signal_handlers_run_and_return() {
+    assert_eq!(process::run_elf(SIGNAL, &[], &[]), Ok(21));
+}
+
+#[test_case]
+fn signal_interrupts_process_in_user_mode() {
+    let pid = process::spawn_elf(SPIN, &[], &[]).expect("spawn failed");
+    task::sleep(ms_to_ticks(20));
+    assert_eq!(signal::send(pid, Signal::Terminate), Ok(()));
+    assert_eq!(process::wait(pid), Ok(Signal::Terminate.exit_code()));
+}
+
+#[test_case]
+fn signal_ends_process_blocked_in_receive() {
+    let pid = process::spawn_elf(RECEIVE, &[], &[]).expect("spawn failed");
+    task::sleep(ms_to_ticks(20));
+    assert_eq!(signal::send(pid, Signal::Kill), Ok(()));
+    assert_eq!(process::wait(pid), Ok(Signal::Kill.exit_code()));
+}
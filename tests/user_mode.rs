@@ -0,0 +1,177 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::arch::global_asm;
+use core::panic::PanicInfo;
+use blog_os::syscall::SyscallError;
+use blog_os::{memory, task, usermode};
+use bootloader::{entry_point, BootInfo};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    blog_os::init();
+    unsafe {
+        memory::init(VirtAddr::new(boot_info.physical_memory_offset));
+        memory::init_frame_allocator(&boot_info.memory_map);
+    }
+
+    test_main();
+    blog_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+// tiny user programs; they are copied to user pages, so they only contain
+// position independent code. The ones that enter the kernel with `syscall`
+// exit with the value the test checks.
+global_asm!(
+    ".pushsection .rodata",
+    ".global exit_42, exit_42_end",
+    "exit_42:",
+    "mov edi, 42",
+    "mov eax, 1", // SYS_EXIT
+    "int 0x80",
+    "exit_42_end:",
+    "",
+    ".global read_kernel_memory, read_kernel_memory_end",
+    "read_kernel_memory:",
+    "mov eax, dword ptr [0xb8000]",
+    "read_kernel_memory_end:",
+    "",
+    ".global privileged_instruction, privileged_instruction_end",
+    "privileged_instruction:",
+    "hlt",
+    "privileged_instruction_end:",
+    "",
+    ".global write_hello, write_hello_end",
+    "write_hello:",
+    "lea rdi, [rip + 2f]",
+    "mov esi, 3f - 2f",
+    "mov eax, 0", // SYS_WRITE
+    "syscall",
+    "mov rdi, rax",
+    "mov eax, 1", // SYS_EXIT
+    "syscall",
+    "2:",
+    ".ascii \"hello from user mode\\n\"",
+    "3:",
+    "write_hello_end:",
+    "",
+    ".global write_kernel_memory, write_kernel_memory_end",
+    "write_kernel_memory:",
+    "mov edi, 0xb8000",
+    "mov esi, 16",
+    "mov eax, 0", // SYS_WRITE
+    "syscall",
+    "mov rdi, rax",
+    "mov eax, 1", // SYS_EXIT
+    "syscall",
+    "write_kernel_memory_end:",
+    "",
+    ".global sleep_100ms, sleep_100ms_end",
+    "sleep_100ms:",
+    "mov eax, 3", // SYS_TIME
+    "syscall",
+    "mov rbx, rax",
+    "mov edi, 100",
+    "mov eax, 2", // SYS_SLEEP
+    "syscall",
+    "mov eax, 3", // SYS_TIME
+    "syscall",
+    "sub rax, rbx",
+    "mov rdi, rax",
+    "mov eax, 1", // SYS_EXIT
+    "syscall",
+    "sleep_100ms_end:",
+    "",
+    ".global yield_then_unknown, yield_then_unknown_end",
+    "yield_then_unknown:",
+    "mov eax, 4", // SYS_YIELD
+    "syscall",
+    "mov eax, 999",
+    "syscall",
+    "mov rdi, rax",
+    "mov eax, 1", // SYS_EXIT
+    "syscall",
+    "yield_then_unknown_end:",
+    ".popsection",
+);
+
+extern "C" {
+    static exit_42: u8;
+    static exit_42_end: u8;
+    static read_kernel_memory: u8;
+    static read_kernel_memory_end: u8;
+    static privileged_instruction: u8;
+    static privileged_instruction_end: u8;
+    static write_hello: u8;
+    static write_hello_end: u8;
+    static write_kernel_memory: u8;
+    static write_kernel_memory_end: u8;
+    static sleep_100ms: u8;
+    static sleep_100ms_end: u8;
+    static yield_then_unknown: u8;
+    static yield_then_unknown_end: u8;
+}
+
+fn code(start: &'static u8, end: &'static u8) -> &'static [u8] {
+    let start = start as *const u8;
+    let len = end as *const u8 as usize - start as usize;
+    unsafe { core::slice::from_raw_parts(start, len) }
+}
+
+fn run(code: &[u8]) -> Option<i64> {
+    let id = usermode::spawn_user_code(code).expect("spawn_user_code failed");
+    task::join(id)
+}
+
+#[test_case]
+fn user_code_exits_through_system_call() {
+    let program = unsafe { code(&exit_42, &exit_42_end) };
+    assert_eq!(run(program), Some(42));
+}
+
+#[test_case]
+fn user_page_fault_kills_only_the_task() {
+    let program = unsafe { code(&read_kernel_memory, &read_kernel_memory_end) };
+    assert_eq!(run(program), Some(usermode::USER_FAULT_EXIT_CODE));
+}
+
+#[test_case]
+fn privileged_instruction_in_user_mode_is_a_user_fault() {
+    let program = unsafe { code(&privileged_instruction, &privileged_instruction_end) };
+    assert_eq!(run(program), Some(usermode::USER_FAULT_EXIT_CODE));
+}
+
+#[test_case]
+fn write_returns_the_length() {
+    let program = unsafe { code(&write_hello, &write_hello_end) };
+    assert_eq!(run(program), Some("hello from user mode\n".len() as i64));
+}
+
+#[test_case]
+fn write_from_kernel_memory_is_rejected() {
+    let program = unsafe { code(&write_kernel_memory, &write_kernel_memory_end) };
+    assert_eq!(run(program), Some(SyscallError::BadAddress as i64));
+}
+
+#[test_case]
+fn sleep_waits_at_least_the_given_time() {
+    let program = unsafe { code(&sleep_100ms, &sleep_100ms_end) };
+    let elapsed = run(program).expect("task vanished");
+    assert!(elapsed >= 100, "slept only {} ms", elapsed);
+}
+
+#[test_case]
+fn unknown_system_call_fails() {
+    let program = unsafe { code(&yield_then_unknown, &yield_then_unknown_end) };
+    assert_eq!(run(program), Some(SyscallError::NoSuchCall as i64));
+}
@@ -0,0 +1,84 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::panic::PanicInfo;
+use blog_os::graphics::{self, GraphicsError, GLYPH_HEIGHT, GLYPH_WIDTH, HEIGHT, WIDTH};
+use blog_os::vga_buffer::{self, Color, BUFFER_WIDTH};
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    blog_os::init();
+    test_main();
+    blog_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+/// Returns the character at `row` and `col` of the text screen
+fn screen_char(row: usize, col: usize) -> u8 {
+    let vga = 0xb8000 as *const u16;
+    unsafe { vga.add(row * BUFFER_WIDTH + col).read_volatile() as u8 }
+}
+
+#[test_case]
+fn draws_and_reads_back_pixels() {
+    let mut framebuffer = graphics::enter().expect("failed to enter mode 13h");
+    assert_eq!(graphics::enter().err(), Some(GraphicsError::AlreadyActive));
+    assert_eq!(framebuffer.pixel(0, 0), Some(0));
+    assert_eq!(framebuffer.pixel(WIDTH, 0), None);
+
+    framebuffer.set_pixel(10, 20, 42);
+    framebuffer.set_pixel(WIDTH, HEIGHT, 42);
+    assert_eq!(framebuffer.pixel(10, 20), Some(42));
+
+    framebuffer.draw_line(0, 0, 9, 9, 1);
+    framebuffer.draw_line(-5, 199, 400, 199, 2);
+    assert_eq!(framebuffer.pixel(5, 5), Some(1));
+    assert_eq!(framebuffer.pixel(5, 6), Some(0));
+    assert_eq!(framebuffer.pixel(0, 199), Some(2));
+    assert_eq!(framebuffer.pixel(WIDTH - 1, 199), Some(2));
+
+    framebuffer.fill_rect(100, 100, 10, 5, 3);
+    assert_eq!(framebuffer.pixel(109, 104), Some(3));
+    assert_eq!(framebuffer.pixel(110, 104), Some(0));
+    framebuffer.draw_rect(200, 50, 20, 10, 4);
+    assert_eq!(framebuffer.pixel(219, 59), Some(4));
+    assert_eq!(framebuffer.pixel(210, 55), Some(0));
+
+    let white = Color::White as u8;
+    let blue = Color::Blue as u8;
+    framebuffer.draw_text(0, 150, "A", white, Some(blue));
+    let glyph = framebuffer.glyph(b'A');
+    assert!(glyph.iter().any(|&row| row != 0), "font was not saved");
+    for (dy, &row) in glyph.iter().enumerate() {
+        for dx in 0..GLYPH_WIDTH {
+            let expected = if row & (0x80 >> dx) != 0 { white } else { blue };
+            assert_eq!(framebuffer.pixel(dx, 150 + dy), Some(expected));
+        }
+    }
+    assert_eq!(framebuffer.pixel(GLYPH_WIDTH, 150 + GLYPH_HEIGHT - 1), Some(0));
+}
+
+#[test_case]
+fn text_mode_comes_back() {
+    vga_buffer::WRITER.lock().write_at(1, 0, "before");
+    let mut framebuffer = graphics::enter().expect("failed to enter mode 13h");
+    let glyph = framebuffer.glyph(b'A');
+    // text written meanwhile goes to the console's own screen
+    vga_buffer::WRITER.lock().write_at(2, 0, "during");
+    framebuffer.clear(0xff);
+    drop(framebuffer);
+
+    assert_eq!(screen_char(1, 0), b'b');
+    assert_eq!(screen_char(2, 0), b'd');
+    assert!(vga_buffer::WRITER.lock().is_shown());
+    // the font survived mode 13h
+    let framebuffer = graphics::enter().expect("failed to enter mode 13h");
+    assert_eq!(framebuffer.glyph(b'A'), glyph);
+}